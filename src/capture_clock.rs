@@ -0,0 +1,47 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// pcapのパケットタイムスタンプ(packet.header.ts)から導出したキャプチャ時刻
+// ライブキャプチャでもファイル再生でも、タイムアウトや到着時刻はすべてこの時刻を基準にする
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureClock {
+    now: SystemTime,
+}
+
+impl CaptureClock {
+    pub fn new() -> Self {
+        CaptureClock { now: UNIX_EPOCH }
+    }
+
+    // パケットのタイムスタンプで時刻を進める
+    // タイムスタンプが巻き戻った場合(順序の乱れたキャプチャファイルなど)は現在の時刻を維持する
+    pub fn advance(&mut self, header: &pcap::PacketHeader) -> SystemTime {
+        let ts = timestamp_to_system_time(header);
+        if ts > self.now {
+            self.now = ts;
+        }
+        self.now
+    }
+
+    // 最後に進めた時刻
+    pub fn now(&self) -> SystemTime {
+        self.now
+    }
+}
+
+impl Default for CaptureClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// pcapのタイムスタンプ(秒+マイクロ秒)をSystemTimeに変換
+pub fn timestamp_to_system_time(header: &pcap::PacketHeader) -> SystemTime {
+    let secs = header.ts.tv_sec.max(0) as u64;
+    let micros = header.ts.tv_usec.clamp(0, 999_999) as u64;
+    UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_micros(micros)
+}
+
+// earlierからnowまでの経過時間 (時刻が逆転している場合は0)
+pub fn elapsed_between(earlier: SystemTime, now: SystemTime) -> Duration {
+    now.duration_since(earlier).unwrap_or(Duration::ZERO)
+}
@@ -0,0 +1,283 @@
+use crate::packet_processor::IPPROTO_UDP;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// オフロードの判定に使う直近の検証回数と、オフロードとみなす不正なチェックサムの割合
+const OFFLOAD_SAMPLE_SIZE: usize = 1000;
+const OFFLOAD_BAD_RATIO: f64 = 0.1;
+
+// インターネットチェックサム (RFC 1071) の1の補数和を計算する
+fn ones_complement_sum(data: &[u8], mut sum: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+// IPv4ヘッダーのチェックサムを検証する (headerはオプションを含むヘッダー全体)
+// チェックサムフィールドを含めた和が0xFFFFになれば正しい
+pub fn verify_ipv4_header(header: &[u8]) -> bool {
+    fold(ones_complement_sum(header, 0)) == 0xFFFF
+}
+
+// 疑似ヘッダーを含めてTCP/UDPのチェックサムを検証する (segmentはヘッダーを含む上位層のデータ全体)
+// IPv4のUDPでチェックサムが0の場合は計算されていないため正しいものとする
+pub fn verify_transport(src_ip: IpAddr, dst_ip: IpAddr, protocol: u8, segment: &[u8]) -> bool {
+    if protocol == IPPROTO_UDP && src_ip.is_ipv4() && segment.get(6..8) == Some(&[0, 0]) {
+        return true;
+    }
+
+    let mut sum = 0;
+    match (src_ip, dst_ip) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            sum = ones_complement_sum(&src.octets(), sum);
+            sum = ones_complement_sum(&dst.octets(), sum);
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            sum = ones_complement_sum(&src.octets(), sum);
+            sum = ones_complement_sum(&dst.octets(), sum);
+        }
+        _ => return false,
+    }
+    // 上位層の長さ(IPv6は32ビット)とプロトコル番号
+    let length = segment.len() as u32;
+    sum += length >> 16;
+    sum += length & 0xFFFF;
+    sum += protocol as u32;
+
+    fold(ones_complement_sum(segment, sum)) == 0xFFFF
+}
+
+// チェックサムが不正なパケットの扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumPolicy {
+    Drop,  // 状態の追跡に使わない (ホストが破棄するパケットでIDSの状態がずれないようにする)
+    Alert, // 通常どおり処理し、アラートを出す
+    Auto,  // Dropと同様に扱うが、NICのオフロードを検出している間は自ホストから送信したパケットを不正でも受け入れる
+    None,  // 検証しない
+}
+
+impl FromStr for ChecksumPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "drop" => Ok(ChecksumPolicy::Drop),
+            "alert" => Ok(ChecksumPolicy::Alert),
+            "auto" => Ok(ChecksumPolicy::Auto),
+            "none" => Ok(ChecksumPolicy::None),
+            s => Err(format!("不明なチェックサムポリシーです: {} (drop, alert, auto, none)", s)),
+        }
+    }
+}
+
+// 検証したチェックサムの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumKind {
+    Ipv4,
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for ChecksumKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChecksumKind::Ipv4 => write!(f, "IPv4 header"),
+            ChecksumKind::Tcp => write!(f, "TCP"),
+            ChecksumKind::Udp => write!(f, "UDP"),
+        }
+    }
+}
+
+// チェックサムの検証結果に対する処理
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumVerdict {
+    Accept,  // 通常どおり処理する
+    Alert,   // 処理し、アラートを出す
+    Discard, // 状態の追跡に使わない
+}
+
+// 不正なチェックサムの件数
+#[derive(Debug, Default, Clone)]
+pub struct ChecksumCounters {
+    pub checked: u64, // 検証したチェックサムの数
+    pub ipv4: u64,
+    pub tcp: u64,
+    pub udp: u64,
+}
+
+// インターフェースごとのチェックサムの検証
+pub struct ChecksumValidator {
+    policy: ChecksumPolicy,
+    interface: String,
+    local_addresses: HashSet<IpAddr>, // インターフェースのアドレス (オフロードの影響を受けるのはここから送信したパケットのみ)
+    offload_detected: bool,
+    recent: VecDeque<bool>, // 自ホストから送信したパケットの直近の検証結果 (trueは不正)
+    recent_bad: usize,
+    pub counters: ChecksumCounters,
+}
+
+impl ChecksumValidator {
+    pub fn new(policy: ChecksumPolicy, interface: String, local_addresses: Vec<IpAddr>) -> Self {
+        ChecksumValidator {
+            policy,
+            interface,
+            local_addresses: local_addresses.into_iter().collect(),
+            offload_detected: false,
+            recent: VecDeque::with_capacity(OFFLOAD_SAMPLE_SIZE),
+            recent_bad: 0,
+            counters: ChecksumCounters::default(),
+        }
+    }
+
+    pub fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    // チェックサムの検証が必要か (オフロードを検出している間も判定を見直すために検証を続ける)
+    pub fn enabled(&self) -> bool {
+        self.policy != ChecksumPolicy::None
+    }
+
+    pub fn offload_detected(&self) -> bool {
+        self.offload_detected
+    }
+
+    // 検証結果を集計し、パケットの扱いを決める (src_ipはパケットの送信元)
+    pub fn verdict(&mut self, kind: ChecksumKind, valid: bool, src_ip: IpAddr) -> ChecksumVerdict {
+        if !self.enabled() {
+            return ChecksumVerdict::Accept;
+        }
+
+        self.counters.checked += 1;
+        if !valid {
+            match kind {
+                ChecksumKind::Ipv4 => self.counters.ipv4 += 1,
+                ChecksumKind::Tcp => self.counters.tcp += 1,
+                ChecksumKind::Udp => self.counters.udp += 1,
+            }
+        }
+
+        // 自ホストから送信したパケットの直近の検証で不正な割合が大きい場合は、送信側でチェックサムを計算するNICのオフロードとみなす
+        // 他のホストからのパケットで判定すると送信元を問わず不正なパケットを受け入れさせられるため、判定には使わない
+        // 一度の判定で検証をやめると最初に不正なパケットを送るだけで無効にできるため、検証のたびに見直す
+        let local = self.local_addresses.contains(&src_ip);
+        if self.policy == ChecksumPolicy::Auto && local {
+            self.record(!valid);
+        }
+
+        match (valid, self.policy) {
+            (true, _) => ChecksumVerdict::Accept,
+            (false, ChecksumPolicy::Alert) => ChecksumVerdict::Alert,
+            (false, _) if self.offload_detected && local => ChecksumVerdict::Accept,
+            (false, _) => ChecksumVerdict::Discard,
+        }
+    }
+
+    // 直近の検証結果を記録し、オフロードの判定を更新する
+    fn record(&mut self, bad: bool) {
+        if self.recent.len() == OFFLOAD_SAMPLE_SIZE && self.recent.pop_front() == Some(true) {
+            self.recent_bad -= 1;
+        }
+        self.recent.push_back(bad);
+        if bad {
+            self.recent_bad += 1;
+        }
+        if self.recent.len() == OFFLOAD_SAMPLE_SIZE {
+            self.offload_detected = self.recent_bad as f64 / OFFLOAD_SAMPLE_SIZE as f64 > OFFLOAD_BAD_RATIO;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const LOCAL: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const REMOTE: IpAddr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+
+    fn validator(policy: ChecksumPolicy) -> ChecksumValidator {
+        ChecksumValidator::new(policy, "eth0".to_string(), vec![LOCAL])
+    }
+
+    // src_ipからのパケットをcount件、bad_every件に1件を不正として検証する
+    fn check(validator: &mut ChecksumValidator, src_ip: IpAddr, count: usize, bad_every: usize) {
+        for i in 0..count {
+            validator.verdict(ChecksumKind::Tcp, i % bad_every != 0, src_ip);
+        }
+    }
+
+    #[test]
+    fn verifies_ipv4_header_and_transport_checksums() {
+        // チェックサム0xB861のIPv4ヘッダー
+        let mut header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xB8, 0x61, 0xC0, 0xA8, 0x00, 0x01, 0xC0,
+            0xA8, 0x00, 0xC7,
+        ];
+        assert!(verify_ipv4_header(&header));
+        header[8] = 0x3F;
+        assert!(!verify_ipv4_header(&header));
+
+        // チェックサムが0のIPv4のUDPは検証しない
+        let (src, dst) = (LOCAL, REMOTE);
+        let mut udp = [0x30, 0x39, 0x00, 0x35, 0x00, 0x0A, 0x00, 0x00, b'h', b'i'];
+        assert!(verify_transport(src, dst, IPPROTO_UDP, &udp));
+        udp[6..8].copy_from_slice(&[0x12, 0x34]);
+        assert!(!verify_transport(src, dst, IPPROTO_UDP, &udp));
+
+        // 計算したチェックサムを埋めると正しくなる
+        udp[6..8].copy_from_slice(&[0, 0]);
+        let mut sum = ones_complement_sum(&[192, 0, 2, 1, 198, 51, 100, 1], 0);
+        sum += udp.len() as u32 + IPPROTO_UDP as u32;
+        let checksum = !fold(ones_complement_sum(&udp, sum));
+        udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+        assert!(verify_transport(src, dst, IPPROTO_UDP, &udp));
+    }
+
+    #[test]
+    fn policies_handle_bad_checksums() {
+        let mut drop = validator(ChecksumPolicy::Drop);
+        assert_eq!(drop.verdict(ChecksumKind::Tcp, false, REMOTE), ChecksumVerdict::Discard);
+        assert_eq!(drop.verdict(ChecksumKind::Tcp, true, REMOTE), ChecksumVerdict::Accept);
+        let mut alert = validator(ChecksumPolicy::Alert);
+        assert_eq!(alert.verdict(ChecksumKind::Udp, false, REMOTE), ChecksumVerdict::Alert);
+        assert_eq!(alert.counters.udp, 1);
+        let mut none = validator(ChecksumPolicy::None);
+        assert_eq!(none.verdict(ChecksumKind::Tcp, false, REMOTE), ChecksumVerdict::Accept);
+        assert_eq!(none.counters.checked, 0);
+    }
+
+    #[test]
+    fn offload_is_detected_only_from_local_addresses() {
+        // 他のホストからの不正なパケットではオフロードとみなさない
+        let mut validator = validator(ChecksumPolicy::Auto);
+        check(&mut validator, REMOTE, OFFLOAD_SAMPLE_SIZE, 1);
+        assert!(!validator.offload_detected());
+        assert_eq!(validator.counters.tcp, OFFLOAD_SAMPLE_SIZE as u64);
+
+        // 自ホストから送信したパケットの不正な割合が大きい場合はオフロードとみなし、自ホストのパケットのみ受け入れる
+        check(&mut validator, LOCAL, OFFLOAD_SAMPLE_SIZE, 2);
+        assert!(validator.offload_detected());
+        assert_eq!(validator.verdict(ChecksumKind::Tcp, false, LOCAL), ChecksumVerdict::Accept);
+        assert_eq!(validator.verdict(ChecksumKind::Tcp, false, REMOTE), ChecksumVerdict::Discard);
+
+        // 割合が下がれば再び破棄する
+        check(&mut validator, LOCAL, OFFLOAD_SAMPLE_SIZE, OFFLOAD_SAMPLE_SIZE);
+        assert!(!validator.offload_detected());
+        assert_eq!(validator.verdict(ChecksumKind::Tcp, false, LOCAL), ChecksumVerdict::Discard);
+    }
+}
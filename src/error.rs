@@ -0,0 +1,58 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+// キャプチャの準備と解析で発生するエラー
+#[derive(Debug)]
+pub enum NidsError {
+    Pcap(pcap::Error),
+    Io(io::Error),
+    DeviceNotFound(String),          // 指定されたインターフェースが存在しない
+    InvalidDeviceSelection(String),  // 対話的な選択で入力された番号が不正
+    CaptureFileNotFound(PathBuf),    // 再生するキャプチャファイルが存在しない
+    Database(mysql::Error),          // packet_logの接続設定が不正
+}
+
+impl fmt::Display for NidsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NidsError::Pcap(e) => write!(f, "pcapのエラーです: {}", e),
+            NidsError::Io(e) => write!(f, "入出力のエラーです: {}", e),
+            NidsError::DeviceNotFound(name) => write!(f, "デバイスが見つかりません: {}", name),
+            NidsError::InvalidDeviceSelection(input) => write!(f, "無効なデバイス番号です: {}", input),
+            NidsError::CaptureFileNotFound(path) => {
+                write!(f, "キャプチャファイルが見つかりません: {}", path.display())
+            }
+            NidsError::Database(e) => write!(f, "データベースのエラーです: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for NidsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NidsError::Pcap(e) => Some(e),
+            NidsError::Io(e) => Some(e),
+            NidsError::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<pcap::Error> for NidsError {
+    fn from(e: pcap::Error) -> Self {
+        NidsError::Pcap(e)
+    }
+}
+
+impl From<io::Error> for NidsError {
+    fn from(e: io::Error) -> Self {
+        NidsError::Io(e)
+    }
+}
+
+impl From<mysql::Error> for NidsError {
+    fn from(e: mysql::Error) -> Self {
+        NidsError::Database(e)
+    }
+}
@@ -0,0 +1,257 @@
+use chrono::{DateTime, Local};
+use serde_json::{Map, Value};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+// 構造化したイベント(JSON Lines)の出力先
+#[derive(Debug, Clone, PartialEq)]
+pub enum EveTarget {
+    Stdout,
+    File(PathBuf),
+    Unix(PathBuf), // 待ち受けているUnixドメインソケットに接続して書き込む
+}
+
+impl FromStr for EveTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let path = |prefix: &str| {
+            let path = &s.trim()[prefix.len()..];
+            if path.is_empty() {
+                return Err(format!("{} の後にパスを指定してください", prefix));
+            }
+            Ok(PathBuf::from(path))
+        };
+        match s.trim() {
+            "stdout" | "-" => Ok(EveTarget::Stdout),
+            s if s.starts_with("file:") => Ok(EveTarget::File(path("file:")?)),
+            s if s.starts_with("unix:") => Ok(EveTarget::Unix(path("unix:")?)),
+            s => Err(format!("不明なイベントの出力先です: {} (stdout, file:<path>, unix:<path>)", s)),
+        }
+    }
+}
+
+enum EveWriter {
+    Stdout(io::Stdout),
+    File(BufWriter<File>),
+    #[cfg(unix)]
+    Unix(BufWriter<UnixStream>),
+}
+
+impl EveWriter {
+    fn as_write(&mut self) -> &mut dyn Write {
+        match self {
+            EveWriter::Stdout(w) => w,
+            EveWriter::File(w) => w,
+            #[cfg(unix)]
+            EveWriter::Unix(w) => w,
+        }
+    }
+}
+
+// イベントが属するフロー
+// flow_idは方向によらず同じ値になるため、同じ接続のイベントを結び付けられる
+#[derive(Debug, Clone)]
+pub struct EveFlow {
+    pub flow_id: u64,
+    pub proto: &'static str, // TCP, UDP, ICMP, IPv6-ICMP
+    pub src_ip: IpAddr,
+    pub src_port: Option<u16>,
+    pub dest_ip: IpAddr,
+    pub dest_port: Option<u16>,
+}
+
+impl EveFlow {
+    pub fn new(
+        proto: &'static str,
+        src_ip: IpAddr,
+        src_port: Option<u16>,
+        dest_ip: IpAddr,
+        dest_port: Option<u16>,
+    ) -> Self {
+        EveFlow {
+            flow_id: flow_id(proto, (src_ip, src_port.unwrap_or(0)), (dest_ip, dest_port.unwrap_or(0))),
+            proto,
+            src_ip,
+            src_port,
+            dest_ip,
+            dest_port,
+        }
+    }
+
+    // ストリームとUDPフローのキー (クライアント, サーバーの順) から作成する
+    pub fn from_key(proto: &'static str, key: &(IpAddr, u16, IpAddr, u16)) -> Self {
+        EveFlow::new(proto, key.0, Some(key.1), key.2, Some(key.3))
+    }
+}
+
+// プロトコルと両端のアドレスからフローIDを計算する (FNV-1a、実行ごとに変わらない)
+// 両端を並べ替えてから計算するため、どちらの方向のパケットからも同じ値になる
+fn flow_id(proto: &str, a: (IpAddr, u16), b: (IpAddr, u16)) -> u64 {
+    let (first, second) = if a <= b { (a, b) } else { (b, a) };
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut feed = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    };
+    feed(proto.as_bytes());
+    for (ip, port) in [first, second] {
+        match ip {
+            IpAddr::V4(ip) => feed(&ip.octets()),
+            IpAddr::V6(ip) => feed(&ip.octets()),
+        }
+        feed(&port.to_be_bytes());
+    }
+    // 符号付き64ビット整数として扱うツールのため正の値に収める
+    hash & 0x7FFF_FFFF_FFFF_FFFF
+}
+
+// キャプチャ時刻をイベントのタイムスタンプに変換する
+pub fn timestamp_string(time: SystemTime) -> String {
+    let datetime: DateTime<Local> = time.into();
+    datetime.format("%Y-%m-%dT%H:%M:%S%.6f%z").to_string()
+}
+
+// EVE形式(JSON Lines)のイベントを複数の出力先へ書き出す
+// 各行はtimestamp、event_type、フローの情報と、event_typeと同名のキーの詳細からなる
+pub struct EveLog {
+    writers: Vec<EveWriter>,
+}
+
+impl EveLog {
+    pub fn open(targets: &[EveTarget]) -> io::Result<Self> {
+        let mut writers = Vec::new();
+        for target in targets {
+            let writer = match target {
+                EveTarget::Stdout => EveWriter::Stdout(io::stdout()),
+                EveTarget::File(path) => {
+                    let file = OpenOptions::new().create(true).append(true).open(path)?;
+                    EveWriter::File(BufWriter::new(file))
+                }
+                #[cfg(unix)]
+                EveTarget::Unix(path) => EveWriter::Unix(BufWriter::new(UnixStream::connect(path)?)),
+                #[cfg(not(unix))]
+                EveTarget::Unix(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "Unixドメインソケットはこのプラットフォームでは使用できません",
+                    ))
+                }
+            };
+            writers.push(writer);
+        }
+        Ok(EveLog { writers })
+    }
+
+    pub fn write_event(&mut self, timestamp: SystemTime, event_type: &str, flow: Option<&EveFlow>, details: Value) {
+        let mut event = Map::new();
+        event.insert("timestamp".to_string(), timestamp_string(timestamp).into());
+        if let Some(flow) = flow {
+            event.insert("flow_id".to_string(), flow.flow_id.into());
+        }
+        event.insert("event_type".to_string(), event_type.into());
+        if let Some(flow) = flow {
+            event.insert("src_ip".to_string(), flow.src_ip.to_string().into());
+            if let Some(port) = flow.src_port {
+                event.insert("src_port".to_string(), port.into());
+            }
+            event.insert("dest_ip".to_string(), flow.dest_ip.to_string().into());
+            if let Some(port) = flow.dest_port {
+                event.insert("dest_port".to_string(), port.into());
+            }
+            event.insert("proto".to_string(), flow.proto.into());
+        }
+        event.insert(event_type.to_string(), details);
+
+        let line = Value::Object(event).to_string();
+        for writer in &mut self.writers {
+            if let Err(e) = writeln!(writer.as_write(), "{}", line) {
+                eprintln!("イベントの書き込みに失敗しました: {}", e);
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        for writer in &mut self.writers {
+            if let Err(e) = writer.as_write().flush() {
+                eprintln!("イベントのフラッシュに失敗しました: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_targets() {
+        assert_eq!("stdout".parse(), Ok(EveTarget::Stdout));
+        assert_eq!(" file:eve.json".parse(), Ok(EveTarget::File(PathBuf::from("eve.json"))));
+        assert_eq!("unix:/run/nids.sock".parse(), Ok(EveTarget::Unix(PathBuf::from("/run/nids.sock"))));
+        assert!("file:".parse::<EveTarget>().is_err());
+        assert!("tcp:127.0.0.1:9000".parse::<EveTarget>().is_err());
+    }
+
+    #[test]
+    fn flow_id_is_same_in_both_directions() {
+        let client = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let server = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let request = EveFlow::new("TCP", client, Some(50000), server, Some(80));
+        let response = EveFlow::new("TCP", server, Some(80), client, Some(50000));
+        assert_eq!(request.flow_id, response.flow_id);
+        assert!(request.flow_id <= i64::MAX as u64);
+
+        // プロトコルやポートが違えば別のフローになる
+        assert_ne!(request.flow_id, EveFlow::new("UDP", client, Some(50000), server, Some(80)).flow_id);
+        assert_ne!(request.flow_id, EveFlow::new("TCP", client, Some(50001), server, Some(80)).flow_id);
+    }
+
+    #[test]
+    fn writes_one_json_object_per_line() {
+        let path = std::env::temp_dir().join(format!("nids-eve-test-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut eve = EveLog::open(&[EveTarget::File(path.clone())]).unwrap();
+        let flow = EveFlow::new(
+            "TCP",
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            Some(50000),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            Some(80),
+        );
+        let time = SystemTime::UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        eve.write_event(time, "alert", Some(&flow), json!({"signature": "test"}));
+        eve.write_event(time, "stats", None, json!({"packets": 1}));
+        eve.flush();
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let events: Vec<Value> = content.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event_type"], "alert");
+        assert_eq!(events[0]["flow_id"], flow.flow_id);
+        assert_eq!(events[0]["src_port"], 50000);
+        assert_eq!(events[0]["dest_ip"], "10.0.0.2");
+        assert_eq!(events[0]["proto"], "TCP");
+        assert_eq!(events[0]["alert"]["signature"], "test");
+        assert_eq!(events[0]["timestamp"], timestamp_string(time));
+        assert!(timestamp_string(time).contains(".123456"));
+
+        // フローのないイベントにはフローの項目を出力しない
+        assert_eq!(events[1]["event_type"], "stats");
+        assert!(events[1].get("flow_id").is_none());
+        assert_eq!(events[1]["stats"]["packets"], 1);
+    }
+}
@@ -0,0 +1,438 @@
+use crate::capture_clock::elapsed_between;
+use crate::icmp_header::{IcmpHeader, IcmpMessage};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+// 1つの送信元がこの期間内にこの数以上のホストへエコー要求を送った場合はping sweepとみなす
+const PING_SWEEP_WINDOW: Duration = Duration::from_secs(10);
+const PING_SWEEP_THRESHOLD: usize = 20;
+
+// 1つの宛先がこの期間内にこの数を超えるICMPを受け取った場合はフラッドとみなす
+const ICMP_FLOOD_WINDOW: Duration = Duration::from_secs(1);
+const ICMP_FLOOD_THRESHOLD: u64 = 1000;
+
+// 同じ送信元からのリダイレクトはこの間隔で一度だけ通知する
+const REDIRECT_ALERT_INTERVAL: Duration = Duration::from_secs(60);
+
+// 通常のpingより大きいエコーのペイロード (Linuxは56バイト、Windowsは32バイト)
+const MAX_ECHO_PAYLOAD: usize = 1024;
+
+// 同じ送信元と宛先の大きいエコーはこの間隔で一度だけ通知し、間の件数は次の通知に含める
+const OVERSIZED_ECHO_ALERT_INTERVAL: Duration = Duration::from_secs(60);
+
+// 通知しなかった件数が残っている記録を次の通知のために保持する期間
+const SUPPRESSED_RETENTION: Duration = Duration::from_secs(600);
+
+// 応答を待つエコー要求の保持期間と件数の上限
+const ECHO_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_PENDING_ECHOES: usize = 65536;
+
+// ICMPの検知イベント
+#[derive(Debug, Clone)]
+pub enum IcmpEvent {
+    // 多数のホストへのエコー要求
+    PingSweep {
+        src_ip: IpAddr,
+        hosts: usize,
+        window: Duration,
+    },
+    // 1つの宛先への大量のICMP
+    IcmpFlood {
+        dst_ip: IpAddr,
+        count: u64,
+        window: Duration,
+    },
+    // 経路の書き換えに使われるリダイレクト
+    Redirect {
+        src_ip: IpAddr,
+        dst_ip: IpAddr,
+        gateway: Option<IpAddr>,
+    },
+    // 通常より大きいペイロードのエコー
+    OversizedEcho {
+        src_ip: IpAddr,
+        dst_ip: IpAddr,
+        size: usize,
+        suppressed: u64, // 前回の通知以降に通知しなかった数
+    },
+    // 要求と異なるペイロードの応答 (ICMPトンネルの疑い)
+    EchoPayloadMismatch {
+        src_ip: IpAddr,
+        dst_ip: IpAddr,
+        identifier: u16,
+        sequence: u16,
+    },
+}
+
+impl IcmpEvent {
+    // イベントの種類を表す名前 (構造化した出力に使う)
+    pub fn name(&self) -> &'static str {
+        match self {
+            IcmpEvent::PingSweep { .. } => "ping_sweep",
+            IcmpEvent::IcmpFlood { .. } => "icmp_flood",
+            IcmpEvent::Redirect { .. } => "icmp_redirect",
+            IcmpEvent::OversizedEcho { .. } => "oversized_echo",
+            IcmpEvent::EchoPayloadMismatch { .. } => "echo_payload_mismatch",
+        }
+    }
+}
+
+impl fmt::Display for IcmpEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IcmpEvent::PingSweep { src_ip, hosts, window } => write!(
+                f,
+                "ping sweep ({} sent echo requests to {} hosts within {:?})",
+                src_ip, hosts, window
+            ),
+            IcmpEvent::IcmpFlood { dst_ip, count, window } => write!(
+                f,
+                "ICMP flood ({} received {} ICMP messages within {:?})",
+                dst_ip, count, window
+            ),
+            IcmpEvent::Redirect { src_ip, dst_ip, gateway: Some(gateway) } => write!(
+                f,
+                "ICMP redirect ({} -> {} gateway={})",
+                src_ip, dst_ip, gateway
+            ),
+            IcmpEvent::Redirect { src_ip, dst_ip, gateway: None } => {
+                write!(f, "ICMP redirect ({} -> {})", src_ip, dst_ip)
+            }
+            IcmpEvent::OversizedEcho { src_ip, dst_ip, size, suppressed: 0 } => write!(
+                f,
+                "oversized echo payload ({} -> {} size={})",
+                src_ip, dst_ip, size
+            ),
+            IcmpEvent::OversizedEcho { src_ip, dst_ip, size, suppressed } => write!(
+                f,
+                "oversized echo payload ({} -> {} size={}, {} suppressed)",
+                src_ip, dst_ip, size, suppressed
+            ),
+            IcmpEvent::EchoPayloadMismatch { src_ip, dst_ip, identifier, sequence } => write!(
+                f,
+                "echo reply payload differs from the request, possible ICMP tunnel ({} -> {} id={} seq={})",
+                src_ip, dst_ip, identifier, sequence
+            ),
+        }
+    }
+}
+
+// 送信元ごとのエコー要求の宛先
+struct SweepCounter {
+    window_start: SystemTime,
+    hosts: HashSet<IpAddr>,
+    alerted: bool,
+}
+
+// 宛先ごとのICMP数
+struct FloodCounter {
+    window_start: SystemTime,
+    count: u64,
+    alerted: bool,
+}
+
+// (送信元, 宛先)ごとの大きいエコーの通知
+struct OversizedEchoAlert {
+    last_alert: SystemTime,
+    suppressed: u64, // 次の通知に含める件数
+}
+
+// 応答を待つエコー要求 (ペイロードはハッシュのみ保持する)
+struct PendingEcho {
+    payload_hash: u64,
+    sent: SystemTime,
+}
+
+// エコー要求を識別するキー (要求の送信元, 宛先, ID, シーケンス番号)
+type EchoKey = (IpAddr, IpAddr, u16, u16);
+
+// ICMPのping sweep、フラッド、リダイレクト、異常なエコーを検知する
+#[derive(Default)]
+pub struct IcmpDetector {
+    sweep_counters: HashMap<IpAddr, SweepCounter>,
+    flood_counters: HashMap<IpAddr, FloodCounter>,
+    redirect_alerts: HashMap<IpAddr, SystemTime>, // 送信元ごとの最後に通知した時刻
+    oversized_echo_alerts: HashMap<(IpAddr, IpAddr), OversizedEchoAlert>,
+    pending_echoes: HashMap<EchoKey, PendingEcho>,
+    events: Vec<IcmpEvent>, // 未出力のイベント
+}
+
+impl IcmpDetector {
+    pub fn new() -> Self {
+        IcmpDetector::default()
+    }
+
+    // ICMPメッセージを検査する (payloadはICMPヘッダー以降のデータ)
+    pub fn inspect(
+        &mut self,
+        src_ip: IpAddr,
+        dst_ip: IpAddr,
+        header: &IcmpHeader,
+        payload: &[u8],
+        now: SystemTime,
+    ) {
+        self.count_flood(dst_ip, now);
+
+        match header.message(payload) {
+            IcmpMessage::EchoRequest { identifier, sequence } => {
+                self.count_sweep(src_ip, dst_ip, now);
+                self.check_echo_size(src_ip, dst_ip, payload, now);
+                if self.pending_echoes.len() < MAX_PENDING_ECHOES {
+                    self.pending_echoes.insert(
+                        (src_ip, dst_ip, identifier, sequence),
+                        PendingEcho {
+                            payload_hash: payload_hash(payload),
+                            sent: now,
+                        },
+                    );
+                }
+            }
+            IcmpMessage::EchoReply { identifier, sequence } => {
+                self.check_echo_size(src_ip, dst_ip, payload, now);
+                // 応答は要求のペイロードをそのまま返すため、異なる場合はデータを運んでいる疑いがある
+                if let Some(request) = self.pending_echoes.remove(&(dst_ip, src_ip, identifier, sequence)) {
+                    if request.payload_hash != payload_hash(payload) {
+                        self.events.push(IcmpEvent::EchoPayloadMismatch {
+                            src_ip,
+                            dst_ip,
+                            identifier,
+                            sequence,
+                        });
+                    }
+                }
+            }
+            IcmpMessage::Redirect { gateway, .. } => {
+                let alerted_recently = self
+                    .redirect_alerts
+                    .get(&src_ip)
+                    .is_some_and(|last| elapsed_between(*last, now) < REDIRECT_ALERT_INTERVAL);
+                if !alerted_recently {
+                    self.redirect_alerts.insert(src_ip, now);
+                    self.events.push(IcmpEvent::Redirect { src_ip, dst_ip, gateway });
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // 宛先ごとのICMP数を数え、閾値を超えたら期間ごとに一度だけ通知する
+    fn count_flood(&mut self, dst_ip: IpAddr, now: SystemTime) {
+        let counter = self.flood_counters.entry(dst_ip).or_insert(FloodCounter {
+            window_start: now,
+            count: 0,
+            alerted: false,
+        });
+
+        if elapsed_between(counter.window_start, now) >= ICMP_FLOOD_WINDOW {
+            counter.window_start = now;
+            counter.count = 0;
+            counter.alerted = false;
+        }
+
+        counter.count += 1;
+        if counter.count > ICMP_FLOOD_THRESHOLD && !counter.alerted {
+            counter.alerted = true;
+            self.events.push(IcmpEvent::IcmpFlood {
+                dst_ip,
+                count: counter.count,
+                window: ICMP_FLOOD_WINDOW,
+            });
+        }
+    }
+
+    // 送信元ごとにエコー要求の宛先を数え、閾値に達したら期間ごとに一度だけ通知する
+    fn count_sweep(&mut self, src_ip: IpAddr, dst_ip: IpAddr, now: SystemTime) {
+        let counter = self.sweep_counters.entry(src_ip).or_insert(SweepCounter {
+            window_start: now,
+            hosts: HashSet::new(),
+            alerted: false,
+        });
+
+        if elapsed_between(counter.window_start, now) >= PING_SWEEP_WINDOW {
+            counter.window_start = now;
+            counter.hosts.clear();
+            counter.alerted = false;
+        }
+
+        counter.hosts.insert(dst_ip);
+        if counter.hosts.len() >= PING_SWEEP_THRESHOLD && !counter.alerted {
+            counter.alerted = true;
+            self.events.push(IcmpEvent::PingSweep {
+                src_ip,
+                hosts: counter.hosts.len(),
+                window: PING_SWEEP_WINDOW,
+            });
+        }
+    }
+
+    // 大きいエコーは大量に送られても(送信元, 宛先)ごとに間隔を空けて通知する
+    fn check_echo_size(&mut self, src_ip: IpAddr, dst_ip: IpAddr, payload: &[u8], now: SystemTime) {
+        if payload.len() <= MAX_ECHO_PAYLOAD {
+            return;
+        }
+        if let Some(alert) = self.oversized_echo_alerts.get_mut(&(src_ip, dst_ip)) {
+            if elapsed_between(alert.last_alert, now) < OVERSIZED_ECHO_ALERT_INTERVAL {
+                alert.suppressed += 1;
+                return;
+            }
+        }
+        let suppressed = self
+            .oversized_echo_alerts
+            .insert((src_ip, dst_ip), OversizedEchoAlert { last_alert: now, suppressed: 0 })
+            .map_or(0, |previous| previous.suppressed);
+        self.events.push(IcmpEvent::OversizedEcho {
+            src_ip,
+            dst_ip,
+            size: payload.len(),
+            suppressed,
+        });
+    }
+
+    // 未出力のイベントを取り出す
+    pub fn take_events(&mut self) -> Vec<IcmpEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    // キャプチャ時刻nowを基準に期間の過ぎたカウンターと応答のないエコー要求を削除する
+    pub fn cleanup(&mut self, now: SystemTime) {
+        self.sweep_counters
+            .retain(|_, counter| elapsed_between(counter.window_start, now) < PING_SWEEP_WINDOW);
+        self.flood_counters
+            .retain(|_, counter| elapsed_between(counter.window_start, now) < ICMP_FLOOD_WINDOW);
+        self.redirect_alerts
+            .retain(|_, last| elapsed_between(*last, now) < REDIRECT_ALERT_INTERVAL);
+        self.oversized_echo_alerts.retain(|_, alert| {
+            let retention = if alert.suppressed > 0 {
+                SUPPRESSED_RETENTION
+            } else {
+                OVERSIZED_ECHO_ALERT_INTERVAL
+            };
+            elapsed_between(alert.last_alert, now) < retention
+        });
+        self.pending_echoes
+            .retain(|_, echo| elapsed_between(echo.sent, now) < ECHO_TIMEOUT);
+    }
+}
+
+fn payload_hash(payload: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    payload.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::icmp_header::{parse_icmp_header, ICMP_ECHO_REPLY, ICMP_ECHO_REQUEST, ICMP_REDIRECT};
+    use std::net::Ipv4Addr;
+
+    fn at(millis: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    fn host(n: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, n))
+    }
+
+    fn icmp(icmp_type: u8, rest: [u8; 4]) -> IcmpHeader {
+        let data = [&[icmp_type, 0, 0, 0][..], &rest].concat();
+        parse_icmp_header(&data, false).unwrap().0
+    }
+
+    fn names(detector: &mut IcmpDetector) -> Vec<&'static str> {
+        detector.take_events().iter().map(IcmpEvent::name).collect()
+    }
+
+    #[test]
+    fn ping_sweep_is_reported_once_per_window() {
+        let mut detector = IcmpDetector::new();
+        let request = icmp(ICMP_ECHO_REQUEST, [0, 1, 0, 1]);
+        for n in 1..PING_SWEEP_THRESHOLD as u8 {
+            detector.inspect(host(200), host(n), &request, b"ping", at(0));
+        }
+        assert!(detector.take_events().is_empty());
+        detector.inspect(host(200), host(100), &request, b"ping", at(0));
+        detector.inspect(host(200), host(101), &request, b"ping", at(0));
+        assert_eq!(names(&mut detector), ["ping_sweep"]);
+
+        // 期間が過ぎると数え直す
+        detector.inspect(host(200), host(1), &request, b"ping", at(10_000));
+        assert!(detector.take_events().is_empty());
+    }
+
+    #[test]
+    fn icmp_flood_to_one_destination() {
+        let mut detector = IcmpDetector::new();
+        let reply = icmp(ICMP_ECHO_REPLY, [0; 4]);
+        for _ in 0..=ICMP_FLOOD_THRESHOLD + 10 {
+            detector.inspect(host(1), host(2), &reply, b"", at(500));
+        }
+        let events = detector.take_events();
+        assert!(matches!(
+            events.as_slice(),
+            [IcmpEvent::IcmpFlood { count, .. }] if *count == ICMP_FLOOD_THRESHOLD + 1
+        ));
+    }
+
+    #[test]
+    fn reply_payload_must_match_request() {
+        let mut detector = IcmpDetector::new();
+        let request = icmp(ICMP_ECHO_REQUEST, [0, 5, 0, 1]);
+        let reply = icmp(ICMP_ECHO_REPLY, [0, 5, 0, 1]);
+        detector.inspect(host(1), host(2), &request, b"abcdefgh", at(0));
+        detector.inspect(host(2), host(1), &reply, b"abcdefgh", at(1));
+        detector.inspect(host(1), host(2), &request, b"abcdefgh", at(2));
+        detector.inspect(host(2), host(1), &reply, b"secret!!", at(3));
+        let events = detector.take_events();
+        assert!(matches!(
+            events.as_slice(),
+            [IcmpEvent::EchoPayloadMismatch { identifier: 5, sequence: 1, .. }]
+        ));
+
+        // 要求のない応答や時間が経った要求への応答は比較しない
+        detector.inspect(host(2), host(1), &reply, b"secret!!", at(4));
+        detector.inspect(host(1), host(2), &request, b"abcdefgh", at(5));
+        detector.cleanup(at(30_005));
+        detector.inspect(host(2), host(1), &reply, b"secret!!", at(30_006));
+        assert!(detector.take_events().is_empty());
+    }
+
+    #[test]
+    fn redirect_is_rate_limited_per_source() {
+        let mut detector = IcmpDetector::new();
+        let redirect = icmp(ICMP_REDIRECT, [192, 0, 2, 254]);
+        detector.inspect(host(1), host(2), &redirect, b"", at(0));
+        detector.inspect(host(1), host(3), &redirect, b"", at(1_000));
+        detector.inspect(host(4), host(2), &redirect, b"", at(2_000));
+        let events = detector.take_events();
+        assert!(matches!(
+            events.as_slice(),
+            [IcmpEvent::Redirect { gateway: Some(g1), .. }, IcmpEvent::Redirect { .. }] if *g1 == host(254)
+        ));
+        detector.inspect(host(1), host(2), &redirect, b"", at(60_000));
+        assert_eq!(names(&mut detector), ["icmp_redirect"]);
+    }
+
+    #[test]
+    fn oversized_echo_alerts_are_rate_limited_per_pair() {
+        let mut detector = IcmpDetector::new();
+        let request = icmp(ICMP_ECHO_REQUEST, [0; 4]);
+        let payload = vec![0; MAX_ECHO_PAYLOAD + 1];
+        for millis in 0..5 {
+            detector.inspect(host(1), host(2), &request, &payload, at(millis));
+        }
+        detector.inspect(host(1), host(3), &request, &payload, at(5));
+        detector.inspect(host(1), host(2), &request, &payload[..MAX_ECHO_PAYLOAD], at(6));
+        assert_eq!(names(&mut detector), ["oversized_echo", "oversized_echo"]);
+
+        // 通知しなかった件数は間隔が過ぎた後の通知に含める
+        detector.cleanup(at(120_000));
+        detector.inspect(host(1), host(2), &request, &payload, at(120_000));
+        let events = detector.take_events();
+        assert!(matches!(events.as_slice(), [IcmpEvent::OversizedEcho { suppressed: 4, .. }]));
+    }
+}
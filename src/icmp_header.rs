@@ -0,0 +1,335 @@
+use crate::ip_header::parse_ip_header;
+use crate::ipv6_header::parse_ipv6_header;
+use crate::packet_processor::{IPPROTO_TCP, IPPROTO_UDP};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// 0                   1                   2                   3
+// 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |     Type      |     Code      |          Checksum             |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |                     (タイプごとの内容)                        |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// ICMPとICMPv6のヘッダーは先頭8バイトが共通の形式
+
+// ICMPのタイプ
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_DEST_UNREACHABLE: u8 = 3;
+pub const ICMP_SOURCE_QUENCH: u8 = 4;
+pub const ICMP_REDIRECT: u8 = 5;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+pub const ICMP_TIME_EXCEEDED: u8 = 11;
+pub const ICMP_PARAMETER_PROBLEM: u8 = 12;
+
+// 宛先到達不能のコード
+pub const ICMP_PORT_UNREACHABLE: u8 = 3;
+pub const ICMP_FRAGMENTATION_NEEDED: u8 = 4;
+
+// ICMPv6のタイプ
+pub const ICMPV6_DEST_UNREACHABLE: u8 = 1;
+pub const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+pub const ICMPV6_TIME_EXCEEDED: u8 = 3;
+pub const ICMPV6_PARAMETER_PROBLEM: u8 = 4;
+pub const ICMPV6_ECHO_REQUEST: u8 = 128;
+pub const ICMPV6_ECHO_REPLY: u8 = 129;
+pub const ICMPV6_REDIRECT: u8 = 137;
+
+// ICMPv6の宛先到達不能のコード
+pub const ICMPV6_PORT_UNREACHABLE: u8 = 4;
+
+const ICMP_HEADER_SIZE: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct IcmpHeader {
+    pub ipv6: bool, // ICMPv6かどうか
+    pub icmp_type: u8,
+    pub code: u8,
+    pub checksum: u16,
+    pub rest_of_header: [u8; 4], // タイプごとの内容 (エコーのID/シーケンス番号、MTUなど)
+}
+
+// タイプとコードを解釈したICMPメッセージ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IcmpMessage {
+    EchoRequest { identifier: u16, sequence: u16 },
+    EchoReply { identifier: u16, sequence: u16 },
+    PortUnreachable,
+    DestinationUnreachable { code: u8 },
+    // IPv4のDF付きパケットに対するフラグメント要求、またはICMPv6のPacket Too Big
+    FragmentationNeeded { mtu: u32 },
+    TimeExceeded { code: u8 },
+    Redirect { code: u8, gateway: Option<IpAddr> },
+    ParameterProblem { code: u8, pointer: u32 },
+    SourceQuench,
+    Other { icmp_type: u8, code: u8 },
+}
+
+// ICMP/ICMPv6ヘッダーを解析し、ヘッダーと以降のデータを返す
+pub fn parse_icmp_header(data: &[u8], ipv6: bool) -> Option<(IcmpHeader, &[u8])> {
+    if data.len() < ICMP_HEADER_SIZE {
+        return None;
+    }
+
+    Some((
+        IcmpHeader {
+            ipv6,
+            icmp_type: data[0],
+            code: data[1],
+            checksum: u16::from_be_bytes([data[2], data[3]]),
+            rest_of_header: [data[4], data[5], data[6], data[7]],
+        },
+        &data[ICMP_HEADER_SIZE..],
+    ))
+}
+
+impl IcmpHeader {
+    // タイプとコードからメッセージを解釈する
+    // ICMPv6のリダイレクトのターゲットはヘッダー以降のデータにあるため、bodyも渡す
+    pub fn message(&self, body: &[u8]) -> IcmpMessage {
+        let rest = self.rest_of_header;
+        let identifier = u16::from_be_bytes([rest[0], rest[1]]);
+        let sequence = u16::from_be_bytes([rest[2], rest[3]]);
+        let code = self.code;
+
+        if self.ipv6 {
+            match self.icmp_type {
+                ICMPV6_ECHO_REQUEST => IcmpMessage::EchoRequest { identifier, sequence },
+                ICMPV6_ECHO_REPLY => IcmpMessage::EchoReply { identifier, sequence },
+                ICMPV6_DEST_UNREACHABLE if code == ICMPV6_PORT_UNREACHABLE => IcmpMessage::PortUnreachable,
+                ICMPV6_DEST_UNREACHABLE => IcmpMessage::DestinationUnreachable { code },
+                ICMPV6_PACKET_TOO_BIG => IcmpMessage::FragmentationNeeded { mtu: u32::from_be_bytes(rest) },
+                ICMPV6_TIME_EXCEEDED => IcmpMessage::TimeExceeded { code },
+                ICMPV6_PARAMETER_PROBLEM => IcmpMessage::ParameterProblem {
+                    code,
+                    pointer: u32::from_be_bytes(rest),
+                },
+                ICMPV6_REDIRECT => IcmpMessage::Redirect {
+                    code,
+                    gateway: body
+                        .get(..16)
+                        .and_then(|target| <[u8; 16]>::try_from(target).ok())
+                        .map(|target| IpAddr::V6(Ipv6Addr::from(target))),
+                },
+                icmp_type => IcmpMessage::Other { icmp_type, code },
+            }
+        } else {
+            match self.icmp_type {
+                ICMP_ECHO_REQUEST => IcmpMessage::EchoRequest { identifier, sequence },
+                ICMP_ECHO_REPLY => IcmpMessage::EchoReply { identifier, sequence },
+                ICMP_DEST_UNREACHABLE if code == ICMP_PORT_UNREACHABLE => IcmpMessage::PortUnreachable,
+                ICMP_DEST_UNREACHABLE if code == ICMP_FRAGMENTATION_NEEDED => {
+                    IcmpMessage::FragmentationNeeded { mtu: sequence as u32 }
+                }
+                ICMP_DEST_UNREACHABLE => IcmpMessage::DestinationUnreachable { code },
+                ICMP_TIME_EXCEEDED => IcmpMessage::TimeExceeded { code },
+                ICMP_PARAMETER_PROBLEM => IcmpMessage::ParameterProblem { code, pointer: rest[0] as u32 },
+                ICMP_REDIRECT => IcmpMessage::Redirect {
+                    code,
+                    gateway: Some(IpAddr::V4(Ipv4Addr::from(rest))),
+                },
+                ICMP_SOURCE_QUENCH => IcmpMessage::SourceQuench,
+                icmp_type => IcmpMessage::Other { icmp_type, code },
+            }
+        }
+    }
+
+    // 元のパケットのヘッダーを引用するエラーメッセージかどうか
+    pub fn is_error(&self) -> bool {
+        if self.ipv6 {
+            // ICMPv6はタイプの最上位ビットが0のものがエラーメッセージ
+            self.icmp_type < 128
+        } else {
+            matches!(
+                self.icmp_type,
+                ICMP_DEST_UNREACHABLE | ICMP_SOURCE_QUENCH | ICMP_REDIRECT | ICMP_TIME_EXCEEDED | ICMP_PARAMETER_PROBLEM
+            )
+        }
+    }
+
+    pub fn is_echo_request(&self) -> bool {
+        self.icmp_type == if self.ipv6 { ICMPV6_ECHO_REQUEST } else { ICMP_ECHO_REQUEST }
+    }
+}
+
+impl fmt::Display for IcmpMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IcmpMessage::EchoRequest { identifier, sequence } => {
+                write!(f, "echo request id={} seq={}", identifier, sequence)
+            }
+            IcmpMessage::EchoReply { identifier, sequence } => {
+                write!(f, "echo reply id={} seq={}", identifier, sequence)
+            }
+            IcmpMessage::PortUnreachable => write!(f, "port unreachable"),
+            IcmpMessage::DestinationUnreachable { code } => write!(f, "destination unreachable code={}", code),
+            IcmpMessage::FragmentationNeeded { mtu } => write!(f, "fragmentation needed mtu={}", mtu),
+            IcmpMessage::TimeExceeded { code } => write!(f, "time exceeded code={}", code),
+            IcmpMessage::Redirect { code, gateway: Some(gateway) } => {
+                write!(f, "redirect code={} gateway={}", code, gateway)
+            }
+            IcmpMessage::Redirect { code, gateway: None } => write!(f, "redirect code={}", code),
+            IcmpMessage::ParameterProblem { code, pointer } => {
+                write!(f, "parameter problem code={} pointer={}", code, pointer)
+            }
+            IcmpMessage::SourceQuench => write!(f, "source quench"),
+            IcmpMessage::Other { icmp_type, code } => write!(f, "type={} code={}", icmp_type, code),
+        }
+    }
+}
+
+// エラーメッセージに引用された元のパケットのヘッダー
+// 引用はIPヘッダーと上位層の先頭8バイト以上が含まれる
+#[derive(Debug, Clone)]
+pub struct QuotedPacket {
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    pub protocol: u8,
+    pub src_port: Option<u16>, // TCP/UDPの場合のみ
+    pub dst_port: Option<u16>,
+    pub tcp_seq: Option<u32>, // TCPの場合のみ
+}
+
+// エラーメッセージのデータから引用されたIPヘッダーと上位層のヘッダーを取り出す
+pub fn parse_quoted_packet(header: &IcmpHeader, body: &[u8]) -> Option<QuotedPacket> {
+    if !header.is_error() {
+        return None;
+    }
+
+    let (src_ip, dst_ip, protocol, transport) = if header.ipv6 {
+        let (ipv6_header, header_size) = parse_ipv6_header(body)?;
+        // フラグメントの先頭以外には上位層のヘッダーがない
+        let transport = match &ipv6_header.fragment {
+            Some(fragment) if fragment.offset != 0 => &[][..],
+            _ => body.get(header_size..).unwrap_or(&[]),
+        };
+        (IpAddr::V6(ipv6_header.src_ip), IpAddr::V6(ipv6_header.dst_ip), ipv6_header.protocol, transport)
+    } else {
+        let (ip_header, header_size) = parse_ip_header(body)?;
+        let transport = match ip_header.flags_fragment_offset & 0x1FFF {
+            0 => &body[header_size..],
+            _ => &[][..],
+        };
+        (IpAddr::V4(ip_header.src_ip), IpAddr::V4(ip_header.dst_ip), ip_header.protocol, transport)
+    };
+
+    let mut quoted = QuotedPacket {
+        src_ip,
+        dst_ip,
+        protocol,
+        src_port: None,
+        dst_port: None,
+        tcp_seq: None,
+    };
+
+    // TCPとUDPはどちらも先頭4バイトがポート番号
+    if matches!(protocol, IPPROTO_TCP | IPPROTO_UDP) && transport.len() >= 4 {
+        quoted.src_port = Some(u16::from_be_bytes([transport[0], transport[1]]));
+        quoted.dst_port = Some(u16::from_be_bytes([transport[2], transport[3]]));
+    }
+    if protocol == IPPROTO_TCP && transport.len() >= 8 {
+        quoted.tcp_seq = Some(u32::from_be_bytes([transport[4], transport[5], transport[6], transport[7]]));
+    }
+
+    Some(quoted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(ipv6: bool, icmp_type: u8, code: u8, rest: [u8; 4]) -> IcmpHeader {
+        let data = [&[icmp_type, code, 0xAB, 0xCD][..], &rest].concat();
+        parse_icmp_header(&data, ipv6).unwrap().0
+    }
+
+    // 引用されたIPv4ヘッダー(10.0.0.1 -> 10.0.0.2)と上位層の先頭8バイト
+    fn quoted_ipv4(protocol: u8, flags_fragment_offset: u16) -> Vec<u8> {
+        let mut data = vec![0x45, 0, 0, 48, 0, 1];
+        data.extend_from_slice(&flags_fragment_offset.to_be_bytes());
+        data.extend_from_slice(&[64, protocol, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+        data.extend_from_slice(&[0x9C, 0x40, 0x00, 0x50, 0x00, 0x00, 0x03, 0xE8]);
+        data
+    }
+
+    #[test]
+    fn parses_header_and_body() {
+        let (icmp, body) = parse_icmp_header(&[8, 0, 0xAB, 0xCD, 0, 1, 0, 2, b'x'], false).unwrap();
+        assert_eq!((icmp.icmp_type, icmp.code, icmp.checksum), (8, 0, 0xABCD));
+        assert_eq!(body, b"x");
+        assert!(parse_icmp_header(&[8, 0, 0, 0, 0, 0, 0], false).is_none());
+    }
+
+    #[test]
+    fn interprets_icmp_messages() {
+        let echo = header(false, ICMP_ECHO_REQUEST, 0, [0, 7, 0, 9]);
+        assert_eq!(echo.message(&[]), IcmpMessage::EchoRequest { identifier: 7, sequence: 9 });
+        assert!(echo.is_echo_request() && !echo.is_error());
+
+        let cases = [
+            (ICMP_DEST_UNREACHABLE, ICMP_PORT_UNREACHABLE, [0; 4], IcmpMessage::PortUnreachable),
+            (
+                ICMP_DEST_UNREACHABLE,
+                ICMP_FRAGMENTATION_NEEDED,
+                [0, 0, 0x05, 0xDC],
+                IcmpMessage::FragmentationNeeded { mtu: 1500 },
+            ),
+            (ICMP_DEST_UNREACHABLE, 1, [0; 4], IcmpMessage::DestinationUnreachable { code: 1 }),
+            (ICMP_PARAMETER_PROBLEM, 0, [20, 0, 0, 0], IcmpMessage::ParameterProblem { code: 0, pointer: 20 }),
+            (
+                ICMP_REDIRECT,
+                1,
+                [192, 0, 2, 1],
+                IcmpMessage::Redirect { code: 1, gateway: Some("192.0.2.1".parse().unwrap()) },
+            ),
+            (13, 0, [0; 4], IcmpMessage::Other { icmp_type: 13, code: 0 }),
+        ];
+        for (icmp_type, code, rest, expected) in cases {
+            assert_eq!(header(false, icmp_type, code, rest).message(&[]), expected);
+        }
+    }
+
+    #[test]
+    fn interprets_icmpv6_messages() {
+        let echo = header(true, ICMPV6_ECHO_REPLY, 0, [0, 7, 0, 9]);
+        assert_eq!(echo.message(&[]), IcmpMessage::EchoReply { identifier: 7, sequence: 9 });
+        assert!(!header(true, ICMP_ECHO_REQUEST, 0, [0; 4]).is_echo_request());
+
+        let too_big = header(true, ICMPV6_PACKET_TOO_BIG, 0, [0, 0, 0x05, 0x00]);
+        assert_eq!(too_big.message(&[]), IcmpMessage::FragmentationNeeded { mtu: 1280 });
+        assert!(too_big.is_error());
+        let unreachable = header(true, ICMPV6_DEST_UNREACHABLE, ICMPV6_PORT_UNREACHABLE, [0; 4]);
+        assert_eq!(unreachable.message(&[]), IcmpMessage::PortUnreachable);
+
+        // リダイレクトのターゲットはヘッダー以降のデータにある
+        let redirect = header(true, ICMPV6_REDIRECT, 0, [0; 4]);
+        let target: Ipv6Addr = "fe80::1".parse().unwrap();
+        assert_eq!(
+            redirect.message(&target.octets()),
+            IcmpMessage::Redirect { code: 0, gateway: Some(IpAddr::V6(target)) }
+        );
+        assert_eq!(redirect.message(&[0; 8]), IcmpMessage::Redirect { code: 0, gateway: None });
+    }
+
+    #[test]
+    fn parses_quoted_packet_of_error() {
+        let unreachable = header(false, ICMP_DEST_UNREACHABLE, ICMP_PORT_UNREACHABLE, [0; 4]);
+        let quoted = parse_quoted_packet(&unreachable, &quoted_ipv4(IPPROTO_TCP, 0)).unwrap();
+        assert_eq!(quoted.src_ip, "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(quoted.dst_ip, "10.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(
+            (quoted.src_port, quoted.dst_port, quoted.tcp_seq),
+            (Some(40000), Some(80), Some(1000))
+        );
+
+        // UDPはシーケンス番号を持たず、先頭以外のフラグメントには上位層のヘッダーがない
+        let udp = parse_quoted_packet(&unreachable, &quoted_ipv4(IPPROTO_UDP, 0)).unwrap();
+        assert_eq!((udp.src_port, udp.tcp_seq), (Some(40000), None));
+        let fragment = parse_quoted_packet(&unreachable, &quoted_ipv4(IPPROTO_TCP, 0x0010)).unwrap();
+        assert_eq!((fragment.src_port, fragment.tcp_seq), (None, None));
+
+        // エラーメッセージ以外は引用を持たない
+        let reply = header(false, ICMP_ECHO_REPLY, 0, [0; 4]);
+        assert!(parse_quoted_packet(&reply, &quoted_ipv4(IPPROTO_TCP, 0)).is_none());
+    }
+}
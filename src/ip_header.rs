@@ -1,4 +1,5 @@
-use std::net::Ipv4Addr;
+use crate::ipv6_header::Ipv6Header;
+use std::net::{IpAddr, Ipv4Addr};
 
 // 0                   1                   2                   3
 // 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
@@ -39,6 +40,9 @@ pub fn parse_ip_header(data: &[u8]) -> Option<(IpHeader, usize)> {
     }
 
     let ihl = (data[0] & 0xF) as usize * 4;
+    if ihl < 20 || ihl > data.len() {
+        return None;  // ヘッダー長が不正
+    }
     let dscp_ecn = data[1];
     let total_length = u16::from_be_bytes([data[2], data[3]]);
     let identification = u16::from_be_bytes([data[4], data[5]]);
@@ -65,4 +69,79 @@ pub fn parse_ip_header(data: &[u8]) -> Option<(IpHeader, usize)> {
         },
         ihl
     ))
-}
\ No newline at end of file
+}
+
+// IPv4/IPv6共通で上位層の処理に必要なヘッダー情報
+#[derive(Debug)]
+pub enum IpPacketHeader {
+    V4(IpHeader),
+    V6(Ipv6Header),
+}
+
+impl IpPacketHeader {
+    pub fn src_ip(&self) -> IpAddr {
+        match self {
+            IpPacketHeader::V4(header) => IpAddr::V4(header.src_ip),
+            IpPacketHeader::V6(header) => IpAddr::V6(header.src_ip),
+        }
+    }
+
+    pub fn dst_ip(&self) -> IpAddr {
+        match self {
+            IpPacketHeader::V4(header) => IpAddr::V4(header.dst_ip),
+            IpPacketHeader::V6(header) => IpAddr::V6(header.dst_ip),
+        }
+    }
+
+    // 上位層のプロトコル番号
+    pub fn protocol(&self) -> u8 {
+        match self {
+            IpPacketHeader::V4(header) => header.protocol,
+            IpPacketHeader::V6(header) => header.protocol,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(version_ihl: u8) -> Vec<u8> {
+        let mut data = vec![
+            version_ihl, 0x00, 0x00, 0x28, 0x12, 0x34, 0x20, 0x01, 64, 6, 0xAB, 0xCD,
+            192, 168, 0, 1, 10, 0, 0, 2,
+        ];
+        data.resize((version_ihl & 0xF) as usize * 4, 0);
+        data
+    }
+
+    #[test]
+    fn parses_fields() {
+        let (ip, length) = parse_ip_header(&header(0x45)).unwrap();
+        assert_eq!(length, 20);
+        assert_eq!(ip.total_length, 40);
+        assert_eq!(ip.identification, 0x1234);
+        assert_eq!(ip.flags_fragment_offset, 0x2001);
+        assert_eq!(ip.ttl, 64);
+        assert_eq!(ip.protocol, 6);
+        assert_eq!(ip.header_checksum, 0xABCD);
+        assert_eq!(ip.src_ip, Ipv4Addr::new(192, 168, 0, 1));
+        assert_eq!(ip.dst_ip, Ipv4Addr::new(10, 0, 0, 2));
+    }
+
+    #[test]
+    fn header_length_includes_options() {
+        let (ip, length) = parse_ip_header(&header(0x46)).unwrap();
+        assert_eq!(ip.ihl, 24);
+        assert_eq!(length, 24);
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        assert!(parse_ip_header(&header(0x45)[..19]).is_none());
+        assert!(parse_ip_header(&header(0x65)).is_none());
+        // IHLが5未満、またはデータに収まらない
+        assert!(parse_ip_header(&[0x44; 20]).is_none());
+        assert!(parse_ip_header(&header(0x46)[..20]).is_none());
+    }
+}
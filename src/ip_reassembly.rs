@@ -1,94 +1,809 @@
-use std::collections::HashMap;
-use std::net::Ipv4Addr;
-use std::time::{Duration, Instant};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use crate::capture_clock::elapsed_between;
 use crate::ip_header::IpHeader;
+use crate::ip_network::IpNetwork;
+use crate::ipv6_header::{Ipv6Fragment, Ipv6Header};
+
+// 重複するフラグメントのどちらのデータで再構築するか (ターゲットベースの再構築ポリシー)
+// 宛先ホストのOSと同じポリシーで再構築することで、攻撃対象が実際に受け取るデータを再現する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentPolicy {
+    First,    // 先に届いたフラグメントを常に採用
+    Last,     // 後から届いたフラグメントを常に採用
+    Bsd,      // 新しいフラグメントが既存のものより前から始まる場合のみ採用 (FreeBSD, AIX, HP-UXなど)
+    BsdRight, // BSDに加え、開始位置が同じ場合も新しいフラグメントを採用 (HP JetDirect)
+    Linux,    // BSDに加え、開始位置が同じで新しいフラグメントの方が長い場合も採用
+    Windows,  // 既存のフラグメントを完全に覆う場合のみ新しいフラグメントを採用
+    Solaris,  // 既存のフラグメントより前から始まり、末尾まで覆う場合のみ採用 (Solaris, HP-UX 11)
+}
+
+impl FragmentPolicy {
+    // 重複部分で新しいフラグメントのデータを採用するか
+    fn new_data_wins(self, new_start: usize, new_end: usize, old_start: usize, old_end: usize) -> bool {
+        match self {
+            FragmentPolicy::First => false,
+            FragmentPolicy::Last => true,
+            FragmentPolicy::Bsd => new_start < old_start,
+            FragmentPolicy::BsdRight => new_start <= old_start,
+            FragmentPolicy::Linux => new_start < old_start || (new_start == old_start && new_end > old_end),
+            FragmentPolicy::Windows => new_start < old_start && new_end > old_end,
+            FragmentPolicy::Solaris => new_start < old_start && new_end >= old_end,
+        }
+    }
+}
+
+impl FromStr for FragmentPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "first" => Ok(FragmentPolicy::First),
+            "last" => Ok(FragmentPolicy::Last),
+            "bsd" => Ok(FragmentPolicy::Bsd),
+            "bsd-right" => Ok(FragmentPolicy::BsdRight),
+            "linux" => Ok(FragmentPolicy::Linux),
+            "windows" => Ok(FragmentPolicy::Windows),
+            "solaris" => Ok(FragmentPolicy::Solaris),
+            s => Err(format!(
+                "不明なフラグメント再構築ポリシーです: {} (first, last, bsd, bsd-right, linux, windows, solaris)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FragmentPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FragmentPolicy::First => "first",
+            FragmentPolicy::Last => "last",
+            FragmentPolicy::Bsd => "bsd",
+            FragmentPolicy::BsdRight => "bsd-right",
+            FragmentPolicy::Linux => "linux",
+            FragmentPolicy::Windows => "windows",
+            FragmentPolicy::Solaris => "solaris",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// 宛先ネットワークごとのポリシー指定 (例: 10.0.0.0/8=windows)
+#[derive(Debug, Clone)]
+pub struct FragmentPolicyTarget {
+    pub network: IpNetwork,
+    pub policy: FragmentPolicy,
+}
+
+impl FromStr for FragmentPolicyTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, policy) = s
+            .split_once('=')
+            .ok_or_else(|| format!("<network>=<policy> の形式で指定してください: {}", s))?;
+        Ok(FragmentPolicyTarget {
+            network: network.parse()?,
+            policy: policy.parse()?,
+        })
+    }
+}
+
+// 宛先アドレスから再構築ポリシーを選ぶ (最長一致)
+#[derive(Debug, Clone)]
+pub struct FragmentPolicyMap {
+    default: FragmentPolicy,
+    targets: Vec<FragmentPolicyTarget>,
+}
+
+impl FragmentPolicyMap {
+    pub fn new(default: FragmentPolicy, targets: Vec<FragmentPolicyTarget>) -> Self {
+        FragmentPolicyMap { default, targets }
+    }
+
+    pub fn policy_for(&self, dst_ip: IpAddr) -> FragmentPolicy {
+        self.targets
+            .iter()
+            .filter(|target| target.network.contains(dst_ip))
+            .max_by_key(|target| target.network.prefix_len)
+            .map(|target| target.policy)
+            .unwrap_or(self.default)
+    }
+}
+
+// 再構築後のデータグラムの最大長 (IPの全長フィールドの上限)
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+// TCPヘッダーの最小長 (これより短い先頭フラグメントはヘッダーを分割している)
+const MIN_TCP_HEADER_SIZE: usize = 20;
+
+// 1つの送信元からのフラグメント数がこの期間内に閾値を超えたらフラッドとみなす
+const FRAGMENT_FLOOD_WINDOW: Duration = Duration::from_secs(1);
+const FRAGMENT_FLOOD_THRESHOLD: u64 = 1000;
+
+// 再構築バッファのメモリ上限
+#[derive(Debug, Clone, Copy)]
+pub struct FragmentLimits {
+    pub max_buffers: usize,   // 同時に再構築するデータグラム数
+    pub memcap: usize,        // 全バッファで保持するフラグメントのバイト数
+    pub max_fragments: usize, // 1つのデータグラムで保持するフラグメント数
+}
+
+impl Default for FragmentLimits {
+    fn default() -> Self {
+        FragmentLimits {
+            max_buffers: 4096,
+            memcap: 64 * 1024 * 1024,
+            max_fragments: 1024,
+        }
+    }
+}
+
+// 再構築バッファを破棄した理由ごとの件数 (上限の調整に使う)
+#[derive(Debug, Default, Clone)]
+pub struct FragmentEvictionCounters {
+    pub max_buffers: u64,   // バッファ数の上限により破棄した数
+    pub memcap: u64,        // メモリ上限により破棄した数
+    pub max_fragments: u64, // フラグメント数の上限により破棄した数
+    pub timeout: u64,       // タイムアウトにより破棄した数
+}
+
+// フラグメントの再構築中に検出したイベント
+#[derive(Debug, Clone, PartialEq)]
+pub enum FragmentEvent {
+    // 重複するフラグメントのデータが異なる (IDS回避やOSフィンガープリントに使われる)
+    OverlapConflict {
+        src_ip: IpAddr,
+        dst_ip: IpAddr,
+        identification: u32,
+        offset: usize,
+        length: usize,
+        policy: FragmentPolicy,
+    },
+    // 既存のフラグメントの内側で始まり、その終端より手前で終わるフラグメント (Teardrop)
+    Teardrop {
+        src_ip: IpAddr,
+        dst_ip: IpAddr,
+        identification: u32,
+        offset: usize,
+        length: usize,
+    },
+    // TCPヘッダーを分割する小さな先頭フラグメント、またはTCPヘッダーを上書きするフラグメント
+    TinyFragment {
+        src_ip: IpAddr,
+        dst_ip: IpAddr,
+        identification: u32,
+        offset: usize,
+        length: usize,
+    },
+    // 再構築すると最大長(65535バイト)を超えるデータグラム (Ping of Death)
+    OversizedDatagram {
+        src_ip: IpAddr,
+        dst_ip: IpAddr,
+        identification: u32,
+        size: usize,
+    },
+    // 長さの異なる最後のフラグメント(MF=0)の重複
+    ConflictingLastFragment {
+        src_ip: IpAddr,
+        dst_ip: IpAddr,
+        identification: u32,
+        first_length: usize,
+        second_length: usize,
+    },
+    // 1つの送信元からの大量のフラグメント
+    FragmentFlood {
+        src_ip: IpAddr,
+        count: u64,
+        window: Duration,
+    },
+}
+
+impl FragmentEvent {
+    // イベントの種類を表す名前 (構造化した出力に使う)
+    pub fn name(&self) -> &'static str {
+        match self {
+            FragmentEvent::OverlapConflict { .. } => "overlap_conflict",
+            FragmentEvent::Teardrop { .. } => "teardrop",
+            FragmentEvent::TinyFragment { .. } => "tiny_fragment",
+            FragmentEvent::OversizedDatagram { .. } => "oversized_datagram",
+            FragmentEvent::ConflictingLastFragment { .. } => "conflicting_last_fragment",
+            FragmentEvent::FragmentFlood { .. } => "fragment_flood",
+        }
+    }
+
+    // フラグメントの送信元と宛先 (フラッドは送信元のみ)
+    pub fn addresses(&self) -> (IpAddr, Option<IpAddr>) {
+        match self {
+            FragmentEvent::OverlapConflict { src_ip, dst_ip, .. }
+            | FragmentEvent::Teardrop { src_ip, dst_ip, .. }
+            | FragmentEvent::TinyFragment { src_ip, dst_ip, .. }
+            | FragmentEvent::OversizedDatagram { src_ip, dst_ip, .. }
+            | FragmentEvent::ConflictingLastFragment { src_ip, dst_ip, .. } => (*src_ip, Some(*dst_ip)),
+            FragmentEvent::FragmentFlood { src_ip, .. } => (*src_ip, None),
+        }
+    }
+}
+
+impl fmt::Display for FragmentEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FragmentEvent::OverlapConflict { src_ip, dst_ip, identification, offset, length, policy } => write!(
+                f,
+                "overlapping fragments with different data ({} -> {} id={} offset={} len={} policy={})",
+                src_ip, dst_ip, identification, offset, length, policy
+            ),
+            FragmentEvent::Teardrop { src_ip, dst_ip, identification, offset, length } => write!(
+                f,
+                "teardrop fragment inside a previous fragment ({} -> {} id={} offset={} len={})",
+                src_ip, dst_ip, identification, offset, length
+            ),
+            FragmentEvent::TinyFragment { src_ip, dst_ip, identification, offset, length } => write!(
+                f,
+                "tiny fragment splitting or overwriting the TCP header ({} -> {} id={} offset={} len={})",
+                src_ip, dst_ip, identification, offset, length
+            ),
+            FragmentEvent::OversizedDatagram { src_ip, dst_ip, identification, size } => write!(
+                f,
+                "reassembled datagram exceeds {} bytes ({} -> {} id={} size={})",
+                MAX_DATAGRAM_SIZE, src_ip, dst_ip, identification, size
+            ),
+            FragmentEvent::ConflictingLastFragment { src_ip, dst_ip, identification, first_length, second_length } => write!(
+                f,
+                "duplicate last fragment with conflicting length ({} -> {} id={} first={} second={})",
+                src_ip, dst_ip, identification, first_length, second_length
+            ),
+            FragmentEvent::FragmentFlood { src_ip, count, window } => write!(
+                f,
+                "fragment flood ({} sent {} fragments within {:?})",
+                src_ip, count, window
+            ),
+        }
+    }
+}
 
 // フラグメントされたIPパケットを表す構造体
 #[derive(Clone)]
 struct IpFragment {
     data: Vec<u8>,
-    offset: u16,
+    offset: usize, // バイト単位のオフセット
     more_fragments: bool,
-    arrival_time: Instant,
+    arrival_time: SystemTime,
+}
+
+impl IpFragment {
+    fn end(&self) -> usize {
+        self.offset + self.data.len()
+    }
+}
+
+// フラグメントの検査に使うヘッダー情報
+struct FragmentHeader {
+    offset: usize, // バイト単位のオフセット
+    more_fragments: bool,
+    protocol: u8,         // 上位層のプロトコル
+    header_length: usize, // フラグメント化されない部分(IPヘッダー)の長さ
+}
+
+// 送信元ごとのフラグメント数
+struct FloodCounter {
+    window_start: SystemTime,
+    count: u64,
+    alerted: bool,
 }
 
 // 再構築中のIPパケットを表す構造体
 struct ReassemblyBuffer {
     fragments: Vec<IpFragment>,
-    total_length: usize,
-    last_activity: Instant,
+    total_length: Option<usize>, // 最後のフラグメント(MF=0)を受信するまでは不明
+    last_activity: SystemTime,
+    policy: FragmentPolicy,
+    buffered_bytes: usize,
+}
+
+impl ReassemblyBuffer {
+    // 保持しているフラグメントのうち最も早く受信した時刻
+    fn first_arrival(&self) -> SystemTime {
+        self.fragments
+            .iter()
+            .map(|fragment| fragment.arrival_time)
+            .min()
+            .unwrap_or(self.last_activity)
+    }
 }
 
+// フラグメントを識別するキー (送信元, 宛先, Identification)
+// IPv4のIdentificationは16ビット、IPv6は32ビット
+type FragmentKey = (IpAddr, IpAddr, u32);
+
 pub struct IpReassembler {
-    buffers: HashMap<(Ipv4Addr, Ipv4Addr, u16), ReassemblyBuffer>,
+    buffers: HashMap<FragmentKey, ReassemblyBuffer>,
+    by_activity: BTreeSet<(SystemTime, FragmentKey)>, // 最終受信時刻順の索引 (破棄するバッファを全体を走査せずに選ぶ)
     timeout: Duration,
+    policies: FragmentPolicyMap,
+    events: Vec<FragmentEvent>, // 未出力のイベント
+    flood_counters: HashMap<IpAddr, FloodCounter>,
+    limits: FragmentLimits,
+    memory_usage: usize, // 全バッファで保持しているフラグメントのバイト数
+    pub counters: FragmentEvictionCounters,
 }
 
 impl IpReassembler {
-    pub fn new(timeout: Duration) -> Self {
+    pub fn new(timeout: Duration, policies: FragmentPolicyMap, limits: FragmentLimits) -> Self {
         IpReassembler {
             buffers: HashMap::new(),
+            by_activity: BTreeSet::new(),
             timeout,
+            policies,
+            events: Vec::new(),
+            flood_counters: HashMap::new(),
+            limits,
+            memory_usage: 0,
+            counters: FragmentEvictionCounters::default(),
         }
     }
 
-    pub fn process_packet(&mut self, ip_header: &IpHeader, payload: &[u8]) -> Option<Vec<u8>> {
-        let key = (ip_header.src_ip, ip_header.dst_ip, ip_header.identification);
-        let fragment_offset = (ip_header.flags_fragment_offset & 0x1FFF) * 8;
-        let more_fragments = (ip_header.flags_fragment_offset & 0x2000) != 0;
+    pub fn len(&self) -> usize {
+        self.buffers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage
+    }
+
+    pub fn process_packet(&mut self, ip_header: &IpHeader, payload: &[u8], now: SystemTime) -> Option<Vec<u8>> {
+        let key = (
+            IpAddr::V4(ip_header.src_ip),
+            IpAddr::V4(ip_header.dst_ip),
+            ip_header.identification as u32,
+        );
+        let header = FragmentHeader {
+            offset: (ip_header.flags_fragment_offset & 0x1FFF) as usize * 8,
+            more_fragments: (ip_header.flags_fragment_offset & 0x2000) != 0,
+            protocol: ip_header.protocol,
+            header_length: ip_header.ihl as usize,
+        };
+
+        self.add_fragment(key, header, payload, now)
+    }
+
+    // IPv6のフラグメントヘッダーを持つパケットを処理
+    // 返されるデータはフラグメント化可能部分 (残りの拡張ヘッダーと上位層)
+    pub fn process_ipv6_fragment(
+        &mut self,
+        ipv6_header: &Ipv6Header,
+        fragment: &Ipv6Fragment,
+        header_size: usize,
+        payload: &[u8],
+        now: SystemTime,
+    ) -> Option<Vec<u8>> {
+        let key = (
+            IpAddr::V6(ipv6_header.src_ip),
+            IpAddr::V6(ipv6_header.dst_ip),
+            fragment.identification,
+        );
+
+        let header = FragmentHeader {
+            offset: fragment.offset as usize,
+            more_fragments: fragment.more_fragments,
+            protocol: ipv6_header.protocol,
+            // ペイロード長の上限に固定ヘッダー(40バイト)は含まれない
+            header_length: header_size.saturating_sub(40),
+        };
+
+        self.add_fragment(key, header, payload, now)
+    }
+
+    fn add_fragment(
+        &mut self,
+        key: FragmentKey,
+        header: FragmentHeader,
+        payload: &[u8],
+        now: SystemTime,
+    ) -> Option<Vec<u8>> {
+        // フラグメントされていないパケットはそのまま返す
+        if header.offset == 0 && !header.more_fragments && !self.buffers.contains_key(&key) {
+            return Some(payload.to_vec());
+        }
+
+        let (src_ip, dst_ip, identification) = key;
+        self.count_fragment(src_ip, now);
+
+        // 再構築後に最大長を超えるフラグメント(Ping of Death)は再構築しない
+        let size = header.header_length + header.offset + payload.len();
+        if size > MAX_DATAGRAM_SIZE {
+            self.events.push(FragmentEvent::OversizedDatagram { src_ip, dst_ip, identification, size });
+            self.remove_buffer(&key);
+            return None;
+        }
+
+        // TCPヘッダーを分割する先頭フラグメント、またはTCPヘッダー(フラグ)を上書きするフラグメント
+        if header.protocol == 6
+            && ((header.offset == 0 && header.more_fragments && payload.len() < MIN_TCP_HEADER_SIZE)
+                || (header.offset > 0 && header.offset < MIN_TCP_HEADER_SIZE))
+        {
+            self.events.push(FragmentEvent::TinyFragment {
+                src_ip,
+                dst_ip,
+                identification,
+                offset: header.offset,
+                length: payload.len(),
+            });
+        }
+
+        // バッファ数が上限に達している場合は最も古いバッファを破棄する
+        while !self.buffers.contains_key(&key) && self.buffers.len() >= self.limits.max_buffers {
+            if !self.evict_oldest(None) {
+                break;
+            }
+            self.counters.max_buffers += 1;
+        }
 
-        let fragment = IpFragment {
+        let policy = self.policies.policy_for(key.1);
+        let by_activity = &mut self.by_activity;
+        let buffer = self.buffers.entry(key).or_insert_with(|| {
+            by_activity.insert((now, key));
+            ReassemblyBuffer {
+                fragments: Vec::new(),
+                total_length: None,
+                last_activity: now,
+                policy,
+                buffered_bytes: 0,
+            }
+        });
+
+        // 1つのデータグラムに大量のフラグメントを送りつける場合は再構築を諦める
+        if buffer.fragments.len() >= self.limits.max_fragments {
+            self.counters.max_fragments += 1;
+            self.remove_buffer(&key);
+            return None;
+        }
+
+        let mut fragment = IpFragment {
             data: payload.to_vec(),
-            offset: fragment_offset,
-            more_fragments,
-            arrival_time: Instant::now(),
+            offset: header.offset,
+            more_fragments: header.more_fragments,
+            arrival_time: now,
         };
 
-        self.buffers.entry(key).or_insert_with(|| ReassemblyBuffer {
-            fragments: Vec::new(),
-            total_length: 0,
-            last_activity: Instant::now(),
-        }).fragments.push(fragment);
+        // 長さの異なる最後のフラグメントは最初に受信したものを採用する
+        if let Some(total_length) = buffer.total_length {
+            if !fragment.more_fragments && fragment.end() != total_length {
+                self.events.push(FragmentEvent::ConflictingLastFragment {
+                    src_ip,
+                    dst_ip,
+                    identification,
+                    first_length: total_length,
+                    second_length: fragment.end(),
+                });
+                return None;
+            }
+        }
+
+        // 既存のフラグメントの内側で始まり、その終端より手前で終わるフラグメントは
+        // 重複部分を切り詰める単純な実装では長さが負になる (Teardrop)
+        if buffer
+            .fragments
+            .iter()
+            .any(|existing| fragment.offset >= existing.offset && fragment.end() < existing.end())
+        {
+            self.events.push(FragmentEvent::Teardrop {
+                src_ip,
+                dst_ip,
+                identification,
+                offset: fragment.offset,
+                length: fragment.data.len(),
+            });
+        }
+
+        // 既存のフラグメントとの重複部分をポリシーに従って一方のデータに揃える
+        for existing in &mut buffer.fragments {
+            let overlap_start = fragment.offset.max(existing.offset);
+            let overlap_end = fragment.end().min(existing.end());
+            if overlap_start >= overlap_end {
+                continue;
+            }
+
+            let new_range = overlap_start - fragment.offset..overlap_end - fragment.offset;
+            let old_range = overlap_start - existing.offset..overlap_end - existing.offset;
+            if fragment.data[new_range.clone()] == existing.data[old_range.clone()] {
+                continue;
+            }
+
+            self.events.push(FragmentEvent::OverlapConflict {
+                src_ip,
+                dst_ip,
+                identification,
+                offset: overlap_start,
+                length: overlap_end - overlap_start,
+                policy: buffer.policy,
+            });
+
+            if buffer.policy.new_data_wins(fragment.offset, fragment.end(), existing.offset, existing.end()) {
+                existing.data[old_range].copy_from_slice(&fragment.data[new_range]);
+            } else {
+                fragment.data[new_range].copy_from_slice(&existing.data[old_range]);
+            }
+        }
+
+        if !fragment.more_fragments {
+            buffer.total_length = Some(fragment.end());
+        }
+        buffer.buffered_bytes += fragment.data.len();
+        self.memory_usage += fragment.data.len();
+        buffer.fragments.push(fragment);
+        if buffer.last_activity != now {
+            self.by_activity.remove(&(buffer.last_activity, key));
+            self.by_activity.insert((now, key));
+            buffer.last_activity = now;
+        }
+
+        // メモリ上限を超えている間は最も古いバッファから破棄する (処理中のバッファは最後)
+        while self.memory_usage > self.limits.memcap {
+            if !self.evict_oldest(Some(&key)) && self.remove_buffer(&key).is_none() {
+                break;
+            }
+            self.counters.memcap += 1;
+        }
 
         self.try_reassemble(key)
     }
 
-    fn try_reassemble(&mut self, key: (Ipv4Addr, Ipv4Addr, u16)) -> Option<Vec<u8>> {
-        if let Some(buffer) = self.buffers.get_mut(&key) {
-            buffer.fragments.sort_by_key(|f| f.offset);
-
-            let mut reassembled = Vec::new();
-            let mut expected_offset = 0;
-            let mut complete = true;
-
-            for fragment in &buffer.fragments {
-                if fragment.offset != expected_offset {
-                    complete = false;
-                    break;
-                }
-                reassembled.extend_from_slice(&fragment.data);
-                expected_offset = fragment.offset + fragment.data.len() as u16;
-                if !fragment.more_fragments {
-                    break;
-                }
+    // 最終受信時刻が最も古いバッファを破棄する
+    fn evict_oldest(&mut self, exclude: Option<&FragmentKey>) -> bool {
+        let oldest = self
+            .by_activity
+            .iter()
+            .map(|(_, key)| *key)
+            .find(|key| Some(key) != exclude);
+        match oldest {
+            Some(key) => self.remove_buffer(&key).is_some(),
+            None => false,
+        }
+    }
+
+    fn remove_buffer(&mut self, key: &FragmentKey) -> Option<ReassemblyBuffer> {
+        let buffer = self.buffers.remove(key)?;
+        self.by_activity.remove(&(buffer.last_activity, *key));
+        self.memory_usage -= buffer.buffered_bytes;
+        Some(buffer)
+    }
+
+    // 最後のフラグメントまで隙間なく揃っていれば再構築する
+    fn try_reassemble(&mut self, key: FragmentKey) -> Option<Vec<u8>> {
+        let buffer = self.buffers.get_mut(&key)?;
+        let total_length = buffer.total_length?;
+
+        buffer.fragments.sort_by_key(|f| f.offset);
+
+        let mut covered = 0;
+        for fragment in &buffer.fragments {
+            if fragment.offset > covered {
+                return None;
             }
+            covered = covered.max(fragment.end());
+            if covered >= total_length {
+                break;
+            }
+        }
+        if covered < total_length {
+            return None;
+        }
 
-            if complete {
-                self.buffers.remove(&key);
-                Some(reassembled)
-            } else {
-                None
+        let buffer = self.remove_buffer(&key)?;
+        let mut reassembled = vec![0; total_length];
+        for fragment in &buffer.fragments {
+            let end = fragment.end().min(total_length);
+            if fragment.offset < end {
+                reassembled[fragment.offset..end].copy_from_slice(&fragment.data[..end - fragment.offset]);
             }
-        } else {
-            None
         }
+
+        Some(reassembled)
     }
 
-    pub fn cleanup(&mut self) {
-        let now = Instant::now();
+    // 送信元ごとのフラグメント数を数え、閾値を超えたら期間ごとに一度だけ通知する
+    fn count_fragment(&mut self, src_ip: IpAddr, now: SystemTime) {
+        let counter = self.flood_counters.entry(src_ip).or_insert(FloodCounter {
+            window_start: now,
+            count: 0,
+            alerted: false,
+        });
+
+        if elapsed_between(counter.window_start, now) >= FRAGMENT_FLOOD_WINDOW {
+            counter.window_start = now;
+            counter.count = 0;
+            counter.alerted = false;
+        }
+
+        counter.count += 1;
+        if counter.count > FRAGMENT_FLOOD_THRESHOLD && !counter.alerted {
+            counter.alerted = true;
+            self.events.push(FragmentEvent::FragmentFlood {
+                src_ip,
+                count: counter.count,
+                window: FRAGMENT_FLOOD_WINDOW,
+            });
+        }
+    }
+
+    // 未出力のイベントを取り出す
+    pub fn take_events(&mut self) -> Vec<FragmentEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    // キャプチャ時刻nowを基準にタイムアウトしたバッファを破棄する
+    // タイムアウトは最初のフラグメントの受信から数える (少しずつフラグメントを送り続けてバッファを保持させない)
+    pub fn cleanup(&mut self, now: SystemTime) {
         let timeout = self.timeout;
-        self.buffers.retain(|_, buffer| {
-            now.duration_since(buffer.last_activity) < timeout
+        let expired: Vec<FragmentKey> = self
+            .buffers
+            .iter()
+            .filter(|(_, buffer)| elapsed_between(buffer.first_arrival(), now) >= timeout)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            self.remove_buffer(&key);
+            self.counters.timeout += 1;
+        }
+        self.flood_counters.retain(|_, counter| {
+            elapsed_between(counter.window_start, now) < FRAGMENT_FLOOD_WINDOW
         });
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    fn reassembler(policy: FragmentPolicy, limits: FragmentLimits) -> IpReassembler {
+        IpReassembler::new(TIMEOUT, FragmentPolicyMap::new(policy, Vec::new()), limits)
+    }
+
+    fn fragment(identification: u16, offset: usize, more_fragments: bool) -> IpHeader {
+        IpHeader {
+            version: 4,
+            ihl: 20,
+            dscp_ecn: 0,
+            total_length: 0,
+            identification,
+            flags_fragment_offset: (offset / 8) as u16 | if more_fragments { 0x2000 } else { 0 },
+            ttl: 64,
+            protocol: 17,
+            header_checksum: 0,
+            src_ip: Ipv4Addr::new(192, 0, 2, 1),
+            dst_ip: Ipv4Addr::new(192, 0, 2, 2),
+        }
+    }
+
+    fn at(seconds: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn reassembles_out_of_order_fragments() {
+        let mut reassembler = reassembler(FragmentPolicy::First, FragmentLimits::default());
+        assert_eq!(reassembler.process_packet(&fragment(1, 8, false), b"world!!!", at(0)), None);
+        assert_eq!(
+            reassembler.process_packet(&fragment(1, 0, true), b"hello, w", at(0)),
+            Some(b"hello, wworld!!!".to_vec())
+        );
+        assert!(reassembler.is_empty());
+        assert_eq!(reassembler.memory_usage(), 0);
+        assert_eq!(
+            reassembler.process_packet(&fragment(2, 0, false), b"whole", at(0)),
+            Some(b"whole".to_vec())
+        );
+    }
+
+    // 既存のフラグメント(offset, データ)に重なる新しいフラグメントを送り、最後のフラグメントで再構築する
+    fn reassemble_overlap(policy: FragmentPolicy, existing: (usize, &[u8]), new: (usize, &[u8])) -> (Vec<u8>, Vec<FragmentEvent>) {
+        let mut reassembler = reassembler(policy, FragmentLimits::default());
+        assert_eq!(reassembler.process_packet(&fragment(7, existing.0, true), existing.1, at(0)), None);
+        assert_eq!(reassembler.process_packet(&fragment(7, new.0, true), new.1, at(0)), None);
+        let data = reassembler.process_packet(&fragment(7, 24, false), b"zzzzzzzz", at(0)).unwrap();
+        (data[..24].to_vec(), reassembler.take_events())
+    }
+
+    #[test]
+    fn overlap_covering_existing_fragment() {
+        // 新しいフラグメントが既存のものより前から始まり、後ろまで覆う
+        let expected = [
+            (FragmentPolicy::First, b"yyyyyyyyxxxxxxxxyyyyyyyy"),
+            (FragmentPolicy::Last, b"yyyyyyyyyyyyyyyyyyyyyyyy"),
+            (FragmentPolicy::Bsd, b"yyyyyyyyyyyyyyyyyyyyyyyy"),
+            (FragmentPolicy::BsdRight, b"yyyyyyyyyyyyyyyyyyyyyyyy"),
+            (FragmentPolicy::Linux, b"yyyyyyyyyyyyyyyyyyyyyyyy"),
+            (FragmentPolicy::Windows, b"yyyyyyyyyyyyyyyyyyyyyyyy"),
+            (FragmentPolicy::Solaris, b"yyyyyyyyyyyyyyyyyyyyyyyy"),
+        ];
+        for (policy, data) in expected {
+            let (reassembled, events) = reassemble_overlap(policy, (8, b"xxxxxxxx"), (0, &[b'y'; 24]));
+            assert_eq!(reassembled, data, "{}", policy);
+            assert!(
+                matches!(events[..], [FragmentEvent::OverlapConflict { offset: 8, length: 8, policy: p, .. }] if p == policy),
+                "{}: {:?}",
+                policy,
+                events
+            );
+        }
+    }
+
+    #[test]
+    fn overlap_with_same_start() {
+        // 開始位置が同じで、新しいフラグメントの方が長い
+        let expected = [
+            (FragmentPolicy::First, b"xxxxxxxxyyyyyyyyyyyyyyyy"),
+            (FragmentPolicy::Last, b"yyyyyyyyyyyyyyyyyyyyyyyy"),
+            (FragmentPolicy::Bsd, b"xxxxxxxxyyyyyyyyyyyyyyyy"),
+            (FragmentPolicy::BsdRight, b"yyyyyyyyyyyyyyyyyyyyyyyy"),
+            (FragmentPolicy::Linux, b"yyyyyyyyyyyyyyyyyyyyyyyy"),
+            (FragmentPolicy::Windows, b"xxxxxxxxyyyyyyyyyyyyyyyy"),
+            (FragmentPolicy::Solaris, b"xxxxxxxxyyyyyyyyyyyyyyyy"),
+        ];
+        for (policy, data) in expected {
+            let (reassembled, _) = reassemble_overlap(policy, (0, b"xxxxxxxx"), (0, &[b'y'; 24]));
+            assert_eq!(reassembled, data, "{}", policy);
+        }
+    }
+
+    #[test]
+    fn identical_overlap_is_not_a_conflict() {
+        let (reassembled, events) = reassemble_overlap(FragmentPolicy::Bsd, (0, &[b'a'; 16]), (8, &[b'a'; 16]));
+        assert_eq!(reassembled, [b'a'; 24]);
+        assert!(events.is_empty(), "{:?}", events);
+    }
+
+    #[test]
+    fn teardrop_and_conflicting_last_fragment() {
+        let mut reassembler = reassembler(FragmentPolicy::First, FragmentLimits::default());
+        reassembler.process_packet(&fragment(3, 0, true), &[0; 32], at(0));
+        reassembler.process_packet(&fragment(3, 8, true), &[0; 8], at(0));
+        reassembler.process_packet(&fragment(3, 40, false), &[0; 8], at(0));
+        reassembler.process_packet(&fragment(3, 48, false), &[0; 8], at(0));
+        let names: Vec<_> = reassembler.take_events().iter().map(FragmentEvent::name).collect();
+        assert_eq!(names, ["teardrop", "conflicting_last_fragment"]);
+    }
+
+    #[test]
+    fn timeout_counts_from_first_fragment() {
+        let mut reassembler = reassembler(FragmentPolicy::First, FragmentLimits::default());
+        reassembler.process_packet(&fragment(1, 0, true), &[0; 8], at(0));
+        reassembler.process_packet(&fragment(1, 16, true), &[0; 8], at(20));
+        reassembler.cleanup(at(29));
+        assert_eq!(reassembler.len(), 1);
+        // 最後の受信から30秒経っていなくても、最初の受信から30秒で破棄する
+        reassembler.cleanup(at(30));
+        assert!(reassembler.is_empty());
+        assert_eq!(reassembler.counters.timeout, 1);
+        assert_eq!(reassembler.memory_usage(), 0);
+    }
+
+    #[test]
+    fn evicts_least_recently_active_buffer() {
+        let limits = FragmentLimits {
+            max_buffers: 2,
+            ..FragmentLimits::default()
+        };
+        let mut reassembler = reassembler(FragmentPolicy::First, limits);
+        reassembler.process_packet(&fragment(1, 0, true), &[1; 8], at(0));
+        reassembler.process_packet(&fragment(2, 0, true), &[2; 8], at(1));
+        reassembler.process_packet(&fragment(1, 16, true), &[1; 8], at(2));
+        reassembler.process_packet(&fragment(3, 0, true), &[3; 8], at(3));
+        assert_eq!(reassembler.len(), 2);
+        assert_eq!(reassembler.counters.max_buffers, 1);
+        // 破棄されたのは最後の受信が最も古いIdentification 2のバッファ
+        assert_eq!(reassembler.process_packet(&fragment(1, 8, false), &[1; 8], at(4)), Some(vec![1; 16]));
+        assert_eq!(reassembler.process_packet(&fragment(2, 8, false), &[2; 8], at(4)), None);
+    }
+}
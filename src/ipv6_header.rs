@@ -0,0 +1,227 @@
+use std::net::Ipv6Addr;
+
+// 0                   1                   2                   3
+// 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |Version| Traffic Class |           Flow Label                  |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |         Payload Length        |  Next Header  |   Hop Limit   |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |                                                               |
+// +                                                               +
+// |                                                               |
+// +                         Source Address                        +
+// |                                                               |
+// +                                                               +
+// |                                                               |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |                                                               |
+// +                                                               +
+// |                                                               |
+// +                      Destination Address                      +
+// |                                                               |
+// +                                                               +
+// |                                                               |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+
+// 拡張ヘッダーの次ヘッダー番号
+pub const IPV6_HOP_BY_HOP: u8 = 0;
+pub const IPV6_ROUTING: u8 = 43;
+pub const IPV6_FRAGMENT: u8 = 44;
+pub const IPV6_ESP: u8 = 50;
+pub const IPV6_AUTH: u8 = 51;
+pub const IPV6_NO_NEXT_HEADER: u8 = 59;
+pub const IPV6_DEST_OPTIONS: u8 = 60;
+
+const IPV6_HEADER_SIZE: usize = 40;
+const IPV6_FRAGMENT_HEADER_SIZE: usize = 8;
+
+// フラグメントヘッダーの内容
+// 0                   1                   2                   3
+// 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |  Next Header  |   Reserved    |      Fragment Offset    |Res|M|
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |                         Identification                        |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+#[derive(Debug, Clone)]
+pub struct Ipv6Fragment {
+    pub offset: u16, // バイト単位のオフセット
+    pub more_fragments: bool,
+    pub identification: u32,
+}
+
+#[derive(Debug)]
+pub struct Ipv6Header {
+    pub version: u8,
+    pub traffic_class: u8,
+    pub flow_label: u32,
+    pub payload_length: u16,
+    pub next_header: u8,            // 固定ヘッダーの次ヘッダー
+    pub hop_limit: u8,
+    pub src_ip: Ipv6Addr,
+    pub dst_ip: Ipv6Addr,
+    pub extension_headers: Vec<u8>, // 辿った拡張ヘッダーの種類 (出現順)
+    pub protocol: u8,               // 上位層のプロトコル (フラグメントの場合はフラグメントヘッダーの次ヘッダー)
+    pub fragment: Option<Ipv6Fragment>,
+}
+
+// IPv6ヘッダーと拡張ヘッダーを解析する
+// 戻り値のusizeは上位層(フラグメントの場合はフラグメント部分)までのヘッダー長
+pub fn parse_ipv6_header(data: &[u8]) -> Option<(Ipv6Header, usize)> {
+    if data.len() < IPV6_HEADER_SIZE {
+        return None;
+    }
+
+    let version = (data[0] >> 4) & 0xF;
+    if version != 6 {
+        return None;
+    }
+
+    let traffic_class = ((data[0] & 0xF) << 4) | (data[1] >> 4);
+    let flow_label = u32::from_be_bytes([0, data[1] & 0xF, data[2], data[3]]);
+    let payload_length = u16::from_be_bytes([data[4], data[5]]);
+    let next_header = data[6];
+    let hop_limit = data[7];
+    let src_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&data[8..24]).ok()?);
+    let dst_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&data[24..40]).ok()?);
+
+    let mut extension_headers = Vec::new();
+    let mut fragment = None;
+    let mut protocol = next_header;
+    let mut offset = IPV6_HEADER_SIZE;
+
+    loop {
+        match protocol {
+            IPV6_FRAGMENT => {
+                let header = data.get(offset..offset + IPV6_FRAGMENT_HEADER_SIZE)?;
+                extension_headers.push(protocol);
+                let offset_flags = u16::from_be_bytes([header[2], header[3]]);
+                fragment = Some(Ipv6Fragment {
+                    offset: offset_flags & 0xFFF8, // 8バイト単位の値を3ビット左シフトした位置にある
+                    more_fragments: offset_flags & 0x1 != 0,
+                    identification: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+                });
+                protocol = header[0];
+                offset += IPV6_FRAGMENT_HEADER_SIZE;
+                // フラグメントヘッダー以降はフラグメント化可能部分なので、再構築後に解析する
+                break;
+            }
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DEST_OPTIONS | IPV6_AUTH => {
+                let (next, length) = extension_header_length(protocol, &data[offset..])?;
+                extension_headers.push(protocol);
+                protocol = next;
+                offset += length;
+            }
+            // ESPは暗号化されているため以降は解析できない
+            IPV6_ESP | IPV6_NO_NEXT_HEADER => break,
+            _ => break,
+        }
+    }
+
+    Some((
+        Ipv6Header {
+            version,
+            traffic_class,
+            flow_label,
+            payload_length,
+            next_header,
+            hop_limit,
+            src_ip,
+            dst_ip,
+            extension_headers,
+            protocol,
+            fragment,
+        },
+        offset,
+    ))
+}
+
+// 再構築後のペイロードに残った拡張ヘッダー(宛先オプションなど)を読み飛ばす
+// 戻り値は上位層のプロトコルとそのデータの開始位置
+pub fn skip_extension_headers(mut protocol: u8, data: &[u8]) -> Option<(u8, usize)> {
+    let mut offset = 0;
+    while matches!(protocol, IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DEST_OPTIONS | IPV6_AUTH) {
+        let (next, length) = extension_header_length(protocol, data.get(offset..)?)?;
+        protocol = next;
+        offset += length;
+    }
+    Some((protocol, offset))
+}
+
+// 拡張ヘッダーの次ヘッダーと長さを返す
+fn extension_header_length(protocol: u8, data: &[u8]) -> Option<(u8, usize)> {
+    if data.len() < 2 {
+        return None;
+    }
+    let length = match protocol {
+        // AHは4オクテット単位で、先頭2ワードを含まない
+        IPV6_AUTH => (data[1] as usize + 2) * 4,
+        // その他は8オクテット単位で、先頭8オクテットを含まない
+        _ => (data[1] as usize + 1) * 8,
+    };
+    if data.len() < length {
+        return None;
+    }
+    Some((data[0], length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x6A, 0xB1, 0x23, 0x45];
+        data.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        data.extend_from_slice(&[next_header, 64]);
+        data.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+        data.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2).octets());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn parses_fixed_header() {
+        let (ipv6, length) = parse_ipv6_header(&header(6, &[0; 20])).unwrap();
+        assert_eq!(length, 40);
+        assert_eq!(ipv6.traffic_class, 0xAB);
+        assert_eq!(ipv6.flow_label, 0x12345);
+        assert_eq!(ipv6.payload_length, 20);
+        assert_eq!(ipv6.hop_limit, 64);
+        assert_eq!(ipv6.protocol, 6);
+        assert_eq!(ipv6.dst_ip, Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2));
+        assert!(ipv6.fragment.is_none());
+    }
+
+    #[test]
+    fn follows_extension_headers_to_fragment() {
+        // ホップバイホップ(8バイト) -> フラグメント(オフセット185*8、MF=1) -> UDP
+        let mut payload = vec![IPV6_FRAGMENT, 0, 0, 0, 0, 0, 0, 0];
+        payload.extend_from_slice(&[17, 0, 0x05, 0xC9, 0xDE, 0xAD, 0xBE, 0xEF]);
+        let (ipv6, length) = parse_ipv6_header(&header(IPV6_HOP_BY_HOP, &payload)).unwrap();
+        assert_eq!(length, 56);
+        assert_eq!(ipv6.extension_headers, vec![IPV6_HOP_BY_HOP, IPV6_FRAGMENT]);
+        assert_eq!(ipv6.protocol, 17);
+        let fragment = ipv6.fragment.unwrap();
+        assert_eq!(fragment.offset, 185 * 8);
+        assert!(fragment.more_fragments);
+        assert_eq!(fragment.identification, 0xDEADBEEF);
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        assert!(parse_ipv6_header(&header(6, &[])[..39]).is_none());
+        assert!(parse_ipv6_header(&header(IPV6_FRAGMENT, &[17, 0, 0, 0])).is_none());
+        assert!(parse_ipv6_header(&[0x45; 40]).is_none());
+    }
+
+    #[test]
+    fn skips_remaining_extension_headers() {
+        // 宛先オプション(16バイト) -> TCP
+        let mut data = vec![6, 1];
+        data.resize(16, 0);
+        assert_eq!(skip_extension_headers(IPV6_DEST_OPTIONS, &data), Some((6, 16)));
+        assert_eq!(skip_extension_headers(IPV6_DEST_OPTIONS, &data[..8]), None);
+        assert_eq!(skip_extension_headers(17, &[]), Some((17, 0)));
+    }
+}
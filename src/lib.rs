@@ -0,0 +1,60 @@
+// IPパケットの再構築とTCPストリームの再構成を行うNIDSのライブラリ
+// ヘッダーの解析、IPフラグメントの再構築、TCPストリームの追跡と、それらをまとめたパイプラインを提供する
+pub mod capture_clock;
+pub mod checksum;
+pub mod config;
+pub mod error;
+pub mod eve;
+pub mod icmp_detector;
+pub mod icmp_header;
+pub mod ip_header;
+pub mod ip_network;
+pub mod ip_reassembly;
+pub mod ipv6_header;
+pub mod link_layer;
+pub mod output;
+pub mod packet_log;
+pub mod packet_analysis;
+pub mod packet_processor;
+pub mod pipeline;
+pub mod prefilter;
+pub mod rule;
+pub mod rule_engine;
+pub mod scan_detector;
+pub mod select_device;
+#[cfg(unix)]
+pub mod signal;
+pub mod stream_consumer;
+pub mod stream_table;
+pub mod syn_flood;
+pub mod tcp_anomaly;
+pub mod tcp_header;
+pub mod tcp_reassembly;
+pub mod tcp_stream;
+pub mod udp_flow;
+pub mod udp_header;
+
+pub use checksum::ChecksumPolicy;
+pub use error::NidsError;
+pub use eve::{EveFlow, EveLog, EveTarget};
+pub use icmp_detector::{IcmpDetector, IcmpEvent};
+pub use icmp_header::{parse_icmp_header, IcmpHeader, IcmpMessage, QuotedPacket};
+pub use ip_header::{parse_ip_header, IpHeader, IpPacketHeader};
+pub use ip_reassembly::{FragmentEvent, FragmentPolicy, IpReassembler};
+pub use ipv6_header::{parse_ipv6_header, Ipv6Header};
+pub use link_layer::{decode_link_layer, LinkLayer};
+pub use output::{Output, OutputTarget};
+pub use packet_log::{PacketLogConfig, PacketLogRow, PacketLogWriter};
+pub use packet_analysis::{packet_analysis, packet_analysis_until};
+pub use pipeline::{Pipeline, PipelineBuilder};
+pub use rule::{parse_rule, Rule, RuleSet, RuleVars};
+pub use rule_engine::{RuleAlert, RuleEngine};
+pub use scan_detector::{ScanDetector, ScanEvent, ScanKind};
+pub use stream_consumer::{CloseReason, StreamConsumer, UdpConsumer};
+pub use syn_flood::{SynFloodConfig, SynFloodDetector, SynFloodEvent};
+pub use tcp_anomaly::{TcpAnomaly, TcpAnomalyDetector, TcpAnomalyKind};
+pub use tcp_header::{parse_tcp_header, TcpHeader, TcpOption};
+pub use tcp_reassembly::ReassemblyPolicy;
+pub use tcp_stream::{TcpState, TcpStream, TcpStreamEvent, TcpStreamKey};
+pub use udp_flow::{UdpFlow, UdpFlowKey};
+pub use udp_header::{parse_udp_header, UdpHeader};
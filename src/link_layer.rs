@@ -0,0 +1,238 @@
+use pcap::Linktype;
+
+// EtherTypeの定義
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_IPV6: u16 = 0x86DD;
+pub const ETHERTYPE_VLAN: u16 = 0x8100; // 802.1Q
+pub const ETHERTYPE_QINQ: u16 = 0x88A8; // 802.1ad
+pub const ETHERTYPE_QINQ_LEGACY: u16 = 0x9100; // 802.1ad標準化前のQinQ
+pub const ETHERTYPE_MPLS_UNICAST: u16 = 0x8847;
+pub const ETHERTYPE_MPLS_MULTICAST: u16 = 0x8848;
+
+// DLT_RAWはプラットフォームによって値が異なる (ファイルではLINKTYPE_RAW=101に変換される)
+const DLT_RAW_LINUX: Linktype = Linktype(12);
+const DLT_RAW_OPENBSD: Linktype = Linktype(14);
+
+const ETHERNET_HEADER_SIZE: usize = 14;
+const LINUX_SLL_HEADER_SIZE: usize = 16;
+const LINUX_SLL2_HEADER_SIZE: usize = 20;
+const NULL_HEADER_SIZE: usize = 4;
+const VLAN_TAG_SIZE: usize = 4;
+const MPLS_LABEL_SIZE: usize = 4;
+
+// BSDループバック(NULL/LOOP)のアドレスファミリ
+const BSD_AF_INET: u32 = 2;
+const BSD_AF_INET6: [u32; 3] = [24, 28, 30]; // NetBSD/OpenBSD, FreeBSD, macOS
+const LINUX_AF_INET6: u32 = 10;
+
+// リンク層を取り除いた結果
+#[derive(Debug)]
+pub struct LinkLayer<'a> {
+    pub ethertype: u16,     // ネットワーク層のプロトコル (EtherType)
+    pub vlan_ids: Vec<u16>, // 外側から順に並べたVLAN ID
+    pub payload: &'a [u8],  // ネットワーク層以降のデータ
+}
+
+// キャプチャのデータリンク種別に応じてリンク層ヘッダーを解析する
+// 未対応のデータリンクや短すぎるフレームの場合はNoneを返す
+pub fn decode_link_layer(linktype: Linktype, data: &[u8]) -> Option<LinkLayer<'_>> {
+    let (ethertype, payload) = match linktype {
+        Linktype::ETHERNET => {
+            if data.len() < ETHERNET_HEADER_SIZE {
+                return None;
+            }
+            (u16::from_be_bytes([data[12], data[13]]), &data[ETHERNET_HEADER_SIZE..])
+        }
+        // Linux cooked capture (anyデバイス)
+        Linktype::LINUX_SLL => {
+            if data.len() < LINUX_SLL_HEADER_SIZE {
+                return None;
+            }
+            (u16::from_be_bytes([data[14], data[15]]), &data[LINUX_SLL_HEADER_SIZE..])
+        }
+        Linktype::LINUX_SLL2 => {
+            if data.len() < LINUX_SLL2_HEADER_SIZE {
+                return None;
+            }
+            (u16::from_be_bytes([data[0], data[1]]), &data[LINUX_SLL2_HEADER_SIZE..])
+        }
+        // ヘッダーなしのIPパケット (tunデバイスなど)
+        Linktype::RAW | DLT_RAW_LINUX | DLT_RAW_OPENBSD => (ip_version_to_ethertype(data)?, data),
+        Linktype::IPV4 => (ETHERTYPE_IPV4, data),
+        Linktype::IPV6 => (ETHERTYPE_IPV6, data),
+        // BSDループバック (NULLはホストバイトオーダー、LOOPはネットワークバイトオーダー)
+        Linktype::NULL | Linktype::LOOP => {
+            if data.len() < NULL_HEADER_SIZE {
+                return None;
+            }
+            let family = if linktype == Linktype::LOOP {
+                u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+            } else {
+                // キャプチャしたホストのバイトオーダーが不明なため、値の大きさで判別する
+                let le = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                if le > 0xFFFF { le.swap_bytes() } else { le }
+            };
+            (address_family_to_ethertype(family)?, &data[NULL_HEADER_SIZE..])
+        }
+        _ => return None,
+    };
+
+    decode_ethertype(ethertype, payload)
+}
+
+// VLANタグとMPLSラベルを剥がしてネットワーク層まで進める
+fn decode_ethertype(mut ethertype: u16, mut payload: &[u8]) -> Option<LinkLayer<'_>> {
+    let mut vlan_ids = Vec::new();
+
+    loop {
+        match ethertype {
+            ETHERTYPE_VLAN | ETHERTYPE_QINQ | ETHERTYPE_QINQ_LEGACY => {
+                if payload.len() < VLAN_TAG_SIZE {
+                    return None;
+                }
+                // TCIの下位12ビットがVLAN ID
+                let tci = u16::from_be_bytes([payload[0], payload[1]]);
+                vlan_ids.push(tci & 0x0FFF);
+                ethertype = u16::from_be_bytes([payload[2], payload[3]]);
+                payload = &payload[VLAN_TAG_SIZE..];
+            }
+            ETHERTYPE_MPLS_UNICAST | ETHERTYPE_MPLS_MULTICAST => {
+                // Bottom of Stackビットが立つまでラベルを読み進める
+                loop {
+                    if payload.len() < MPLS_LABEL_SIZE {
+                        return None;
+                    }
+                    let bottom_of_stack = payload[2] & 0x01 != 0;
+                    payload = &payload[MPLS_LABEL_SIZE..];
+                    if bottom_of_stack {
+                        break;
+                    }
+                }
+                // MPLSにはペイロードの種別がないため、IPのバージョンから判別する
+                ethertype = ip_version_to_ethertype(payload)?;
+            }
+            _ => {
+                return Some(LinkLayer {
+                    ethertype,
+                    vlan_ids,
+                    payload,
+                })
+            }
+        }
+    }
+}
+
+fn ip_version_to_ethertype(data: &[u8]) -> Option<u16> {
+    match data.first()? >> 4 {
+        4 => Some(ETHERTYPE_IPV4),
+        6 => Some(ETHERTYPE_IPV6),
+        _ => None,
+    }
+}
+
+fn address_family_to_ethertype(family: u32) -> Option<u16> {
+    match family {
+        BSD_AF_INET => Some(ETHERTYPE_IPV4),
+        LINUX_AF_INET6 => Some(ETHERTYPE_IPV6),
+        family if BSD_AF_INET6.contains(&family) => Some(ETHERTYPE_IPV6),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IPV4: [u8; 4] = [0x45, 0, 0, 20];
+    const IPV6: [u8; 4] = [0x60, 0, 0, 0];
+
+    fn ethernet(ethertype: u16, rest: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(rest);
+        frame
+    }
+
+    #[test]
+    fn decodes_ethernet_and_cooked_captures() {
+        let frame = ethernet(ETHERTYPE_IPV4, &IPV4);
+        let link = decode_link_layer(Linktype::ETHERNET, &frame).unwrap();
+        assert_eq!(link.ethertype, ETHERTYPE_IPV4);
+        assert_eq!(link.payload, IPV4);
+        assert!(link.vlan_ids.is_empty());
+        assert!(decode_link_layer(Linktype::ETHERNET, &frame[..13]).is_none());
+
+        let mut sll = vec![0; 14];
+        sll.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+        sll.extend_from_slice(&IPV6);
+        let link = decode_link_layer(Linktype::LINUX_SLL, &sll).unwrap();
+        assert_eq!((link.ethertype, link.payload), (ETHERTYPE_IPV6, &IPV6[..]));
+
+        let mut sll2 = ETHERTYPE_IPV4.to_be_bytes().to_vec();
+        sll2.resize(LINUX_SLL2_HEADER_SIZE, 0);
+        sll2.extend_from_slice(&IPV4);
+        let link = decode_link_layer(Linktype::LINUX_SLL2, &sll2).unwrap();
+        assert_eq!((link.ethertype, link.payload), (ETHERTYPE_IPV4, &IPV4[..]));
+    }
+
+    #[test]
+    fn raw_ip_uses_version_nibble() {
+        for linktype in [Linktype::RAW, DLT_RAW_LINUX, DLT_RAW_OPENBSD] {
+            assert_eq!(decode_link_layer(linktype, &IPV4).unwrap().ethertype, ETHERTYPE_IPV4);
+            assert_eq!(decode_link_layer(linktype, &IPV6).unwrap().ethertype, ETHERTYPE_IPV6);
+            assert!(decode_link_layer(linktype, &[0x50]).is_none());
+            assert!(decode_link_layer(linktype, &[]).is_none());
+        }
+    }
+
+    #[test]
+    fn loopback_address_family_in_either_byte_order() {
+        // NULLはキャプチャしたホストのバイトオーダー
+        for header in [2u32.to_le_bytes(), 2u32.to_be_bytes()] {
+            let frame = [&header[..], &IPV4].concat();
+            assert_eq!(decode_link_layer(Linktype::NULL, &frame).unwrap().ethertype, ETHERTYPE_IPV4);
+        }
+        for family in [10u32, 24, 28, 30] {
+            let frame = [&family.to_le_bytes()[..], &IPV6].concat();
+            assert_eq!(decode_link_layer(Linktype::NULL, &frame).unwrap().ethertype, ETHERTYPE_IPV6);
+        }
+        let frame = [&30u32.to_be_bytes()[..], &IPV6].concat();
+        assert_eq!(decode_link_layer(Linktype::LOOP, &frame).unwrap().ethertype, ETHERTYPE_IPV6);
+        assert!(decode_link_layer(Linktype::NULL, &[7, 0, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn strips_stacked_vlan_tags() {
+        // 外側のQinQタグ(VLAN 100)と内側の802.1Qタグ(優先度付きのVLAN 200)
+        let mut rest = vec![0x00, 100];
+        rest.extend_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+        rest.extend_from_slice(&[0xA0, 200]);
+        rest.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        rest.extend_from_slice(&IPV4);
+        let frame = ethernet(ETHERTYPE_QINQ, &rest);
+        let link = decode_link_layer(Linktype::ETHERNET, &frame).unwrap();
+        assert_eq!(link.vlan_ids, [100, 200]);
+        assert_eq!((link.ethertype, link.payload), (ETHERTYPE_IPV4, &IPV4[..]));
+
+        // タグの途中で切れている
+        assert!(decode_link_layer(Linktype::ETHERNET, &ethernet(ETHERTYPE_VLAN, &[0, 1])).is_none());
+    }
+
+    #[test]
+    fn skips_mpls_label_stack() {
+        // Bottom of Stackビットが立つ2つ目のラベルまで読み進める
+        let rest = [&[0, 0x10, 0x00, 64][..], &[0, 0x20, 0x01, 64], &IPV6].concat();
+        let frame = ethernet(ETHERTYPE_MPLS_UNICAST, &rest);
+        let link = decode_link_layer(Linktype::ETHERNET, &frame).unwrap();
+        assert_eq!((link.ethertype, link.payload), (ETHERTYPE_IPV6, &IPV6[..]));
+
+        // Bottom of Stackのラベルがない
+        let rest = [0, 0x10, 0x00, 64];
+        assert!(decode_link_layer(Linktype::ETHERNET, &ethernet(ETHERTYPE_MPLS_MULTICAST, &rest)).is_none());
+    }
+
+    #[test]
+    fn rejects_unsupported_linktype() {
+        assert!(decode_link_layer(Linktype::IEEE802_11, &IPV4).is_none());
+    }
+}
@@ -1,26 +1,80 @@
-use crate::select_device::select_device;
+use clap::Parser;
 use dotenv::dotenv;
-use pcap::{Active, Capture, Device};
-mod packet_analysis;
-mod select_device;
-mod ip_header;
-mod ip_reassembly;
-mod packet_processor;
-mod tcp_header;
-mod tcp_stream;
-
-use crate::packet_analysis::packet_analysis;
-use std::error::Error;
-
-fn main() -> Result<(), Box<dyn Error>> {
-    // .envファイルを読み込む
+use nids_for_rust::config::Config;
+use nids_for_rust::select_device::{open_capture_file, open_device, select_device};
+#[cfg(unix)]
+use nids_for_rust::signal::StopSignals;
+use nids_for_rust::{packet_analysis_until, EveLog, NidsError, Output, PacketLogWriter, PipelineBuilder, RuleSet};
+use pcap::{Activated, Capture, Device};
+use std::net::IpAddr;
+
+fn main() -> Result<(), NidsError> {
+    // .envファイルを読み込む (引数の解析より前に読み込んで環境変数として参照させる)
     dotenv().ok();
-    let (cap, device): (Capture<Active>, Device) = select_device()?;
-    println!("デバイスの選択に成功しました: {}", device.name);
+    let config = Config::parse();
+    let capture_config = config.capture_config();
+
+    // ファイル指定があればオフラインで再生し、インターフェース指定がなければ対話的に選択する
+    // ファイルの再生ではキャプチャしたホストのアドレスが分からないため、チェックサムのオフロードは判定しない
+    let (mut cap, interface, local_addresses): (Capture<dyn Activated>, String, Vec<IpAddr>) =
+        match (&config.read, &config.interface) {
+            (Some(path), _) => (open_capture_file(path)?.into(), path.display().to_string(), Vec::new()),
+            (None, Some(name)) => {
+                let (cap, device) = open_device(name, &capture_config)?;
+                println!("デバイスを開きました: {}", device.name);
+                let addresses = device_addresses(&device);
+                (cap.into(), device.name, addresses)
+            }
+            (None, None) => {
+                let (cap, device) = select_device(&capture_config)?;
+                println!("デバイスの選択に成功しました: {}", device.name);
+                let addresses = device_addresses(&device);
+                (cap.into(), device.name, addresses)
+            }
+        };
+
+    // Ctrl+CやSIGTERMではキャプチャを止めてから終了処理を行う (書き込みのスレッドを作成する前にシグナルをブロックする)
+    #[cfg(unix)]
+    let stop = StopSignals::block()?.stop_on_signal(cap.breakloop_handle());
+    #[cfg(not(unix))]
+    let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
 
-    if let Err(e) = packet_analysis(cap) {
+    // アプリケーション層の解析などのコンシューマーは .consumer() で登録する
+    let mut builder = PipelineBuilder::from_config(&config)
+        .interface(&interface)
+        .local_addresses(local_addresses)
+        .output(Output::open(&config.output)?);
+    if !config.eve.is_empty() {
+        builder = builder.eve(EveLog::open(&config.eve)?);
+    }
+    if let Some(packet_log_config) = config.packet_log_config() {
+        builder = builder.packet_log(PacketLogWriter::start(packet_log_config)?);
+    }
+    if !config.rules.is_empty() {
+        // 解析できないルールは報告して読み飛ばす
+        let vars = config.rule_vars();
+        let mut rule_set = RuleSet::new();
+        for path in &config.rules {
+            let loaded = rule_set.load(path, &vars)?;
+            println!("ルールを読み込みました: {} ({}件)", path.display(), loaded);
+        }
+        for error in &rule_set.errors {
+            eprintln!("ルールを読み込めませんでした: {}", error);
+        }
+        builder = builder.rules(rule_set.rules);
+    }
+    let mut pipeline = builder.build();
+
+    if let Err(e) = packet_analysis_until(cap, &mut pipeline, &stop) {
         println!("パケットの解析に失敗しました: {}", e);
     }
 
+    pipeline.finish();
+
     Ok(())
-}
\ No newline at end of file
+}
+
+// デバイスに割り当てられたアドレス
+fn device_addresses(device: &Device) -> Vec<IpAddr> {
+    device.addresses.iter().map(|address| address.addr).collect()
+}
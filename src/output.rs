@@ -0,0 +1,88 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+// 解析結果の出力先
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputTarget {
+    Stdout,
+    File(PathBuf),
+}
+
+impl FromStr for OutputTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "stdout" | "-" => Ok(OutputTarget::Stdout),
+            s if s.starts_with("file:") => {
+                let path = &s["file:".len()..];
+                if path.is_empty() {
+                    return Err("file: の後にパスを指定してください".to_string());
+                }
+                Ok(OutputTarget::File(PathBuf::from(path)))
+            }
+            s => Err(format!("不明な出力先です: {} (stdout, file:<path>)", s)),
+        }
+    }
+}
+
+enum OutputWriter {
+    Stdout(io::Stdout),
+    File(BufWriter<File>),
+}
+
+impl OutputWriter {
+    fn as_write(&mut self) -> &mut dyn Write {
+        match self {
+            OutputWriter::Stdout(w) => w,
+            OutputWriter::File(w) => w,
+        }
+    }
+}
+
+// 複数の出力先へ同じ行を書き出す
+pub struct Output {
+    writers: Vec<OutputWriter>,
+}
+
+impl Output {
+    pub fn open(targets: &[OutputTarget]) -> io::Result<Self> {
+        let mut writers = Vec::new();
+        for target in targets {
+            let writer = match target {
+                OutputTarget::Stdout => OutputWriter::Stdout(io::stdout()),
+                OutputTarget::File(path) => {
+                    let file = OpenOptions::new().create(true).append(true).open(path)?;
+                    OutputWriter::File(BufWriter::new(file))
+                }
+            };
+            writers.push(writer);
+        }
+        Ok(Output { writers })
+    }
+
+    // 標準出力のみに書き出す
+    pub fn stdout() -> Self {
+        Output {
+            writers: vec![OutputWriter::Stdout(io::stdout())],
+        }
+    }
+
+    pub fn write_line(&mut self, line: &str) {
+        for writer in &mut self.writers {
+            if let Err(e) = writeln!(writer.as_write(), "{}", line) {
+                eprintln!("出力の書き込みに失敗しました: {}", e);
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        for writer in &mut self.writers {
+            if let Err(e) = writer.as_write().flush() {
+                eprintln!("出力のフラッシュに失敗しました: {}", e);
+            }
+        }
+    }
+}
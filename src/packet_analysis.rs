@@ -1,26 +1,38 @@
-use crate::ip_reassembly::IpReassembler;
-use crate::packet_processor::process_packet;
-use pcap::{Active, Capture};
-use std::collections::HashMap;
-use std::time::Duration;
-use crate::tcp_stream;
-use crate::tcp_stream::{TcpStream, TcpStreamKey};
+use crate::error::NidsError;
+use crate::pipeline::Pipeline;
+use pcap::{Activated, Capture};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-pub fn packet_analysis(mut cap: Capture<Active>) -> Result<(), Box<dyn std::error::Error>> {
-    let mut streams: HashMap<TcpStreamKey, TcpStream> = HashMap::new();
-    let mut ip_reassembler = IpReassembler::new(Duration::from_secs(30));
+// ライブキャプチャ(Active)とファイル再生(Offline)のどちらも同じ経路で解析する
+pub fn packet_analysis<T: Activated + ?Sized>(cap: Capture<T>, pipeline: &mut Pipeline) -> Result<(), NidsError> {
+    packet_analysis_until(cap, pipeline, &AtomicBool::new(false))
+}
 
-    while let Ok(packet) = cap.next_packet() {
-        match process_packet(&packet, &mut streams, &mut ip_reassembler) {
-            Ok(_) => (),
-            Err(e) => eprintln!("パケット処理中にエラーが発生しました: {}", e),
-        }
+// stopがtrueになるまで解析する (シグナルで停止する場合はbreakloopでパケットの待機も中断する)
+pub fn packet_analysis_until<T: Activated + ?Sized>(
+    mut cap: Capture<T>,
+    pipeline: &mut Pipeline,
+    stop: &AtomicBool,
+) -> Result<(), NidsError> {
+    let linktype = cap.get_datalink();
 
-        // 古いストリームの削除
-        streams.retain(|_, stream| {
-            stream.last_activity.elapsed() < Duration::from_secs(300) || stream.state != tcp_stream::TcpState::Closed
-        });
+    while !stop.load(Ordering::Relaxed) {
+        let packet = match cap.next_packet() {
+            Ok(packet) => packet,
+            // ライブキャプチャのタイムアウトは読み込みを継続
+            Err(pcap::Error::TimeoutExpired) => continue,
+            // キャプチャファイルの終端 (breakloopで中断された場合も同じエラーになる)
+            Err(pcap::Error::NoMorePackets) => {
+                if !stop.load(Ordering::Relaxed) {
+                    println!("キャプチャファイルの読み込みが完了しました");
+                }
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        pipeline.process(&packet, linktype);
     }
 
     Ok(())
-}
\ No newline at end of file
+}
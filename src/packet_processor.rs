@@ -1,196 +1,1013 @@
-use crate::ip_header::{parse_ip_header, IpHeader};
+use crate::checksum::{verify_ipv4_header, verify_transport, ChecksumKind, ChecksumValidator, ChecksumVerdict};
+use crate::eve::{timestamp_string, EveFlow, EveLog};
+use crate::icmp_detector::IcmpDetector;
+use crate::icmp_header::{parse_icmp_header, parse_quoted_packet, IcmpHeader, IcmpMessage, QuotedPacket};
+use crate::ip_header::{parse_ip_header, IpPacketHeader};
 use crate::ip_reassembly::IpReassembler;
-use crate::tcp_header::{parse_tcp_header, parse_tcp_options};
-use crate::tcp_stream::{TcpStream, TcpStreamKey, TCP_SYN};
+use crate::ipv6_header::{parse_ipv6_header, skip_extension_headers};
+use crate::link_layer::{decode_link_layer, ETHERTYPE_IPV4, ETHERTYPE_IPV6};
+use crate::output::Output;
+use crate::packet_log::{application_protocol, PacketLogRow, PacketLogWriter};
+use crate::rule_engine::{is_established, PacketContext, RuleEngine};
+use crate::scan_detector::ScanDetector;
+use crate::stream_consumer::{deliver_chunks, CloseReason, StreamConsumer, UdpConsumer};
+use crate::stream_table::StreamTable;
+use crate::syn_flood::SynFloodDetector;
+use crate::tcp_anomaly::TcpAnomalyDetector;
+use crate::tcp_header::{parse_tcp_header, TcpHeader};
+use crate::tcp_reassembly::{ReassemblyPolicy, StreamLimits};
+use crate::tcp_stream::{TcpStream, TCP_ACK, TCP_FIN, TCP_RST, TCP_SYN};
+use crate::udp_flow::{UdpFlow, UdpFlowTable};
+use crate::udp_header::{parse_udp_header, UdpHeader};
 use chrono::{DateTime, Local};
-use std::collections::HashMap;
+use serde_json::{json, Value};
+use pcap::Linktype;
+use std::net::IpAddr;
 use std::time::SystemTime;
 
+// 上位層のプロトコル番号
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+pub const IPPROTO_ICMPV6: u8 = 58;
+
+// パケット処理の間で保持する状態
+pub struct ProcessorState {
+    pub streams: StreamTable,
+    pub ip_reassembler: IpReassembler,
+    pub output: Output,
+    pub tcp_policy: ReassemblyPolicy,  // 重複するTCPセグメントの再構築ポリシー
+    pub stream_limits: StreamLimits,   // 1ストリームあたりのキューと保持データの上限
+    pub midstream: bool,               // SYNを観測していない接続も途中から追跡するか
+    pub consumers: Vec<Box<dyn StreamConsumer>>, // 再構築したデータを受け取るコンシューマー
+    pub udp_flows: UdpFlowTable,
+    pub udp_consumers: Vec<Box<dyn UdpConsumer>>, // UDPのデータグラムを受け取るコンシューマー
+    pub icmp_detector: IcmpDetector,
+    pub syn_flood: SynFloodDetector, // 半開きの接続の追跡とSYNフラッドの検知
+    pub scan_detector: ScanDetector, // ポートスキャンとホストスイープの検知
+    pub tcp_anomalies: TcpAnomalyDetector, // TCPヘッダーのフラグや長さの異常の検知
+    pub checksums: ChecksumValidator, // チェックサムの検証とインターフェースごとの不正な件数
+    pub packet_log: Option<PacketLogWriter>, // パケットごとの行を書き込むデータベース
+    pub eve: Option<EveLog>,                 // 構造化したイベントの出力
+    pub print_packets: bool,                 // パケットごとの行(Arrival timeなど)を出力するか
+    pub rules: RuleEngine,                   // 読み込んだルールによる検知
+}
+
 // パケットを処理
-pub fn process_packet<>(
+pub fn process_packet(
     packet: &pcap::Packet,
-    streams: &mut HashMap<TcpStreamKey, TcpStream>,
-    ip_reassembler: &mut IpReassembler,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let arrival_time = SystemTime::now();
-    let eth_header_size = 14; // Ethernetヘッダーのサイズ
-    if packet.data.len() <= eth_header_size {
-        return Ok(());
+    state: &mut ProcessorState,
+    arrival_time: SystemTime,
+    linktype: Linktype,
+) {
+    // データリンク種別に応じてリンク層を取り除く
+    let link_layer = match decode_link_layer(linktype, packet.data) {
+        Some(link_layer) => link_layer,
+        None => return,
+    };
+
+    let ip_data = link_layer.payload;
+    let vlan_ids = &link_layer.vlan_ids;
+
+    match link_layer.ethertype {
+        ETHERTYPE_IPV4 => process_ipv4_packet(ip_data, state, arrival_time, vlan_ids),
+        ETHERTYPE_IPV6 => process_ipv6_packet(ip_data, state, arrival_time, vlan_ids),
+        // IP以外(ARPなど)は対象外
+        _ => (),
+    }
+
+    // 削除したストリーム(終了、タイムアウト、上限による破棄)をコンシューマーに通知
+    notify_closed_streams(state);
+}
+
+// 削除したストリームとUDPフローをコンシューマーに通知
+pub fn notify_closed_streams(state: &mut ProcessorState) {
+    for (key, stream, reason) in state.streams.take_closed() {
+        state.rules.remove_stream(&key);
+        for consumer in state.consumers.iter_mut() {
+            consumer.on_close(&key, &stream, reason);
+        }
+        if let Some(eve) = &mut state.eve {
+            let flow = EveFlow::from_key("TCP", &key);
+            eve.write_event(
+                stream.last_activity,
+                "flow",
+                Some(&flow),
+                json!({
+                    "pkts_toserver": stream.client_packets,
+                    "pkts_toclient": stream.server_packets,
+                    "bytes_toserver": stream.client_bytes,
+                    "bytes_toclient": stream.server_bytes,
+                    "start": timestamp_string(stream.first_seen),
+                    "end": timestamp_string(stream.last_activity),
+                    "reason": close_reason_name(reason),
+                    "state": format!("{:?}", stream.state),
+                }),
+            );
+            eve.write_event(
+                stream.last_activity,
+                "stream-close",
+                Some(&flow),
+                json!({
+                    "reason": close_reason_name(reason),
+                    "state": format!("{:?}", stream.state),
+                    "client_state": format!("{:?}", stream.client_state),
+                    "server_state": format!("{:?}", stream.server_state),
+                    "midstream": stream.midstream,
+                    "client_data_bytes": stream.client_data.len(),
+                    "server_data_bytes": stream.server_data.len(),
+                    "truncated_bytes": stream.truncated_bytes,
+                    "ooo_dropped_segments": stream.ooo_dropped_segments,
+                    "retransmitted_segments": stream.retransmitted_segments,
+                    "conflicting_segments": stream.conflicting_segments,
+                    "paws_rejected_segments": stream.paws_rejected_segments,
+                    "rejected_rst_segments": stream.rejected_rst_segments,
+                }),
+            );
+        }
+    }
+    for (key, flow, reason) in state.udp_flows.take_closed() {
+        for consumer in state.udp_consumers.iter_mut() {
+            consumer.on_close(&key, &flow, reason);
+        }
+        if let Some(eve) = &mut state.eve {
+            eve.write_event(
+                flow.last_activity,
+                "flow",
+                Some(&EveFlow::from_key("UDP", &key)),
+                json!({
+                    "pkts_toserver": flow.client_packets,
+                    "pkts_toclient": flow.server_packets,
+                    "bytes_toserver": flow.client_bytes,
+                    "bytes_toclient": flow.server_bytes,
+                    "start": timestamp_string(flow.first_seen),
+                    "end": timestamp_string(flow.last_activity),
+                    "reason": close_reason_name(reason),
+                }),
+            );
+        }
+    }
+}
+
+fn close_reason_name(reason: CloseReason) -> &'static str {
+    match reason {
+        CloseReason::Closed => "closed",
+        CloseReason::Timeout => "timeout",
+        CloseReason::Evicted => "evicted",
+        CloseReason::Shutdown => "shutdown",
+    }
+}
+
+// アラートを出力し、構造化したイベントにも書き出す
+// ストリームを借用したまま呼べるよう、状態全体ではなく出力先のみを受け取る
+fn report_event(
+    output: &mut Output,
+    eve: &mut Option<EveLog>,
+    now: SystemTime,
+    event_type: &str,
+    flow: Option<&EveFlow>,
+    message: &str,
+    details: Value,
+) {
+    output.write_line(&format!("Alert: {}", message));
+    if let Some(eve) = eve {
+        eve.write_event(now, event_type, flow, details);
     }
+}
+
+// SYNフラッドと半開きの接続のイベントを出力 (タイムアウトによる攻撃の終了はパイプラインの定期処理から出力する)
+pub fn report_syn_flood_events(state: &mut ProcessorState, now: SystemTime) {
+    for event in state.syn_flood.take_events() {
+        let message = event.to_string();
+        let details = json!({
+            "signature": message,
+            "type": event.name(),
+            "category": "syn_flood",
+            "host": event.host().to_string(),
+        });
+        report_event(&mut state.output, &mut state.eve, now, "alert", None, &message, details);
+    }
+}
+
+// 終了したスキャンをまとめて出力
+pub fn report_scan_events(state: &mut ProcessorState, now: SystemTime) {
+    const MAX_EVE_LISTED: usize = 1024;
+    for event in state.scan_detector.take_events() {
+        let message = event.to_string();
+        let details = json!({
+            "signature": message,
+            "type": event.pattern.name(),
+            "category": "scan",
+            "scan_type": event.kind.name(),
+            "src_ip": event.src_ip.to_string(),
+            "ports": event.ports.iter().take(MAX_EVE_LISTED).collect::<Vec<_>>(),
+            "hosts": event.hosts.iter().take(MAX_EVE_LISTED).map(|host| host.to_string()).collect::<Vec<_>>(),
+            "open": event
+                .open
+                .iter()
+                .take(MAX_EVE_LISTED)
+                .map(|(host, port)| format!("{}:{}", host, port))
+                .collect::<Vec<_>>(),
+            "closed": event.closed,
+            "filtered": event.filtered,
+            "probes": event.probes,
+            "start": timestamp_string(event.started),
+            "end": timestamp_string(event.ended),
+            "duration": event.duration().as_secs_f64(),
+        });
+        report_event(&mut state.output, &mut state.eve, now, "alert", None, &message, details);
+    }
+}
+
+// TCPヘッダーの異常を出力 (レート制限により通知しなかった件数を含める)
+fn report_tcp_anomalies(state: &mut ProcessorState, now: SystemTime) {
+    for anomaly in state.tcp_anomalies.take_events() {
+        let message = anomaly.to_string();
+        let flow = EveFlow::new(
+            "TCP",
+            anomaly.src_ip,
+            Some(anomaly.src_port),
+            anomaly.dst_ip,
+            Some(anomaly.dst_port),
+        );
+        let details = json!({
+            "type": anomaly.kind.name(),
+            "message": message,
+            "flags": format!("{:02x}", anomaly.flags),
+            "data_offset": anomaly.data_offset,
+            "urgent_ptr": anomaly.urgent_ptr,
+            "suppressed": anomaly.suppressed,
+        });
+        report_event(&mut state.output, &mut state.eve, now, "anomaly", Some(&flow), &message, details);
+    }
+}
 
-    let ip_data = &packet.data[eth_header_size..];
+// ルールに一致したパケット(ストリーム)のアラートを出力
+fn report_rule_alerts(state: &mut ProcessorState, now: SystemTime) {
+    for alert in state.rules.take_alerts() {
+        let rule = &alert.rule;
+        let flow = EveFlow::new(alert.protocol_name(), alert.src_ip, alert.src_port, alert.dst_ip, alert.dst_port);
+        let details = json!({
+            "action": rule.action.name(),
+            "gid": rule.gid,
+            "signature_id": rule.sid,
+            "rev": rule.rev,
+            "signature": rule.msg,
+            "category": rule.classtype,
+            "severity": rule.priority,
+        });
+        report_event(&mut state.output, &mut state.eve, now, "alert", Some(&flow), &alert.to_string(), details);
+    }
+}
 
+fn process_ipv4_packet(
+    ip_data: &[u8],
+    state: &mut ProcessorState,
+    arrival_time: SystemTime,
+    vlan_ids: &[u16],
+) {
     if let Some((ip_header, ip_header_size)) = parse_ip_header(ip_data) {
-        let payload = &ip_data[ip_header_size..];
+        let payload = match ip_payload(ip_data, ip_header_size, ip_header.total_length as usize) {
+            Some(payload) => payload,
+            None => return,
+        };
+
+        // ヘッダーのチェックサムが不正なパケットはポリシーによっては再構築にも使わない
+        if state.checksums.enabled() {
+            let valid = verify_ipv4_header(&ip_data[..ip_header_size]);
+            let (src_ip, dst_ip) = (IpAddr::V4(ip_header.src_ip), IpAddr::V4(ip_header.dst_ip));
+            if !check_checksum(state, ChecksumKind::Ipv4, valid, src_ip, dst_ip, arrival_time) {
+                return;
+            }
+        }
+        // キャプチャで切り詰められたパケットは上位層のチェックサムを検証できない
+        let complete = is_complete(ip_data, ip_header.total_length as usize);
 
-        // IPの再構築を試みる
-        if let Some(reassembled_packet) = ip_reassembler.process_packet(&ip_header, payload) {
+        // IPの再構築を試みる (フラグメントされていないパケットはそのまま返る)
+        // 再構築が完了していないフラグメントは保持され、ここでは処理しない
+        let reassembled_packet = state.ip_reassembler.process_packet(&ip_header, payload, arrival_time);
+        report_fragment_events(state, arrival_time);
+
+        if let Some(reassembled_packet) = reassembled_packet {
             // 再構築されたパケットを処理
-            match process_reassembled_packet(
-                &ip_header,
+            process_transport_packet(
+                &IpPacketHeader::V4(ip_header),
                 &reassembled_packet,
-                streams,
+                complete,
+                state,
                 arrival_time,
-            ) {
-                Ok(_) => (),
-                Err(e) => eprintln!("Error processing reassembled packet: {}", e),
+                vlan_ids,
+            );
+        }
+    }
+}
+
+fn process_ipv6_packet(
+    ip_data: &[u8],
+    state: &mut ProcessorState,
+    arrival_time: SystemTime,
+    vlan_ids: &[u16],
+) {
+    if let Some((mut ipv6_header, header_size)) = parse_ipv6_header(ip_data) {
+        // ペイロード長は拡張ヘッダーを含み、固定ヘッダー(40バイト)を含まない
+        let packet_length = match ipv6_header.payload_length as usize {
+            0 => 0,
+            length => length + 40,
+        };
+        let payload = match ip_payload(ip_data, header_size, packet_length) {
+            Some(payload) => payload,
+            None => return,
+        };
+        let complete = is_complete(ip_data, packet_length);
+
+        match ipv6_header.fragment.clone() {
+            Some(fragment) => {
+                // 再構築が完了するまでフラグメントは保持する
+                let reassembled_packet = state.ip_reassembler.process_ipv6_fragment(
+                    &ipv6_header,
+                    &fragment,
+                    header_size,
+                    payload,
+                    arrival_time,
+                );
+                report_fragment_events(state, arrival_time);
+
+                if let Some(reassembled_packet) = reassembled_packet {
+                    // フラグメント化可能部分に残っている拡張ヘッダーを読み飛ばす
+                    if let Some((protocol, offset)) = skip_extension_headers(ipv6_header.protocol, &reassembled_packet) {
+                        ipv6_header.protocol = protocol;
+                        process_transport_packet(
+                            &IpPacketHeader::V6(ipv6_header),
+                            &reassembled_packet[offset..],
+                            complete,
+                            state,
+                            arrival_time,
+                            vlan_ids,
+                        );
+                    }
+                }
             }
-        } else {
-            // フラグメントされていないパケットまたは再構築が完了していないパケットの処理
-            match process_tcp_packet(&ip_header, payload, streams, arrival_time) {
-                Ok(_) => (),
-                Err(e) => eprintln!("Error processing TCP packet: {}", e),
+            None => {
+                process_transport_packet(
+                    &IpPacketHeader::V6(ipv6_header),
+                    payload,
+                    complete,
+                    state,
+                    arrival_time,
+                    vlan_ids,
+                );
             }
         }
     }
+}
 
-    // 100パケットごとにIP再構築のキャッシュをクリーンアップ
-    if packet.header.len % 100 == 0 {
-        ip_reassembler.cleanup();
+// フラグメントの重複などのイベントを出力
+fn report_fragment_events(state: &mut ProcessorState, now: SystemTime) {
+    for event in state.ip_reassembler.take_events() {
+        let (src_ip, dst_ip) = event.addresses();
+        let message = event.to_string();
+        let details = json!({
+            "type": event.name(),
+            "message": message,
+            "src_ip": src_ip.to_string(),
+            "dest_ip": dst_ip.map(|ip| ip.to_string()),
+        });
+        report_event(&mut state.output, &mut state.eve, now, "fragment", None, &message, details);
     }
+}
 
-    Ok(())
+// リンク層のパディングを除き、IPの長さフィールドが示す範囲のペイロードを取り出す
+// 長さが0の場合(TSOでキャプチャされたパケットやジャンボグラム)はキャプチャされた全体を使う
+fn ip_payload(ip_data: &[u8], header_size: usize, packet_length: usize) -> Option<&[u8]> {
+    let end = match packet_length {
+        0 => ip_data.len(),
+        length => length.min(ip_data.len()),
+    };
+    ip_data.get(header_size..end)
 }
 
-fn process_reassembled_packet(
-    ip_header: &IpHeader,
-    packet: &[u8],
-    streams: &mut HashMap<TcpStreamKey, TcpStream>,
-    arrival_time: SystemTime,
-) -> Result<(), Box<dyn std::error::Error>> {
-    if ip_header.protocol != 6 {
-        // TCPのプロトコル番号は6
-        return Ok(());
-    }
+// IPの長さフィールドが示す全体がキャプチャされているか
+fn is_complete(ip_data: &[u8], packet_length: usize) -> bool {
+    packet_length != 0 && packet_length <= ip_data.len()
+}
 
-    if let Some((tcp_header, tcp_header_size)) = parse_tcp_header(packet) {
-        let payload = &packet[tcp_header_size..];
-        process_tcp_header_and_payload(ip_header, &tcp_header, payload, streams, arrival_time)?;
+// チェックサムの検証結果をポリシーに従って処理し、パケットの処理を続けるかを返す
+fn check_checksum(
+    state: &mut ProcessorState,
+    kind: ChecksumKind,
+    valid: bool,
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
+    now: SystemTime,
+) -> bool {
+    let offload_detected = state.checksums.offload_detected();
+    let verdict = state.checksums.verdict(kind, valid, src_ip);
+    if offload_detected != state.checksums.offload_detected() {
+        let interface = state.checksums.interface().to_string();
+        let message = if offload_detected {
+            format!("Checksum offload no longer detected on {}, bad checksums are handled again", interface)
+        } else {
+            format!("Checksum offload detected on {}, bad checksums are accepted", interface)
+        };
+        state.output.write_line(&message);
     }
 
-    Ok(())
+    match verdict {
+        ChecksumVerdict::Accept => true,
+        ChecksumVerdict::Alert => {
+            let message = format!("bad {} checksum ({} -> {})", kind, src_ip, dst_ip);
+            let details = json!({
+                "type": "bad_checksum",
+                "layer": kind.to_string(),
+                "message": message,
+                "src_ip": src_ip.to_string(),
+                "dest_ip": dst_ip.to_string(),
+            });
+            report_event(&mut state.output, &mut state.eve, now, "anomaly", None, &message, details);
+            true
+        }
+        ChecksumVerdict::Discard => false,
+    }
 }
 
-fn process_tcp_packet(
-    ip_header: &IpHeader,
-    tcp_data: &[u8],
-    streams: &mut HashMap<TcpStreamKey, TcpStream>,
+// 上位層のプロトコルごとに処理を振り分ける
+// completeはデータ全体がキャプチャされていて上位層のチェックサムを検証できるか
+fn process_transport_packet(
+    ip_header: &IpPacketHeader,
+    data: &[u8],
+    complete: bool,
+    state: &mut ProcessorState,
     arrival_time: SystemTime,
-) -> Result<(), Box<dyn std::error::Error>> {
-    if ip_header.protocol != 6 {
-        // TCPのプロトコル番号は6
-        return Ok(());
+    vlan_ids: &[u16],
+) {
+    let protocol = ip_header.protocol();
+    let kind = match protocol {
+        IPPROTO_TCP => Some(ChecksumKind::Tcp),
+        IPPROTO_UDP => Some(ChecksumKind::Udp),
+        _ => None,
+    };
+    if let Some(kind) = kind {
+        if complete && state.checksums.enabled() {
+            let (src_ip, dst_ip) = (ip_header.src_ip(), ip_header.dst_ip());
+            let valid = verify_transport(src_ip, dst_ip, protocol, data);
+            if !check_checksum(state, kind, valid, src_ip, dst_ip, arrival_time) {
+                return;
+            }
+        }
     }
 
-    if let Some((tcp_header, tcp_header_size)) = parse_tcp_header(tcp_data) {
-        let payload = &tcp_data[tcp_header_size..];
-        process_tcp_header_and_payload(ip_header, &tcp_header, payload, streams, arrival_time)?;
+    match protocol {
+        IPPROTO_TCP => {
+            // データオフセットが不正なセグメントは解析できないため、解析の前に検査する
+            state
+                .tcp_anomalies
+                .inspect(ip_header.src_ip(), ip_header.dst_ip(), data, complete, arrival_time);
+            report_tcp_anomalies(state, arrival_time);
+            if let Some((tcp_header, tcp_header_size)) = parse_tcp_header(data) {
+                let payload = &data[tcp_header_size..];
+                process_tcp_data(ip_header, &tcp_header, payload, state, arrival_time, vlan_ids);
+            }
+        }
+        IPPROTO_UDP => {
+            if let Some((udp_header, payload)) = parse_udp_header(data) {
+                process_udp_data(ip_header, &udp_header, payload, state, arrival_time, vlan_ids);
+            }
+        }
+        IPPROTO_ICMP | IPPROTO_ICMPV6 => {
+            let ipv6 = matches!(ip_header, IpPacketHeader::V6(_));
+            if let Some((icmp_header, payload)) = parse_icmp_header(data, ipv6) {
+                process_icmp_data(ip_header, &icmp_header, payload, state, arrival_time);
+            }
+        }
+        _ => {
+            if let Some(packet_log) = &state.packet_log {
+                packet_log.log(packet_log_row(ip_header, "Other", data, arrival_time));
+            }
+        }
     }
+}
+
+// UDPのデータグラムをフローに集計し、コンシューマーに渡す
+fn process_udp_data(
+    ip_header: &IpPacketHeader,
+    udp_header: &UdpHeader,
+    payload: &[u8],
+    state: &mut ProcessorState,
+    arrival_time: SystemTime,
+    vlan_ids: &[u16],
+) {
+    let flow_key = (ip_header.src_ip(), udp_header.src_port, ip_header.dst_ip(), udp_header.dst_port);
+    let reverse_key = (ip_header.dst_ip(), udp_header.dst_port, ip_header.src_ip(), udp_header.src_port);
+
+    // 最初にデータグラムを送った側をクライアントとする
+    let is_from_client = if state.udp_flows.contains_key(&flow_key) {
+        true
+    } else if state.udp_flows.contains_key(&reverse_key) {
+        false
+    } else {
+        let mut flow = UdpFlow::new(arrival_time);
+        flow.vlan_ids = vlan_ids.to_vec();
+        for consumer in state.udp_consumers.iter_mut() {
+            consumer.on_open(&flow_key, &flow);
+        }
+        state.scan_detector.on_udp_flow(&flow_key, arrival_time);
+        state.udp_flows.insert(flow_key, flow);
+        true
+    };
+    let flow_key = if is_from_client { flow_key } else { reverse_key };
+
+    if let Some(flow) = state.udp_flows.get_mut(&flow_key) {
+        flow.update(is_from_client, payload.len(), arrival_time);
+
+        if state.print_packets {
+            state.output.write_line(&format!("Arrival time: {}", arrival_time_to_string(arrival_time)));
+            state.output.write_line(&format!(
+                "UDP: {}:{} -> {}:{} ({} bytes)",
+                ip_header.src_ip(),
+                udp_header.src_port,
+                ip_header.dst_ip(),
+                udp_header.dst_port,
+                payload.len()
+            ));
+        }
+
+        if let Some(packet_log) = &state.packet_log {
+            let mut row = packet_log_row(ip_header, "UDP", payload, arrival_time);
+            row.src_port = Some(udp_header.src_port);
+            row.dst_port = Some(udp_header.dst_port);
+            row.stream_id = Some(stream_id(&flow_key));
+            row.is_from_client = Some(is_from_client);
+            row.application_protocol = application_protocol(udp_header.src_port, udp_header.dst_port);
+            packet_log.log(row);
+        }
+
+        for consumer in state.udp_consumers.iter_mut() {
+            if is_from_client {
+                consumer.on_client_data(&flow_key, flow, payload);
+            } else {
+                consumer.on_server_data(&flow_key, flow, payload);
+            }
+        }
+
+        // サーバーが応答したフローはスキャンのプローブではない
+        if !is_from_client {
+            state.scan_detector.on_data(&flow_key, IPPROTO_UDP);
+        }
 
-    Ok(())
+        // 両方向のデータグラムを観測したフローを確立済みとみなす
+        let packet = PacketContext {
+            protocol: IPPROTO_UDP,
+            src_ip: ip_header.src_ip(),
+            src_port: Some(udp_header.src_port),
+            dst_ip: ip_header.dst_ip(),
+            dst_port: Some(udp_header.dst_port),
+            from_client: Some(is_from_client),
+            established: flow.client_packets > 0 && flow.server_packets > 0,
+            stream_tracked: false,
+        };
+        state.rules.inspect_packet(&packet, payload);
+
+        // 最終通信時刻を破棄の順序に反映する
+        state.udp_flows.refresh(&flow_key);
+        report_rule_alerts(state, arrival_time);
+    }
 }
 
-// TCPヘッダーとペイロードを処理
-fn process_tcp_header_and_payload(
-    ip_header: &IpHeader,
-    tcp_header: &crate::tcp_header::TcpHeader,
+// ICMPメッセージを検査し、エラーメッセージは引用されたパケットのストリームやフローに結び付ける
+fn process_icmp_data(
+    ip_header: &IpPacketHeader,
+    icmp_header: &IcmpHeader,
     payload: &[u8],
-    streams: &mut HashMap<TcpStreamKey, TcpStream>,
+    state: &mut ProcessorState,
     arrival_time: SystemTime,
-) -> Result<(), Box<dyn std::error::Error>> {
-    match process_tcp_data(
-        ip_header,
-        tcp_header,
-        payload,
-        streams,
-        arrival_time,
-    ) {
-        Ok(_) => (),
-        Err(e) => eprintln!("Error processing TCP data: {}", e),
+) {
+    let message = icmp_header.message(payload);
+
+    let proto = if icmp_header.ipv6 { "IPv6-ICMP" } else { "ICMP" };
+    if state.print_packets {
+        state.output.write_line(&format!("Arrival time: {}", arrival_time_to_string(arrival_time)));
+        state.output.write_line(&format!(
+            "{}: {} -> {} {}",
+            if icmp_header.ipv6 { "ICMPv6" } else { "ICMP" },
+            ip_header.src_ip(),
+            ip_header.dst_ip(),
+            message
+        ));
+    }
+
+    // テーブルのprotocolにはICMPv6の値がないため、ICMPv6はOtherとして書き込む (ip_versionで区別できる)
+    if let Some(packet_log) = &state.packet_log {
+        let protocol = if icmp_header.ipv6 { "Other" } else { "ICMP" };
+        packet_log.log(packet_log_row(ip_header, protocol, payload, arrival_time));
+    }
+
+    let packet = PacketContext {
+        protocol: ip_header.protocol(),
+        src_ip: ip_header.src_ip(),
+        src_port: None,
+        dst_ip: ip_header.dst_ip(),
+        dst_port: None,
+        from_client: None,
+        established: false,
+        stream_tracked: false,
+    };
+    state.rules.inspect_packet(&packet, payload);
+    report_rule_alerts(state, arrival_time);
+
+    state
+        .icmp_detector
+        .inspect(ip_header.src_ip(), ip_header.dst_ip(), icmp_header, payload, arrival_time);
+    let icmp_flow = EveFlow::new(proto, ip_header.src_ip(), None, ip_header.dst_ip(), None);
+    for event in state.icmp_detector.take_events() {
+        let message = event.to_string();
+        let details = json!({
+            "signature": message,
+            "type": event.name(),
+            "category": "icmp",
+        });
+        report_event(
+            &mut state.output,
+            &mut state.eve,
+            arrival_time,
+            "alert",
+            Some(&icmp_flow),
+            &message,
+            details,
+        );
+    }
+
+    let quoted = match parse_quoted_packet(icmp_header, payload) {
+        Some(quoted) => quoted,
+        None => return,
+    };
+    let (src_port, dst_port) = match (quoted.src_port, quoted.dst_port) {
+        (Some(src_port), Some(dst_port)) => (src_port, dst_port),
+        _ => return,
+    };
+
+    // 引用されたパケットの送信元から見たキーと、その逆方向のキーで探す
+    let quoted_key = (quoted.src_ip, src_port, quoted.dst_ip, dst_port);
+    let reverse_key = (quoted.dst_ip, dst_port, quoted.src_ip, src_port);
+    state.scan_detector.on_icmp_error(&quoted_key, quoted.protocol, &message);
+
+    match quoted.protocol {
+        IPPROTO_TCP => {
+            for (key, from_client) in [(quoted_key, true), (reverse_key, false)] {
+                if let Some(stream) = state.streams.get_mut(&key) {
+                    if state.print_packets {
+                        state.output.write_line(&format!(
+                            "ICMP error for stream: {}:{} -> {}:{}{}",
+                            key.0,
+                            key.1,
+                            key.2,
+                            key.3,
+                            quoted_seq_suffix(&quoted)
+                        ));
+                    }
+                    report_icmp_error(&mut state.eve, arrival_time, "TCP", &key, from_client, &message);
+                    for consumer in state.consumers.iter_mut() {
+                        consumer.on_icmp_error(&key, stream, from_client, &message);
+                    }
+                    break;
+                }
+            }
+        }
+        IPPROTO_UDP => {
+            for (key, from_client) in [(quoted_key, true), (reverse_key, false)] {
+                if let Some(flow) = state.udp_flows.get_mut(&key) {
+                    if state.print_packets {
+                        state.output.write_line(&format!(
+                            "ICMP error for UDP flow: {}:{} -> {}:{}",
+                            key.0, key.1, key.2, key.3
+                        ));
+                    }
+                    report_icmp_error(&mut state.eve, arrival_time, "UDP", &key, from_client, &message);
+                    for consumer in state.udp_consumers.iter_mut() {
+                        consumer.on_icmp_error(&key, flow, from_client, &message);
+                    }
+                    break;
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+// ICMPエラーを引用元のストリーム(フロー)のflow_idで書き出す
+fn report_icmp_error(
+    eve: &mut Option<EveLog>,
+    now: SystemTime,
+    proto: &'static str,
+    key: &(IpAddr, u16, IpAddr, u16),
+    from_client: bool,
+    message: &IcmpMessage,
+) {
+    if let Some(eve) = eve {
+        eve.write_event(
+            now,
+            "anomaly",
+            Some(&EveFlow::from_key(proto, key)),
+            json!({
+                "type": "icmp_error",
+                "message": message.to_string(),
+                "to_client": from_client,
+            }),
+        );
+    }
+}
+
+fn quoted_seq_suffix(quoted: &QuotedPacket) -> String {
+    match quoted.tcp_seq {
+        Some(seq) => format!(" (quoted seq={})", seq),
+        None => String::new(),
     }
-    Ok(())
 }
 
+// TCPヘッダーとペイロードを処理
 fn process_tcp_data(
-    ip_header: &IpHeader,
-    tcp_header: &crate::tcp_header::TcpHeader,
+    ip_header: &IpPacketHeader,
+    tcp_header: &TcpHeader,
     payload: &[u8],
-    streams: &mut HashMap<TcpStreamKey, TcpStream>,
+    state: &mut ProcessorState,
     arrival_time: SystemTime,
-) -> Result<(), Box<dyn std::error::Error>> {
+    vlan_ids: &[u16],
+) {
     let stream_key = (
-        ip_header.src_ip,
+        ip_header.src_ip(),
         tcp_header.src_port,
-        ip_header.dst_ip,
+        ip_header.dst_ip(),
         tcp_header.dst_port,
     );
     let reverse_key = (
-        ip_header.dst_ip,
+        ip_header.dst_ip(),
         tcp_header.dst_port,
-        ip_header.src_ip,
+        ip_header.src_ip(),
         tcp_header.src_port,
     );
 
+    // SYNはストリームの有無や上限による破棄にかかわらず数える
+    if tcp_header.flags & TCP_SYN != 0 && tcp_header.flags & TCP_ACK == 0 {
+        state.syn_flood.on_syn(&stream_key, arrival_time);
+    }
+
+    // 追跡している接続のないセグメントはフラグからスキャンのプローブを分類し、応答はプローブに結び付ける
+    let new_connection = !state.streams.contains_key(&stream_key) && !state.streams.contains_key(&reverse_key);
+    state
+        .scan_detector
+        .on_tcp_packet(&stream_key, tcp_header.flags, new_connection, arrival_time);
+
     // クライアントからのパケットかどうかを判断
-    let is_from_client = if streams.contains_key(&stream_key) {
+    let is_from_client = if state.streams.contains_key(&stream_key) {
         true
-    } else if streams.contains_key(&reverse_key) {
+    } else if state.streams.contains_key(&reverse_key) {
         false
     } else {
         // 新しいストリームを開始
-        if tcp_header.flags & TCP_SYN != 0 {
-            let mut new_stream = TcpStream::new(tcp_header.seq_num, 0);
-            let options_end = (tcp_header.data_offset as usize * 4).saturating_sub(20);
-            if payload.len() >= options_end {
-                if let Some(mss) = parse_tcp_options(&payload[..options_end]) {
-                    new_stream.set_mss(true, mss);
+        match open_stream(tcp_header, payload, state, arrival_time) {
+            Some((mut new_stream, is_from_client)) => {
+                new_stream.vlan_ids = vlan_ids.to_vec();
+                let key = if is_from_client { stream_key } else { reverse_key };
+                for consumer in state.consumers.iter_mut() {
+                    consumer.on_open(&key, &new_stream);
                 }
+                state.streams.insert(key, new_stream);
+                is_from_client
             }
-            streams.insert(stream_key, new_stream);
+            None => true,
         }
-        true
     };
 
     let stream_key = if is_from_client { stream_key } else { reverse_key };
 
     // ストリームが存在する場合はデータを更新
-    if let Some(stream) = streams.get_mut(&stream_key) {
-        // サーバーからのSYNパケットの場合、MSSを設定
-        if tcp_header.flags & TCP_SYN != 0 && !is_from_client {
-            let options_end = (tcp_header.data_offset as usize * 4).saturating_sub(20);
-            if payload.len() >= options_end {
-                if let Some(mss) = parse_tcp_options(&payload[..options_end]) {
-                    stream.set_mss(false, mss);
+    if let Some(stream) = state.streams.get_mut(&stream_key) {
+        // ストリームの状態を更新
+        // SYNのオプション(MSS、ウィンドウスケールなど)もここで記録される
+        let previous_state = stream.state.clone();
+        let was_established = is_established(stream);
+        stream.update(is_from_client, tcp_header, payload, arrival_time);
+
+        // ハンドシェイクが完了した、または終了した接続は半開きではなくなる (状態が変わったときだけ通知する)
+        if stream.state != previous_state {
+            if is_established(stream) {
+                if !was_established {
+                    state.syn_flood.on_resolved(&stream_key, true);
+                    state.scan_detector.on_established(&stream_key);
                 }
+            } else if tcp_header.flags & (TCP_RST | TCP_FIN) != 0 {
+                state.syn_flood.on_resolved(&stream_key, false);
             }
         }
+        if !payload.is_empty() {
+            state.scan_detector.on_data(&stream_key, IPPROTO_TCP);
+        }
 
-        // ストリームの状態を更新
-        stream.update(
-            is_from_client,
-            tcp_header.seq_num,
-            tcp_header.ack_num,
-            tcp_header.flags,
-            payload,
-            tcp_header.window,
-        );
+        if state.print_packets {
+            state.output.write_line(&format!("Arrival time: {}", arrival_time_to_string(arrival_time)));
+            state.output.write_line(&format!(
+                "Stream: {}:{} -> {}:{}{}",
+                stream_key.0,
+                tcp_header.src_port,
+                stream_key.2,
+                tcp_header.dst_port,
+                if stream.midstream { " (midstream)" } else { "" }
+            ));
+        }
 
-        stream.arrival_time = arrival_time;
+        if let Some(packet_log) = &state.packet_log {
+            let mut row = tcp_packet_log_row(ip_header, tcp_header, payload, arrival_time);
+            row.stream_id = Some(stream_id(&stream_key));
+            row.is_from_client = Some(is_from_client);
+            row.tcp_state = Some(format!("{:?}", stream.state));
+            packet_log.log(row);
+        }
 
-        println!("Arrival time: {}", arrival_time_to_string(arrival_time));
-        println!(
-            "Stream: {}:{} -> {}:{}",
-            stream_key.0, tcp_header.src_port, stream_key.2, tcp_header.dst_port
-        );
+        // 再送データの不一致などのイベントを出力
+        let stream_flow = EveFlow::from_key("TCP", &stream_key);
+        for event in stream.take_events() {
+            let message = event.to_string();
+            let details = json!({
+                "type": event.name(),
+                "message": message,
+            });
+            report_event(
+                &mut state.output,
+                &mut state.eve,
+                arrival_time,
+                "anomaly",
+                Some(&stream_flow),
+                &message,
+                details,
+            );
+        }
+
+        // 新たに連続したデータをコンシューマーに渡す
+        let chunks = stream.take_chunks();
+        deliver_chunks(&mut state.consumers, &stream_key, stream, &chunks);
 
+        // 再構築したデータとパケット単位で検査するルールを照合する
+        state.rules.inspect_stream(&stream_key, stream, &chunks);
+        let packet = tcp_packet_context(ip_header, tcp_header, Some(is_from_client), is_established(stream), true);
+        state.rules.inspect_packet(&packet, payload);
 
         // ストリームが閉じられた場合、ストリームを削除
         if stream.state == crate::tcp_stream::TcpState::Closed {
-            streams.remove(&stream_key);
+            state.streams.close(&stream_key);
+        } else {
+            // 保持しているデータ量を計上し直し、メモリ上限を超えた場合は古いストリームを破棄
+            state.streams.refresh(&stream_key);
         }
+    } else {
+        // 追跡していない接続のセグメントもストリームの列を空にして書き込む
+        if let Some(packet_log) = &state.packet_log {
+            packet_log.log(tcp_packet_log_row(ip_header, tcp_header, payload, arrival_time));
+        }
+
+        // 追跡していない接続のセグメントはパケット単位で検査する
+        let packet = tcp_packet_context(ip_header, tcp_header, None, false, false);
+        state.rules.inspect_packet(&packet, payload);
+    }
+    report_syn_flood_events(state, arrival_time);
+    report_rule_alerts(state, arrival_time);
+}
+
+fn tcp_packet_context(
+    ip_header: &IpPacketHeader,
+    tcp_header: &TcpHeader,
+    from_client: Option<bool>,
+    established: bool,
+    stream_tracked: bool,
+) -> PacketContext {
+    PacketContext {
+        protocol: IPPROTO_TCP,
+        src_ip: ip_header.src_ip(),
+        src_port: Some(tcp_header.src_port),
+        dst_ip: ip_header.dst_ip(),
+        dst_port: Some(tcp_header.dst_port),
+        from_client,
+        established,
+        stream_tracked,
+    }
+}
+
+// 追跡していない接続のセグメントからストリームを作成する
+// SYNからは通常どおり、midstreamモードではSYN-ACKやデータを含むセグメントからも作成する
+// 戻り値のboolはこのセグメントがクライアントから送られたか
+fn open_stream(
+    tcp_header: &TcpHeader,
+    payload: &[u8],
+    state: &ProcessorState,
+    arrival_time: SystemTime,
+) -> Option<(TcpStream, bool)> {
+    let flags = tcp_header.flags;
+    let new_stream = |client_init_seq, server_init_seq| {
+        TcpStream::new(client_init_seq, server_init_seq, arrival_time, state.tcp_policy, state.stream_limits)
+    };
+
+    if flags & TCP_SYN != 0 && flags & TCP_ACK == 0 {
+        return Some((new_stream(tcp_header.seq_num, 0), true));
+    }
+    if !state.midstream || flags & (TCP_RST | TCP_FIN) != 0 || flags & TCP_ACK == 0 {
+        return None;
+    }
+
+    if flags & TCP_SYN != 0 {
+        // SYN-ACKの送信元がサーバー (確認応答番号からクライアントの初期シーケンス番号が分かる)
+        let mut stream = new_stream(tcp_header.ack_num.wrapping_sub(1), tcp_header.seq_num);
+        stream.midstream = true;
+        return Some((stream, false));
     }
 
-    Ok(())
+    if payload.is_empty() {
+        return None;
+    }
+
+    // データを含むセグメントからは確立済みのストリームとして追跡を始める
+    let is_from_client = is_server_port(tcp_header.dst_port, tcp_header.src_port);
+    let (client_next_seq, server_next_seq) = if is_from_client {
+        (tcp_header.seq_num, tcp_header.ack_num)
+    } else {
+        (tcp_header.ack_num, tcp_header.seq_num)
+    };
+    Some((
+        TcpStream::new_midstream(
+            client_next_seq,
+            server_next_seq,
+            arrival_time,
+            state.tcp_policy,
+            state.stream_limits,
+        ),
+        is_from_client,
+    ))
+}
+
+// ポート番号からportがサーバー側かを推定する
+// よく知られたポート、登録済みポート、動的ポートの順にサーバーらしいとみなし、同じ範囲なら小さい方をサーバーとする
+fn is_server_port(port: u16, other_port: u16) -> bool {
+    let rank = |port: u16| match port {
+        0..=1023 => 0,
+        1024..=49151 => 1,
+        _ => 2,
+    };
+    (rank(port), port) <= (rank(other_port), other_port)
+}
+
+// packet_logテーブルの行のうちIPヘッダーとペイロードの部分を作成する
+fn packet_log_row(
+    ip_header: &IpPacketHeader,
+    protocol: &'static str,
+    payload: &[u8],
+    arrival_time: SystemTime,
+) -> PacketLogRow {
+    let mut row = PacketLogRow {
+        arrival_time: Some(arrival_time),
+        protocol,
+        src_ip: ip_header.src_ip().to_string(),
+        dst_ip: ip_header.dst_ip().to_string(),
+        payload_length: payload.len() as u32,
+        payload: payload.to_vec(),
+        ..PacketLogRow::default()
+    };
+    match ip_header {
+        IpPacketHeader::V4(header) => {
+            row.ip_version = 4;
+            row.ip_header_length = Some(header.ihl);
+            row.total_length = Some(header.total_length as u32);
+            row.ttl = Some(header.ttl);
+            row.fragment_offset = Some((header.flags_fragment_offset & 0x1FFF) as u32 * 8);
+        }
+        IpPacketHeader::V6(header) => {
+            row.ip_version = 6;
+            row.total_length = Some(header.payload_length as u32 + 40);
+            row.ttl = Some(header.hop_limit);
+            row.fragment_offset = header.fragment.as_ref().map(|fragment| fragment.offset as u32);
+        }
+    }
+    row
+}
+
+// packet_logテーブルの行のうちTCPヘッダーまでの部分を作成する (ストリームの列は呼び出し側で設定する)
+fn tcp_packet_log_row(
+    ip_header: &IpPacketHeader,
+    tcp_header: &TcpHeader,
+    payload: &[u8],
+    arrival_time: SystemTime,
+) -> PacketLogRow {
+    let mut row = packet_log_row(ip_header, "TCP", payload, arrival_time);
+    row.src_port = Some(tcp_header.src_port);
+    row.dst_port = Some(tcp_header.dst_port);
+    row.tcp_seq_num = Some(tcp_header.seq_num);
+    row.tcp_ack_num = Some(tcp_header.ack_num);
+    row.tcp_window_size = Some(tcp_header.window as u32);
+    row.tcp_flags = Some(tcp_header.flags);
+    row.tcp_data_offset = Some(tcp_header.data_offset);
+    row.application_protocol = application_protocol(tcp_header.src_port, tcp_header.dst_port);
+    row
+}
+
+// ストリーム(フロー)を識別する文字列 (クライアント -> サーバー)
+fn stream_id(key: &(IpAddr, u16, IpAddr, u16)) -> String {
+    format!("{}:{}-{}:{}", key.0, key.1, key.2, key.3)
 }
 
 // SystemTimeを文字列に変換する
@@ -0,0 +1,597 @@
+use crate::capture_clock::{elapsed_between, CaptureClock};
+use crate::checksum::{ChecksumPolicy, ChecksumValidator};
+use crate::config::Config;
+use crate::eve::EveLog;
+use crate::icmp_detector::IcmpDetector;
+use crate::ip_reassembly::{FragmentLimits, FragmentPolicy, FragmentPolicyMap, IpReassembler};
+use crate::output::Output;
+use crate::packet_log::{PacketLogCounters, PacketLogWriter};
+use crate::packet_processor::{
+    notify_closed_streams, process_packet, report_scan_events, report_syn_flood_events, ProcessorState,
+};
+use crate::rule::Rule;
+use crate::rule_engine::RuleEngine;
+use crate::scan_detector::ScanDetector;
+use crate::stream_consumer::{StreamConsumer, UdpConsumer};
+use crate::stream_table::{StreamTable, StreamTableLimits};
+use crate::syn_flood::{SynFloodConfig, SynFloodDetector};
+use crate::tcp_anomaly::{TcpAnomalyDetector, TcpAnomalyKind};
+use crate::tcp_reassembly::{ReassemblyPolicy, StreamLimits};
+use crate::udp_flow::{UdpFlowLimits, UdpFlowTable};
+use pcap::Linktype;
+use serde_json::{json, Map, Value};
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime};
+
+// 通信のないTCPストリームを保持する時間
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// 再構築が完了しないフラグメントを保持する時間
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// この数のパケットごとにタイムアウトしたフラグメントとストリームを削除する
+const CLEANUP_INTERVAL: u64 = 100;
+
+// statsイベントを出力する間隔 (キャプチャ時刻)
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+// 解析パイプラインの設定
+// Pipeline::builder() から各設定を指定し、build() でパイプラインを作成する
+pub struct PipelineBuilder {
+    tcp_policy: ReassemblyPolicy,
+    fragment_policies: FragmentPolicyMap,
+    fragment_timeout: Duration,
+    fragment_limits: FragmentLimits,
+    stream_idle_timeout: Duration,
+    stats_interval: Duration,
+    stream_limits: StreamLimits,
+    stream_table_limits: StreamTableLimits,
+    midstream: bool,
+    udp_flow_limits: UdpFlowLimits,
+    syn_flood: SynFloodConfig,
+    checksum_policy: ChecksumPolicy,
+    interface: String,
+    local_addresses: Vec<IpAddr>,
+    output: Option<Output>,
+    packet_log: Option<PacketLogWriter>,
+    eve: Option<EveLog>,
+    print_packets: bool,
+    rules: Vec<Rule>,
+    consumers: Vec<Box<dyn StreamConsumer>>,
+    udp_consumers: Vec<Box<dyn UdpConsumer>>,
+}
+
+impl Default for PipelineBuilder {
+    fn default() -> Self {
+        PipelineBuilder {
+            tcp_policy: ReassemblyPolicy::Bsd,
+            fragment_policies: FragmentPolicyMap::new(FragmentPolicy::Bsd, Vec::new()),
+            fragment_timeout: FRAGMENT_TIMEOUT,
+            fragment_limits: FragmentLimits::default(),
+            stream_idle_timeout: STREAM_IDLE_TIMEOUT,
+            stats_interval: STATS_INTERVAL,
+            stream_limits: StreamLimits::default(),
+            stream_table_limits: StreamTableLimits::default(),
+            midstream: false,
+            udp_flow_limits: UdpFlowLimits::default(),
+            syn_flood: SynFloodConfig::default(),
+            checksum_policy: ChecksumPolicy::Auto,
+            interface: String::new(),
+            local_addresses: Vec::new(),
+            output: None,
+            packet_log: None,
+            eve: None,
+            print_packets: true,
+            rules: Vec::new(),
+            consumers: Vec::new(),
+            udp_consumers: Vec::new(),
+        }
+    }
+}
+
+impl PipelineBuilder {
+    pub fn new() -> Self {
+        PipelineBuilder::default()
+    }
+
+    // コマンドライン引数(環境変数)の設定を反映する
+    pub fn from_config(config: &Config) -> Self {
+        PipelineBuilder::new()
+            .tcp_policy(config.tcp_policy)
+            .fragment_policies(config.fragment_policies())
+            .fragment_limits(config.fragment_limits())
+            .stream_limits(config.stream_limits())
+            .stream_table_limits(config.stream_table_limits())
+            .midstream(config.midstream)
+            .udp_flow_limits(config.udp_flow_limits())
+            .syn_flood(config.syn_flood_config())
+            .checksum_policy(config.checksum_policy)
+            .stats_interval(Duration::from_secs(config.stats_interval))
+            .print_packets(config.print_packets)
+    }
+
+    pub fn tcp_policy(mut self, policy: ReassemblyPolicy) -> Self {
+        self.tcp_policy = policy;
+        self
+    }
+
+    pub fn fragment_policies(mut self, policies: FragmentPolicyMap) -> Self {
+        self.fragment_policies = policies;
+        self
+    }
+
+    pub fn fragment_timeout(mut self, timeout: Duration) -> Self {
+        self.fragment_timeout = timeout;
+        self
+    }
+
+    pub fn fragment_limits(mut self, limits: FragmentLimits) -> Self {
+        self.fragment_limits = limits;
+        self
+    }
+
+    pub fn stream_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.stream_idle_timeout = timeout;
+        self
+    }
+
+    // statsイベントを出力する間隔 (0の場合は終了時のみ出力する)
+    pub fn stats_interval(mut self, interval: Duration) -> Self {
+        self.stats_interval = interval;
+        self
+    }
+
+    pub fn stream_limits(mut self, limits: StreamLimits) -> Self {
+        self.stream_limits = limits;
+        self
+    }
+
+    pub fn stream_table_limits(mut self, limits: StreamTableLimits) -> Self {
+        self.stream_table_limits = limits;
+        self
+    }
+
+    pub fn midstream(mut self, midstream: bool) -> Self {
+        self.midstream = midstream;
+        self
+    }
+
+    pub fn udp_flow_limits(mut self, limits: UdpFlowLimits) -> Self {
+        self.udp_flow_limits = limits;
+        self
+    }
+
+    // SYNフラッドと半開きの接続の閾値
+    pub fn syn_flood(mut self, config: SynFloodConfig) -> Self {
+        self.syn_flood = config;
+        self
+    }
+
+    pub fn checksum_policy(mut self, policy: ChecksumPolicy) -> Self {
+        self.checksum_policy = policy;
+        self
+    }
+
+    // キャプチャしているインターフェース名 (チェックサムの統計の出力に使う)
+    pub fn interface(mut self, interface: &str) -> Self {
+        self.interface = interface.to_string();
+        self
+    }
+
+    // キャプチャしているインターフェースのアドレス (チェックサムのオフロードはここから送信したパケットのみで判定する)
+    pub fn local_addresses(mut self, addresses: Vec<IpAddr>) -> Self {
+        self.local_addresses = addresses;
+        self
+    }
+
+    // 出力先 (未指定の場合は標準出力)
+    pub fn output(mut self, output: Output) -> Self {
+        self.output = Some(output);
+        self
+    }
+
+    // パケットごとの行をpacket_logテーブルに書き込む
+    pub fn packet_log(mut self, packet_log: PacketLogWriter) -> Self {
+        self.packet_log = Some(packet_log);
+        self
+    }
+
+    // 構造化したイベント(EVE形式のJSON Lines)の出力先
+    pub fn eve(mut self, eve: EveLog) -> Self {
+        self.eve = Some(eve);
+        self
+    }
+
+    // パケットごとの行(Arrival time、Streamなど)を出力するか (アラートと統計は常に出力する)
+    pub fn print_packets(mut self, print_packets: bool) -> Self {
+        self.print_packets = print_packets;
+        self
+    }
+
+    // パケットとストリームを検査するルール
+    pub fn rules(mut self, rules: Vec<Rule>) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    // 再構築したストリームを受け取るコンシューマーを登録する
+    pub fn consumer(mut self, consumer: Box<dyn StreamConsumer>) -> Self {
+        self.consumers.push(consumer);
+        self
+    }
+
+    // UDPのデータグラムを受け取るコンシューマーを登録する
+    pub fn udp_consumer(mut self, consumer: Box<dyn UdpConsumer>) -> Self {
+        self.udp_consumers.push(consumer);
+        self
+    }
+
+    pub fn build(self) -> Pipeline {
+        Pipeline {
+            state: ProcessorState {
+                streams: StreamTable::new(self.stream_table_limits),
+                ip_reassembler: IpReassembler::new(
+                    self.fragment_timeout,
+                    self.fragment_policies,
+                    self.fragment_limits,
+                ),
+                output: self.output.unwrap_or_else(Output::stdout),
+                tcp_policy: self.tcp_policy,
+                stream_limits: self.stream_limits,
+                midstream: self.midstream,
+                consumers: self.consumers,
+                udp_flows: UdpFlowTable::new(self.udp_flow_limits),
+                udp_consumers: self.udp_consumers,
+                icmp_detector: IcmpDetector::new(),
+                syn_flood: SynFloodDetector::new(self.syn_flood),
+                scan_detector: ScanDetector::new(),
+                tcp_anomalies: TcpAnomalyDetector::new(),
+                checksums: ChecksumValidator::new(self.checksum_policy, self.interface, self.local_addresses),
+                packet_log: self.packet_log,
+                eve: self.eve,
+                print_packets: self.print_packets,
+                rules: RuleEngine::new(self.rules),
+            },
+            clock: CaptureClock::new(),
+            packet_count: 0,
+            stream_idle_timeout: self.stream_idle_timeout,
+            stats_interval: self.stats_interval,
+            last_stats: None,
+        }
+    }
+}
+
+// パケットを順に受け取り、IPの再構築からTCPストリームの再構成までを行うパイプライン
+pub struct Pipeline {
+    state: ProcessorState,
+    clock: CaptureClock,
+    packet_count: u64,
+    stream_idle_timeout: Duration,
+    stats_interval: Duration,
+    last_stats: Option<SystemTime>, // 最後にstatsイベントを出力した時刻 (最初のパケットから数える)
+}
+
+impl Pipeline {
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder::new()
+    }
+
+    // 1パケットを処理する (時刻はすべてパケットのタイムスタンプを基準にする)
+    pub fn process(&mut self, packet: &pcap::Packet, linktype: Linktype) {
+        let now = self.clock.advance(packet.header);
+
+        process_packet(packet, &mut self.state, now, linktype);
+
+        // 一定のパケット数ごとにIP再構築のキャッシュと古いストリームを削除
+        self.packet_count += 1;
+        if self.packet_count.is_multiple_of(CLEANUP_INTERVAL) {
+            self.state.ip_reassembler.cleanup(now);
+            self.state.streams.remove_expired(now, self.stream_idle_timeout);
+            self.state.udp_flows.remove_expired(now);
+            self.state.icmp_detector.cleanup(now);
+            self.state.syn_flood.cleanup(now);
+            report_syn_flood_events(&mut self.state, now);
+            self.state.scan_detector.cleanup(now);
+            report_scan_events(&mut self.state, now);
+            self.state.tcp_anomalies.cleanup(now);
+            notify_closed_streams(&mut self.state);
+            // ソケットの受信側が遅れずに読めるようイベントを書き出す
+            if let Some(eve) = &mut self.state.eve {
+                eve.flush();
+            }
+        }
+        self.report_periodic_stats(now);
+    }
+
+    // キャプチャの終了時に統計を出力し、残っているストリームの終了をコンシューマーに通知する
+    pub fn finish(&mut self) {
+        // 継続中のSYNフラッドの集計を出力する
+        self.state.syn_flood.finish(self.clock.now());
+        report_syn_flood_events(&mut self.state, self.clock.now());
+        // 継続中のスキャンの集計を出力する
+        self.state.scan_detector.finish();
+        report_scan_events(&mut self.state, self.clock.now());
+
+        let (lines, mut stats) = self.memory_stats();
+        for line in &lines {
+            self.state.output.write_line(line);
+        }
+
+        self.state.streams.close_all();
+        self.state.udp_flows.close_all();
+        notify_closed_streams(&mut self.state);
+
+        // キューに残っている行を書き込んでから件数を出力する
+        if let Some(packet_log) = &mut self.state.packet_log {
+            packet_log.finish();
+            let counters = &packet_log.counters;
+            self.state.output.write_line(&format!(
+                "Stats: packet_log written={} dropped={} failed={} reconnects={}",
+                counters.written.load(Ordering::Relaxed),
+                counters.dropped.load(Ordering::Relaxed),
+                counters.failed.load(Ordering::Relaxed),
+                counters.reconnects.load(Ordering::Relaxed),
+            ));
+            stats.insert("packet_log".to_string(), packet_log_stats(counters));
+        }
+
+        if let Some(eve) = &mut self.state.eve {
+            stats.insert("packets".to_string(), self.packet_count.into());
+            eve.write_event(self.clock.now(), "stats", None, Value::Object(stats));
+            eve.flush();
+        }
+        self.state.output.flush();
+    }
+
+    // キャプチャ時刻でstats_intervalごとにstatsイベントを出力する (ライブキャプチャでも終了を待たずに統計を確認できる)
+    fn report_periodic_stats(&mut self, now: SystemTime) {
+        if self.stats_interval.is_zero() || self.state.eve.is_none() {
+            return;
+        }
+        let last_stats = *self.last_stats.get_or_insert(now);
+        if elapsed_between(last_stats, now) < self.stats_interval {
+            return;
+        }
+        self.last_stats = Some(now);
+
+        let (_, mut stats) = self.memory_stats();
+        if let Some(packet_log) = &self.state.packet_log {
+            stats.insert("packet_log".to_string(), packet_log_stats(&packet_log.counters));
+        }
+        stats.insert("packets".to_string(), self.packet_count.into());
+        if let Some(eve) = &mut self.state.eve {
+            eve.write_event(now, "stats", None, Value::Object(stats));
+            eve.flush();
+        }
+    }
+
+    pub fn packet_count(&self) -> u64 {
+        self.packet_count
+    }
+
+    pub fn streams(&self) -> &StreamTable {
+        &self.state.streams
+    }
+
+    pub fn ip_reassembler(&self) -> &IpReassembler {
+        &self.state.ip_reassembler
+    }
+
+    pub fn udp_flows(&self) -> &UdpFlowTable {
+        &self.state.udp_flows
+    }
+
+    // ストリームとフラグメントの使用量と破棄した件数、ルールのアラート数、不正なチェックサムの件数
+    // Stats:の行と、同じ内容のstatsイベント用の値を返す
+    fn memory_stats(&self) -> (Vec<String>, Map<String, Value>) {
+        let mut lines = Vec::new();
+        let mut stats = Map::new();
+        let state = &self.state;
+        let streams = &state.streams;
+        let stream_counters = &streams.counters;
+        lines.push(format!(
+            "Stats: streams={} stream_memory={} evicted(max_streams={} memcap={} expired={}) ooo_dropped_segments={} truncated_bytes={}",
+            streams.len(),
+            streams.memory_usage(),
+            stream_counters.max_streams,
+            stream_counters.memcap,
+            stream_counters.expired,
+            stream_counters.ooo_dropped_segments,
+            stream_counters.truncated_bytes,
+        ));
+        stats.insert(
+            "streams".to_string(),
+            json!({
+                "active": streams.len(),
+                "memory": streams.memory_usage(),
+                "evicted_max_streams": stream_counters.max_streams,
+                "evicted_memcap": stream_counters.memcap,
+                "expired": stream_counters.expired,
+                "ooo_dropped_segments": stream_counters.ooo_dropped_segments,
+                "truncated_bytes": stream_counters.truncated_bytes,
+            }),
+        );
+
+        let reassembler = &state.ip_reassembler;
+        let fragment_counters = &reassembler.counters;
+        lines.push(format!(
+            "Stats: frag_buffers={} frag_memory={} evicted(max_buffers={} memcap={} max_fragments={} timeout={})",
+            reassembler.len(),
+            reassembler.memory_usage(),
+            fragment_counters.max_buffers,
+            fragment_counters.memcap,
+            fragment_counters.max_fragments,
+            fragment_counters.timeout,
+        ));
+        stats.insert(
+            "fragments".to_string(),
+            json!({
+                "buffers": reassembler.len(),
+                "memory": reassembler.memory_usage(),
+                "evicted_max_buffers": fragment_counters.max_buffers,
+                "evicted_memcap": fragment_counters.memcap,
+                "evicted_max_fragments": fragment_counters.max_fragments,
+                "timeout": fragment_counters.timeout,
+            }),
+        );
+
+        let udp_flows = &state.udp_flows;
+        lines.push(format!(
+            "Stats: udp_flows={} evicted(max_flows={} expired={})",
+            udp_flows.len(),
+            udp_flows.counters.max_flows,
+            udp_flows.counters.expired,
+        ));
+        stats.insert(
+            "udp_flows".to_string(),
+            json!({
+                "active": udp_flows.len(),
+                "evicted_max_flows": udp_flows.counters.max_flows,
+                "expired": udp_flows.counters.expired,
+            }),
+        );
+
+        let syn_flood = &state.syn_flood;
+        lines.push(format!(
+            "Stats: syn_flood syns={} half_open={} completed={} expired={} untracked={} attacks={}",
+            syn_flood.counters.syns,
+            syn_flood.half_open(),
+            syn_flood.counters.completed,
+            syn_flood.counters.expired,
+            syn_flood.counters.untracked,
+            syn_flood.counters.attacks,
+        ));
+        stats.insert(
+            "syn_flood".to_string(),
+            json!({
+                "syns": syn_flood.counters.syns,
+                "half_open": syn_flood.half_open(),
+                "completed": syn_flood.counters.completed,
+                "expired": syn_flood.counters.expired,
+                "untracked": syn_flood.counters.untracked,
+                "attacks": syn_flood.counters.attacks,
+            }),
+        );
+
+        let scans = &state.scan_detector.counters;
+        lines.push(format!(
+            "Stats: scan detected={} untracked={}",
+            scans.scans, scans.untracked,
+        ));
+        stats.insert(
+            "scan".to_string(),
+            json!({
+                "detected": scans.scans,
+                "untracked": scans.untracked,
+            }),
+        );
+
+        // 種類ごとの検知数 (括弧内はレート制限により通知しなかった数)
+        let tcp_anomalies = &state.tcp_anomalies;
+        let mut line = String::from("Stats: tcp_anomaly");
+        let mut anomaly_stats = Map::new();
+        for kind in TcpAnomalyKind::ALL {
+            let count = tcp_anomalies.count(kind);
+            line.push_str(&format!(" {}={}({})", kind.name(), count.detected, count.suppressed));
+            anomaly_stats.insert(
+                kind.name().to_string(),
+                json!({
+                    "detected": count.detected,
+                    "suppressed": count.suppressed,
+                }),
+            );
+        }
+        lines.push(line);
+        stats.insert("tcp_anomaly".to_string(), Value::Object(anomaly_stats));
+
+        let rules = &state.rules;
+        lines.push(format!(
+            "Stats: rules loaded={} alerts={} passed={}",
+            rules.len(),
+            rules.counters.alerts,
+            rules.counters.passed,
+        ));
+        stats.insert(
+            "rules".to_string(),
+            json!({
+                "loaded": rules.len(),
+                "alerts": rules.counters.alerts,
+                "passed": rules.counters.passed,
+            }),
+        );
+
+        let checksums = &state.checksums;
+        lines.push(format!(
+            "Stats: checksum interface={} checked={} bad(ipv4={} tcp={} udp={}) offload_detected={}",
+            checksums.interface(),
+            checksums.counters.checked,
+            checksums.counters.ipv4,
+            checksums.counters.tcp,
+            checksums.counters.udp,
+            checksums.offload_detected(),
+        ));
+        stats.insert(
+            "checksum".to_string(),
+            json!({
+                "interface": checksums.interface(),
+                "checked": checksums.counters.checked,
+                "bad_ipv4": checksums.counters.ipv4,
+                "bad_tcp": checksums.counters.tcp,
+                "bad_udp": checksums.counters.udp,
+                "offload_detected": checksums.offload_detected(),
+            }),
+        );
+
+        (lines, stats)
+    }
+}
+
+// packet_logへの書き込みの件数 (statsイベント用)
+fn packet_log_stats(counters: &PacketLogCounters) -> Value {
+    json!({
+        "written": counters.written.load(Ordering::Relaxed),
+        "dropped": counters.dropped.load(Ordering::Relaxed),
+        "failed": counters.failed.load(Ordering::Relaxed),
+        "reconnects": counters.reconnects.load(Ordering::Relaxed),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eve::EveTarget;
+    use std::fs;
+
+    fn at(seconds: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn emits_stats_on_capture_time_interval() {
+        let path = std::env::temp_dir().join(format!("nids-stats-test-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let eve = EveLog::open(&[EveTarget::File(path.clone())]).unwrap();
+        let mut pipeline = Pipeline::builder()
+            .eve(eve)
+            .output(Output::open(&[]).unwrap())
+            .stats_interval(Duration::from_secs(10))
+            .build();
+
+        // 最初の時刻から数えて間隔ごとに出力し、終了時にも出力する
+        for seconds in [0, 5, 10, 19, 25] {
+            pipeline.report_periodic_stats(at(seconds));
+        }
+        pipeline.finish();
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let stats: Vec<Value> = content
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .filter(|event| event["event_type"] == "stats")
+            .collect();
+        assert_eq!(stats.len(), 3);
+        assert!(stats.iter().all(|event| event["stats"]["streams"]["active"] == 0));
+    }
+}
@@ -0,0 +1,151 @@
+use crate::rule::Rule;
+use aho_corasick::automaton::{Automaton, StateID};
+use aho_corasick::dfa::DFA;
+use aho_corasick::{Anchored, MatchKind};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+// 全ルールのcontentから作ったAho-Corasickによる候補の絞り込み
+// ルールごとに最も長い(否定でない)contentを代表のパターンとし、データ中に現れたルールのみを照合の候補にする
+// 大文字小文字を区別しないパターンも含めて一括で探すため、大文字小文字を区別せずに探し、照合で確認する
+pub struct Prefilter {
+    automaton: Option<DFA>,
+    pattern_rules: Vec<Vec<usize>>, // パターン番号ごとのルール
+    has_pattern: Vec<bool>,         // ルールごとに代表のパターンがあるか (ない場合は常に候補)
+}
+
+// 探索の途中の状態 (セグメントをまたぐパターンを見つけるため、ストリームの方向ごとに保持する)
+#[derive(Debug, Clone, Copy)]
+pub struct PrefilterState(Option<StateID>);
+
+impl Prefilter {
+    pub fn new(rules: &[Arc<Rule>]) -> Self {
+        let mut patterns: Vec<Vec<u8>> = Vec::new();
+        let mut pattern_rules: Vec<Vec<usize>> = Vec::new();
+        let mut pattern_ids = HashMap::new();
+        let mut has_pattern = vec![false; rules.len()];
+
+        for (index, rule) in rules.iter().enumerate() {
+            let pattern = rule
+                .contents
+                .iter()
+                .filter(|content| !content.negated)
+                .max_by_key(|content| content.pattern.len());
+            if let Some(content) = pattern {
+                // 大文字小文字だけが異なるパターンは1つにまとめる
+                let pattern = content.pattern.to_ascii_lowercase();
+                let id = *pattern_ids.entry(pattern.clone()).or_insert_with(|| {
+                    patterns.push(pattern);
+                    pattern_rules.push(Vec::new());
+                    patterns.len() - 1
+                });
+                pattern_rules[id].push(index);
+                has_pattern[index] = true;
+            }
+        }
+
+        let automaton = if patterns.is_empty() {
+            None
+        } else {
+            match DFA::builder()
+                .match_kind(MatchKind::Standard)
+                .ascii_case_insensitive(true)
+                .build(&patterns)
+            {
+                Ok(automaton) => Some(automaton),
+                Err(e) => {
+                    // 作成できない場合は絞り込まず、すべてのルールを照合する
+                    eprintln!("ルールの絞り込みを作成できませんでした: {}", e);
+                    has_pattern = vec![false; rules.len()];
+                    None
+                }
+            }
+        };
+
+        Prefilter {
+            automaton,
+            pattern_rules,
+            has_pattern,
+        }
+    }
+
+    pub fn start(&self) -> PrefilterState {
+        PrefilterState(
+            self.automaton
+                .as_ref()
+                .and_then(|automaton| automaton.start_state(Anchored::No).ok()),
+        )
+    }
+
+    // dataを続きから探し、見つかったパターンを代表とするルールをcandidatesに記録する
+    pub fn scan(&self, state: &mut PrefilterState, data: &[u8], candidates: &mut HashSet<usize>) {
+        let (Some(automaton), Some(mut sid)) = (&self.automaton, state.0) else {
+            return;
+        };
+        for byte in data {
+            sid = automaton.next_state(Anchored::No, sid, *byte);
+            if automaton.is_match(sid) {
+                for i in 0..automaton.match_len(sid) {
+                    candidates.extend(&self.pattern_rules[automaton.match_pattern(sid, i).as_usize()]);
+                }
+            }
+        }
+        state.0 = Some(sid);
+    }
+
+    // 代表のパターンが見つかったか、代表のパターンを持たないため常に照合するルールか
+    pub fn is_candidate(&self, index: usize, candidates: &HashSet<usize>) -> bool {
+        !self.has_pattern[index] || candidates.contains(&index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::{parse_rule, RuleVars};
+
+    fn prefilter(options: &[&str]) -> Prefilter {
+        let rules: Vec<Arc<Rule>> = options
+            .iter()
+            .enumerate()
+            .map(|(i, options)| {
+                let line = format!("alert tcp any any -> any any ({} sid:{};)", options, i + 1);
+                Arc::new(parse_rule(&line, &RuleVars::new("any")).unwrap())
+            })
+            .collect();
+        Prefilter::new(&rules)
+    }
+
+    fn scan(prefilter: &Prefilter, chunks: &[&[u8]]) -> HashSet<usize> {
+        let mut state = prefilter.start();
+        let mut candidates = HashSet::new();
+        for chunk in chunks {
+            prefilter.scan(&mut state, chunk, &mut candidates);
+        }
+        candidates
+    }
+
+    #[test]
+    fn finds_patterns_split_across_chunks() {
+        let prefilter = prefilter(&["content:\"attack\";", "content:\"evil\";"]);
+        assert_eq!(scan(&prefilter, &[b"xxat", b"t", b"ackyy"]), HashSet::from([0]));
+        assert_eq!(scan(&prefilter, &[b"ev", b"il at", b"tack"]), HashSet::from([0, 1]));
+        assert!(scan(&prefilter, &[b"atta", b"x", b"ck"]).is_empty());
+    }
+
+    #[test]
+    fn uses_longest_positive_content_ignoring_case() {
+        let prefilter = prefilter(&["content:\"ab\"; content:\"LONGER\"; nocase; content:!\"NEGATEDPATTERN\";"]);
+        assert!(scan(&prefilter, &[b"ab"]).is_empty());
+        assert_eq!(scan(&prefilter, &[b"lon", b"ger"]), HashSet::from([0]));
+    }
+
+    #[test]
+    fn rules_without_positive_content_are_always_candidates() {
+        let prefilter = prefilter(&["content:\"abc\";", "content:!\"abc\";", "msg:\"no content\";"]);
+        let candidates = scan(&prefilter, &[b"xyz"]);
+        assert!(!prefilter.is_candidate(0, &candidates));
+        assert!(prefilter.is_candidate(1, &candidates));
+        assert!(prefilter.is_candidate(2, &candidates));
+    }
+}
@@ -0,0 +1,684 @@
+use crate::ip_network::IpNetwork;
+use crate::packet_processor::{IPPROTO_ICMP, IPPROTO_ICMPV6, IPPROTO_TCP, IPPROTO_UDP};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+// 変数の展開が循環している場合に止める深さ
+const MAX_VAR_DEPTH: usize = 8;
+
+// ルールが一致したときの動作 (パッシブなIDSのためdropとrejectもアラートとして出力する)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleAction {
+    Alert,
+    Drop,
+    Reject,
+    Pass, // 一致したパケット(ストリーム)では他のルールのアラートを出さない
+}
+
+impl RuleAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RuleAction::Alert => "alert",
+            RuleAction::Drop => "drop",
+            RuleAction::Reject => "reject",
+            RuleAction::Pass => "pass",
+        }
+    }
+}
+
+impl FromStr for RuleAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "alert" => Ok(RuleAction::Alert),
+            "drop" => Ok(RuleAction::Drop),
+            "reject" => Ok(RuleAction::Reject),
+            "pass" => Ok(RuleAction::Pass),
+            s => Err(format!("不明なアクションです: {} (alert, drop, reject, pass)", s)),
+        }
+    }
+}
+
+// ルールの対象とするプロトコル
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleProtocol {
+    Ip, // すべてのプロトコル
+    Tcp,
+    Udp,
+    Icmp, // ICMPとICMPv6
+}
+
+impl RuleProtocol {
+    pub fn matches(&self, protocol: u8) -> bool {
+        match self {
+            RuleProtocol::Ip => true,
+            RuleProtocol::Tcp => protocol == IPPROTO_TCP,
+            RuleProtocol::Udp => protocol == IPPROTO_UDP,
+            RuleProtocol::Icmp => protocol == IPPROTO_ICMP || protocol == IPPROTO_ICMPV6,
+        }
+    }
+}
+
+impl FromStr for RuleProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ip" => Ok(RuleProtocol::Ip),
+            "tcp" => Ok(RuleProtocol::Tcp),
+            "udp" => Ok(RuleProtocol::Udp),
+            "icmp" => Ok(RuleProtocol::Icmp),
+            s => Err(format!("不明なプロトコルです: {} (ip, tcp, udp, icmp)", s)),
+        }
+    }
+}
+
+// アドレスの指定 (any, CIDR, [リスト], !否定)
+#[derive(Debug, Clone, PartialEq)]
+pub enum AddressSpec {
+    Any,
+    Network(IpNetwork),
+    List(Vec<AddressSpec>),
+    Not(Box<AddressSpec>),
+}
+
+impl AddressSpec {
+    pub fn matches(&self, ip: IpAddr) -> bool {
+        match self {
+            AddressSpec::Any => true,
+            AddressSpec::Network(network) => network.contains(ip),
+            AddressSpec::List(items) => list_matches(items, |item| item.matches(ip), |item| {
+                matches!(item, AddressSpec::Not(_))
+            }),
+            AddressSpec::Not(inner) => !inner.matches(ip),
+        }
+    }
+}
+
+// ポートの指定 (any, 80, 1024:, :1023, 1:1024, [リスト], !否定)
+#[derive(Debug, Clone, PartialEq)]
+pub enum PortSpec {
+    Any,
+    Range(u16, u16),
+    List(Vec<PortSpec>),
+    Not(Box<PortSpec>),
+}
+
+impl PortSpec {
+    // ポートのないプロトコル(ICMPなど)はanyのみ一致する
+    pub fn matches(&self, port: Option<u16>) -> bool {
+        let port = match (self, port) {
+            (PortSpec::Any, _) => return true,
+            (_, None) => return false,
+            (_, Some(port)) => port,
+        };
+        match self {
+            PortSpec::Any => true,
+            PortSpec::Range(low, high) => (*low..=*high).contains(&port),
+            PortSpec::List(items) => list_matches(items, |item| item.matches(Some(port)), |item| {
+                matches!(item, PortSpec::Not(_))
+            }),
+            PortSpec::Not(inner) => !inner.matches(Some(port)),
+        }
+    }
+}
+
+// リストは否定でない要素のいずれかに一致し(否定でない要素がなければ無条件)、否定の要素のすべてを満たす場合に一致する
+fn list_matches<T>(items: &[T], matches: impl Fn(&T) -> bool, is_negated: impl Fn(&T) -> bool) -> bool {
+    let mut positives = items.iter().filter(|item| !is_negated(item)).peekable();
+    let positive = positives.peek().is_none() || positives.any(&matches);
+    positive && items.iter().filter(|item| is_negated(item)).all(&matches)
+}
+
+// contentの検査内容
+// offset/depthはバッファの先頭から、distance/withinは直前のcontentが一致した末尾からの範囲を指定する
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ContentMatch {
+    pub pattern: Vec<u8>,
+    pub negated: bool, // content:!"..." (含まれない場合に一致する)
+    pub nocase: bool,
+    pub offset: Option<usize>,
+    pub depth: Option<usize>,
+    pub distance: Option<i64>,
+    pub within: Option<usize>,
+}
+
+impl ContentMatch {
+    pub fn is_relative(&self) -> bool {
+        self.distance.is_some() || self.within.is_some()
+    }
+}
+
+// ストリームとパケットのどちらを検査するか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamMatching {
+    #[default]
+    Default, // TCPは再構築したストリーム(追跡していない接続はパケット)を検査する
+    OnlyStream,
+    NoStream,
+}
+
+// flowオプション
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FlowOptions {
+    pub established: Option<bool>, // established / not_established
+    pub to_server: Option<bool>,   // to_server(from_client) / to_client(from_server)
+    pub stream: StreamMatching,
+}
+
+// 1つのルール
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub action: RuleAction,
+    pub protocol: RuleProtocol,
+    pub src: AddressSpec,
+    pub src_ports: PortSpec,
+    pub bidirectional: bool, // <> の場合は逆方向のパケットにも一致する
+    pub dst: AddressSpec,
+    pub dst_ports: PortSpec,
+    pub msg: String,
+    pub gid: u32,
+    pub sid: u32,
+    pub rev: u32,
+    pub classtype: Option<String>,
+    pub priority: Option<u8>,
+    pub flow: FlowOptions,
+    pub contents: Vec<ContentMatch>,
+}
+
+impl Rule {
+    // 送信元と宛先がヘッダーの指定に一致するか
+    pub fn matches_endpoints(&self, src_ip: IpAddr, src_port: Option<u16>, dst_ip: IpAddr, dst_port: Option<u16>) -> bool {
+        let forward = |src_ip, src_port, dst_ip, dst_port| {
+            self.src.matches(src_ip)
+                && self.src_ports.matches(src_port)
+                && self.dst.matches(dst_ip)
+                && self.dst_ports.matches(dst_port)
+        };
+        forward(src_ip, src_port, dst_ip, dst_port)
+            || (self.bidirectional && forward(dst_ip, dst_port, src_ip, src_port))
+    }
+}
+
+// ルール中の$HOME_NETなどの変数
+#[derive(Debug, Clone, Default)]
+pub struct RuleVars {
+    vars: HashMap<String, String>,
+}
+
+impl RuleVars {
+    // HOME_NETを指定し、EXTERNAL_NETはそれ以外とする (HOME_NETがanyの場合はany)
+    pub fn new(home_net: &str) -> Self {
+        let mut vars = RuleVars::default();
+        let home_net = home_net.trim();
+        vars.set("HOME_NET", home_net);
+        vars.set("EXTERNAL_NET", if home_net == "any" { "any" } else { "!$HOME_NET" });
+        vars
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.vars.insert(name.to_string(), value.to_string());
+    }
+
+    fn get(&self, name: &str) -> Result<&str, String> {
+        self.vars
+            .get(name)
+            .map(String::as_str)
+            .ok_or_else(|| format!("未定義の変数です: ${}", name))
+    }
+}
+
+// ルールファイルから読み込んだルール
+// 読み込めなかったルールはerrorsに記録し、他のルールの読み込みは続ける
+#[derive(Debug, Default)]
+pub struct RuleSet {
+    pub rules: Vec<Rule>,
+    pub errors: Vec<String>,
+    ids: HashSet<(u32, u32)>, // 読み込み済みの(gid, sid)
+}
+
+impl RuleSet {
+    pub fn new() -> Self {
+        RuleSet::default()
+    }
+
+    // ファイルのルールを追加し、読み込んだ件数を返す
+    // 空行と#で始まる行は読み飛ばし、行末の\で次の行に続ける
+    pub fn load(&mut self, path: &Path, vars: &RuleVars) -> io::Result<usize> {
+        let text = fs::read_to_string(path)?;
+        let mut loaded = 0;
+        let mut line = String::new();
+        let mut first_line = 0;
+        for (number, raw) in text.lines().enumerate() {
+            if line.is_empty() {
+                first_line = number + 1;
+            }
+            let raw = raw.trim();
+            if let Some(continued) = raw.strip_suffix('\\') {
+                line.push_str(continued);
+                continue;
+            }
+            line.push_str(raw);
+            let rule_line = std::mem::take(&mut line);
+            if rule_line.is_empty() || rule_line.starts_with('#') {
+                continue;
+            }
+            match self.add(&rule_line, vars) {
+                Ok(()) => loaded += 1,
+                Err(e) => self.errors.push(format!("{}:{}: {}", path.display(), first_line, e)),
+            }
+        }
+        Ok(loaded)
+    }
+
+    // 1行のルールを追加する
+    pub fn add(&mut self, line: &str, vars: &RuleVars) -> Result<(), String> {
+        let rule = parse_rule(line, vars)?;
+        if !self.ids.insert((rule.gid, rule.sid)) {
+            return Err(format!("sidが重複しています: {}", rule.sid));
+        }
+        self.rules.push(rule);
+        Ok(())
+    }
+}
+
+// ルールを解析する
+// 形式: <action> <proto> <src> <src_port> <-> | <>> <dst> <dst_port> (<option>; ...)
+pub fn parse_rule(line: &str, vars: &RuleVars) -> Result<Rule, String> {
+    let line = line.trim();
+    let (header, options) = match (line.find('('), line.rfind(')')) {
+        (Some(open), Some(close)) if open < close && line[close + 1..].trim().is_empty() => {
+            (&line[..open], &line[open + 1..close])
+        }
+        _ => return Err("オプションを ( ) で囲んでください".to_string()),
+    };
+
+    let fields = split_header(header);
+    let [action, protocol, src, src_ports, direction, dst, dst_ports] = fields.as_slice() else {
+        return Err(format!("ヘッダーの項目数が不正です: {}", header.trim()));
+    };
+    let bidirectional = match direction.as_str() {
+        "->" => false,
+        "<>" => true,
+        direction => return Err(format!("不明な方向です: {} (->, <>)", direction)),
+    };
+
+    let mut rule = Rule {
+        action: action.parse()?,
+        protocol: protocol.parse()?,
+        src: parse_address(src, vars, 0)?,
+        src_ports: parse_ports(src_ports, vars, 0)?,
+        bidirectional,
+        dst: parse_address(dst, vars, 0)?,
+        dst_ports: parse_ports(dst_ports, vars, 0)?,
+        msg: String::new(),
+        gid: 1,
+        sid: 0,
+        rev: 1,
+        classtype: None,
+        priority: None,
+        flow: FlowOptions::default(),
+        contents: Vec::new(),
+    };
+
+    let mut sid = None;
+    for option in split_options(options) {
+        let (name, value) = match option.split_once(':') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (option.trim(), None),
+        };
+        let value = || value.ok_or_else(|| format!("{} には値を指定してください", name));
+        match name {
+            "msg" => rule.msg = unquote(value()?)?,
+            "sid" => sid = Some(parse_number(name, value()?)?),
+            "rev" => rule.rev = parse_number(name, value()?)?,
+            "gid" => rule.gid = parse_number(name, value()?)?,
+            "classtype" => rule.classtype = Some(value()?.to_string()),
+            "priority" => rule.priority = Some(parse_number(name, value()?)?),
+            "flow" => rule.flow = parse_flow(value()?)?,
+            "content" => rule.contents.push(parse_content(value()?)?),
+            "nocase" => last_content(&mut rule, name)?.nocase = true,
+            "offset" => last_content(&mut rule, name)?.offset = Some(parse_number(name, value()?)?),
+            "depth" => last_content(&mut rule, name)?.depth = Some(parse_number(name, value()?)?),
+            "distance" => last_content(&mut rule, name)?.distance = Some(parse_number(name, value()?)?),
+            "within" => last_content(&mut rule, name)?.within = Some(parse_number(name, value()?)?),
+            // 検査に影響しない情報は読み飛ばす
+            "reference" | "metadata" => (),
+            name => return Err(format!("未対応のオプションです: {}", name)),
+        }
+    }
+    rule.sid = sid.ok_or("sidを指定してください")?;
+
+    for content in &rule.contents {
+        if content.is_relative() && (content.offset.is_some() || content.depth.is_some()) {
+            return Err("offset/depthとdistance/withinは同じcontentに指定できません".to_string());
+        }
+        if content.within.is_some_and(|within| within < content.pattern.len())
+            || content.depth.is_some_and(|depth| depth < content.pattern.len())
+        {
+            return Err("depth/withinはcontentの長さ以上にしてください".to_string());
+        }
+    }
+    Ok(rule)
+}
+
+// ヘッダーを空白で分割する ([ ] の中の空白では分割しない)
+fn split_header(header: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut depth = 0;
+    for c in header.chars() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            c if c.is_whitespace() && depth <= 0 => {
+                if !field.is_empty() {
+                    fields.push(std::mem::take(&mut field));
+                }
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            _ => (),
+        }
+        field.push(c);
+    }
+    if !field.is_empty() {
+        fields.push(field);
+    }
+    fields
+}
+
+// [ ] の中をカンマで分割する (入れ子のリストの中では分割しない)
+fn split_list(list: &str) -> Result<Vec<&str>, String> {
+    let inner = list
+        .strip_prefix('[')
+        .and_then(|list| list.strip_suffix(']'))
+        .ok_or_else(|| format!("リストが閉じられていません: {}", list))?;
+    let mut items = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(&inner[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    items.push(&inner[start..]);
+    Ok(items)
+}
+
+fn parse_address(s: &str, vars: &RuleVars, depth: usize) -> Result<AddressSpec, String> {
+    if depth > MAX_VAR_DEPTH {
+        return Err(format!("変数の展開が深すぎます: {}", s));
+    }
+    let s = s.trim();
+    if let Some(inner) = s.strip_prefix('!') {
+        return Ok(AddressSpec::Not(Box::new(parse_address(inner, vars, depth)?)));
+    }
+    if s.starts_with('[') {
+        let items = split_list(s)?
+            .into_iter()
+            .map(|item| parse_address(item, vars, depth))
+            .collect::<Result<_, _>>()?;
+        return Ok(AddressSpec::List(items));
+    }
+    if let Some(name) = s.strip_prefix('$') {
+        return parse_address(vars.get(name)?, vars, depth + 1);
+    }
+    match s {
+        "any" => Ok(AddressSpec::Any),
+        s => Ok(AddressSpec::Network(s.parse()?)),
+    }
+}
+
+fn parse_ports(s: &str, vars: &RuleVars, depth: usize) -> Result<PortSpec, String> {
+    if depth > MAX_VAR_DEPTH {
+        return Err(format!("変数の展開が深すぎます: {}", s));
+    }
+    let s = s.trim();
+    if let Some(inner) = s.strip_prefix('!') {
+        return Ok(PortSpec::Not(Box::new(parse_ports(inner, vars, depth)?)));
+    }
+    if s.starts_with('[') {
+        let items = split_list(s)?
+            .into_iter()
+            .map(|item| parse_ports(item, vars, depth))
+            .collect::<Result<_, _>>()?;
+        return Ok(PortSpec::List(items));
+    }
+    if let Some(name) = s.strip_prefix('$') {
+        return parse_ports(vars.get(name)?, vars, depth + 1);
+    }
+    if s == "any" {
+        return Ok(PortSpec::Any);
+    }
+
+    let port = |s: &str, default: u16| match s.trim() {
+        "" => Ok(default),
+        s => s.parse::<u16>().map_err(|_| format!("不正なポート番号です: {}", s)),
+    };
+    let (low, high) = match s.split_once(':') {
+        Some((low, high)) => (port(low, 0)?, port(high, u16::MAX)?),
+        None => {
+            let port = port(s, 0)?;
+            (port, port)
+        }
+    };
+    if low > high {
+        return Err(format!("ポートの範囲が不正です: {}", s));
+    }
+    Ok(PortSpec::Range(low, high))
+}
+
+// オプションを ; で分割する (引用符の中と \; では分割しない)
+fn split_options(options: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in options.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                result.push(&options[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    result.push(&options[start..]);
+    result.into_iter().filter(|option| !option.trim().is_empty()).collect()
+}
+
+// 引用符を外し、\" \\ \; \: のエスケープを戻す
+fn unquote(value: &str) -> Result<String, String> {
+    let inner = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .filter(|_| value.len() >= 2)
+        .ok_or_else(|| format!("値を引用符で囲んでください: {}", value))?;
+    let mut result = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.push(chars.next().ok_or("末尾の \\ が不正です")?),
+            c => result.push(c),
+        }
+    }
+    Ok(result)
+}
+
+// contentの値を解析する ("..."の中の|41 42|は16進数のバイト列)
+fn parse_content(value: &str) -> Result<ContentMatch, String> {
+    let (negated, value) = match value.strip_prefix('!') {
+        Some(value) => (true, value.trim()),
+        None => (false, value),
+    };
+    let text = unquote(value)?;
+
+    let mut pattern = Vec::new();
+    for (i, part) in text.split('|').enumerate() {
+        if i % 2 == 0 {
+            pattern.extend_from_slice(part.as_bytes());
+            continue;
+        }
+        let digits: String = part.chars().filter(|c| !c.is_whitespace()).collect();
+        if !digits.len().is_multiple_of(2) {
+            return Err(format!("16進数の桁数が不正です: |{}|", part));
+        }
+        for pair in digits.as_bytes().chunks(2) {
+            let pair = std::str::from_utf8(pair).unwrap_or_default();
+            pattern.push(u8::from_str_radix(pair, 16).map_err(|_| format!("不正な16進数です: |{}|", part))?);
+        }
+    }
+    if text.split('|').count().is_multiple_of(2) {
+        return Err(format!("| が閉じられていません: {}", value));
+    }
+    if pattern.is_empty() {
+        return Err("contentが空です".to_string());
+    }
+
+    Ok(ContentMatch {
+        pattern,
+        negated,
+        ..ContentMatch::default()
+    })
+}
+
+fn parse_flow(value: &str) -> Result<FlowOptions, String> {
+    let mut flow = FlowOptions::default();
+    for item in value.split(',') {
+        match item.trim() {
+            "established" => flow.established = Some(true),
+            "not_established" => flow.established = Some(false),
+            "stateless" => flow.established = None,
+            "to_server" | "from_client" => flow.to_server = Some(true),
+            "to_client" | "from_server" => flow.to_server = Some(false),
+            "only_stream" => flow.stream = StreamMatching::OnlyStream,
+            "no_stream" => flow.stream = StreamMatching::NoStream,
+            item => return Err(format!("不明なflowの指定です: {}", item)),
+        }
+    }
+    Ok(flow)
+}
+
+fn parse_number<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("{} の値が不正です: {}", name, value))
+}
+
+// nocaseなどの修飾子は直前のcontentに適用する
+fn last_content<'a>(rule: &'a mut Rule, name: &str) -> Result<&'a mut ContentMatch, String> {
+    rule.contents
+        .last_mut()
+        .ok_or_else(|| format!("{} の前にcontentを指定してください", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Rule, String> {
+        parse_rule(line, &RuleVars::new("10.0.0.0/8"))
+    }
+
+    #[test]
+    fn parses_header_and_options() {
+        let rule = parse(
+            "alert tcp $EXTERNAL_NET any -> $HOME_NET [80,8000:8080] (msg:\"test \\\"rule\\\"\"; \
+             flow:established,to_server; content:\"GET\"; offset:0; depth:3; content:\"|0d 0a|Host\"; nocase; \
+             distance:0; within:64; classtype:web; priority:2; sid:100; rev:3;)",
+        )
+        .unwrap();
+        assert_eq!(rule.action, RuleAction::Alert);
+        assert_eq!(rule.protocol, RuleProtocol::Tcp);
+        assert_eq!(rule.msg, "test \"rule\"");
+        assert_eq!((rule.gid, rule.sid, rule.rev), (1, 100, 3));
+        assert_eq!(rule.classtype.as_deref(), Some("web"));
+        assert_eq!(rule.priority, Some(2));
+        assert_eq!(rule.flow.established, Some(true));
+        assert_eq!(rule.flow.to_server, Some(true));
+        assert_eq!(rule.contents.len(), 2);
+        assert_eq!(rule.contents[0].pattern, b"GET");
+        assert_eq!((rule.contents[0].offset, rule.contents[0].depth), (Some(0), Some(3)));
+        assert_eq!(rule.contents[1].pattern, b"\r\nHost");
+        assert!(rule.contents[1].nocase && rule.contents[1].is_relative());
+        assert_eq!((rule.contents[1].distance, rule.contents[1].within), (Some(0), Some(64)));
+
+        let home: IpAddr = "10.1.2.3".parse().unwrap();
+        let external: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(rule.matches_endpoints(external, Some(40000), home, Some(8080)));
+        assert!(!rule.matches_endpoints(external, Some(40000), home, Some(443)));
+        assert!(!rule.matches_endpoints(home, Some(40000), home, Some(80)));
+    }
+
+    #[test]
+    fn port_and_address_lists_with_negation() {
+        let ports = parse_ports("[1:1024,!22]", &RuleVars::default(), 0).unwrap();
+        assert!(ports.matches(Some(80)));
+        assert!(!ports.matches(Some(22)));
+        assert!(!ports.matches(Some(8080)));
+        assert!(!ports.matches(None));
+
+        let addresses = parse_address("[!192.0.2.0/24]", &RuleVars::default(), 0).unwrap();
+        assert!(addresses.matches("198.51.100.1".parse().unwrap()));
+        assert!(!addresses.matches("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn bidirectional_rule_matches_reverse_direction() {
+        let rule = parse("alert udp 10.0.0.1 53 <> any any (msg:\"dns\"; sid:1;)").unwrap();
+        let server: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(rule.matches_endpoints(server, Some(53), client, Some(40000)));
+        assert!(rule.matches_endpoints(client, Some(40000), server, Some(53)));
+        assert!(!rule.matches_endpoints(client, Some(40000), server, Some(54)));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let invalid = [
+            "alert tcp any any -> any any",
+            "alert tcp any any -> any (sid:1;)",
+            "block tcp any any -> any any (sid:1;)",
+            "alert tcp any any => any any (sid:1;)",
+            "alert tcp any 70000 -> any any (sid:1;)",
+            "alert tcp any 90:80 -> any any (sid:1;)",
+            "alert tcp $UNKNOWN any -> any any (sid:1;)",
+            "alert tcp any any -> any any (msg:\"no sid\";)",
+            "alert tcp any any -> any any (nocase; sid:1;)",
+            "alert tcp any any -> any any (content:\"|4|\"; sid:1;)",
+            "alert tcp any any -> any any (content:\"|41\"; sid:1;)",
+            "alert tcp any any -> any any (content:\"abc\"; depth:2; sid:1;)",
+            "alert tcp any any -> any any (content:\"a\"; content:\"b\"; offset:1; distance:0; sid:1;)",
+            "alert tcp any any -> any any (content:\"a\"; pcre:\"/a/\"; sid:1;)",
+        ];
+        for line in invalid {
+            assert!(parse(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn rule_set_reports_duplicate_sid() {
+        let vars = RuleVars::new("any");
+        let mut rules = RuleSet::new();
+        rules.add("alert ip any any -> any any (msg:\"a\"; sid:1;)", &vars).unwrap();
+        assert!(rules.add("alert ip any any -> any any (msg:\"b\"; sid:1;)", &vars).is_err());
+        rules.add("alert ip any any -> any any (msg:\"c\"; gid:2; sid:1;)", &vars).unwrap();
+        assert_eq!(rules.rules.len(), 2);
+    }
+}
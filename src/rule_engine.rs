@@ -0,0 +1,659 @@
+use crate::packet_processor::{IPPROTO_ICMP, IPPROTO_ICMPV6, IPPROTO_TCP, IPPROTO_UDP};
+use crate::prefilter::{Prefilter, PrefilterState};
+use crate::rule::{ContentMatch, Rule, RuleAction, StreamMatching};
+use crate::tcp_stream::{StreamChunk, TcpState, TcpStream, TcpStreamKey};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+// ルールと照合するパケットの情報
+#[derive(Debug, Clone)]
+pub struct PacketContext {
+    pub protocol: u8,
+    pub src_ip: IpAddr,
+    pub src_port: Option<u16>,
+    pub dst_ip: IpAddr,
+    pub dst_port: Option<u16>,
+    pub from_client: Option<bool>, // 追跡している接続(フロー)での方向 (不明な場合はNone)
+    pub established: bool,
+    pub stream_tracked: bool, // TCPストリームとして再構築しているか
+}
+
+// ルールに一致したパケット(ストリーム)
+#[derive(Debug, Clone)]
+pub struct RuleAlert {
+    pub rule: Arc<Rule>,
+    pub protocol: u8,
+    pub src_ip: IpAddr,
+    pub src_port: Option<u16>,
+    pub dst_ip: IpAddr,
+    pub dst_port: Option<u16>,
+}
+
+impl RuleAlert {
+    pub fn protocol_name(&self) -> &'static str {
+        match self.protocol {
+            IPPROTO_TCP => "TCP",
+            IPPROTO_UDP => "UDP",
+            IPPROTO_ICMP => "ICMP",
+            IPPROTO_ICMPV6 => "IPv6-ICMP",
+            _ => "IP",
+        }
+    }
+}
+
+// Snortのfast.logに似た形式で出力する
+impl fmt::Display for RuleAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rule = &self.rule;
+        write!(f, "[{}:{}:{}] {}", rule.gid, rule.sid, rule.rev, rule.msg)?;
+        if let Some(classtype) = &rule.classtype {
+            write!(f, " [Classification: {}]", classtype)?;
+        }
+        if let Some(priority) = rule.priority {
+            write!(f, " [Priority: {}]", priority)?;
+        }
+        let endpoint = |ip: IpAddr, port: Option<u16>| match port {
+            Some(port) => format!("{}:{}", ip, port),
+            None => ip.to_string(),
+        };
+        write!(
+            f,
+            " {{{}}} {} -> {}",
+            self.protocol_name(),
+            endpoint(self.src_ip, self.src_port),
+            endpoint(self.dst_ip, self.dst_port)
+        )
+    }
+}
+
+// ストリームの方向ごとの検査状態
+struct DirectionInspection {
+    start: usize,     // 検査するデータの先頭 (最後の欠落より後のデータのみを検査する)
+    inspected: usize, // 検査済みのデータの長さ
+    prefilter: PrefilterState,  // 検査済みのデータの末尾での絞り込みの状態
+    candidates: HashSet<usize>, // 代表のパターンが見つかったルール
+    alerted: HashSet<usize>, // 通知済みのルール (同じ方向では一度だけ通知する)
+    progress: HashMap<usize, ConfirmProgress>, // 候補のルールごとの照合の進み具合
+    passed: bool,     // passルールに一致したため以降は検査しない
+}
+
+impl DirectionInspection {
+    fn new(prefilter: &Prefilter) -> Self {
+        DirectionInspection {
+            start: 0,
+            inspected: 0,
+            prefilter: prefilter.start(),
+            candidates: HashSet::new(),
+            alerted: HashSet::new(),
+            progress: HashMap::new(),
+            passed: false,
+        }
+    }
+}
+
+struct StreamInspection {
+    client: DirectionInspection,
+    server: DirectionInspection,
+}
+
+#[derive(Debug, Default)]
+pub struct RuleCounters {
+    pub alerts: u64,
+    pub passed: u64, // passルールにより通知しなかった件数
+}
+
+// 読み込んだルールをパケットと再構築したストリームに照合する
+// TCPのcontentは再構築したclient_data/server_dataで検査し、流れるパケットごとには検査しない
+// 照合するのはprefilterでcontentが見つかった候補のルールのみ
+pub struct RuleEngine {
+    rules: Vec<Arc<Rule>>,
+    prefilter: Prefilter,
+    streams: HashMap<TcpStreamKey, StreamInspection>,
+    alerts: Vec<RuleAlert>, // 未出力のアラート
+    pub counters: RuleCounters,
+}
+
+impl Default for RuleEngine {
+    fn default() -> Self {
+        RuleEngine::new(Vec::new())
+    }
+}
+
+impl RuleEngine {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        let rules: Vec<Arc<Rule>> = rules.into_iter().map(Arc::new).collect();
+        RuleEngine {
+            prefilter: Prefilter::new(&rules),
+            rules,
+            streams: HashMap::new(),
+            alerts: Vec::new(),
+            counters: RuleCounters::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // パケットのペイロードを検査する
+    pub fn inspect_packet(&mut self, packet: &PacketContext, payload: &[u8]) {
+        let applicable = self
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| applies_to_packet(rule, packet) && matches_header(rule, packet))
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        if applicable.is_empty() {
+            return;
+        }
+
+        let mut candidates = HashSet::new();
+        self.prefilter.scan(&mut self.prefilter.start(), payload, &mut candidates);
+        let matched = applicable
+            .into_iter()
+            .filter(|index| {
+                self.prefilter.is_candidate(*index, &candidates)
+                    && match_contents(&self.rules[*index].contents, payload, 0)
+            })
+            .collect::<Vec<_>>();
+        push_alerts(&self.rules, &mut self.alerts, &mut self.counters, &matched, packet);
+    }
+
+    // ストリームに新たに連続したデータを検査する
+    // 絞り込みは新たに追加されたデータのみを前回の状態から続けて探し、候補のルールも前回の照合の続きから照合する
+    pub fn inspect_stream(&mut self, key: &TcpStreamKey, stream: &TcpStream, chunks: &[StreamChunk]) {
+        if self.rules.is_empty() || chunks.is_empty() {
+            return;
+        }
+        let established = is_established(stream);
+        let prefilter = &self.prefilter;
+        let inspection = self.streams.entry(*key).or_insert_with(|| StreamInspection {
+            client: DirectionInspection::new(prefilter),
+            server: DirectionInspection::new(prefilter),
+        });
+
+        for from_client in [true, false] {
+            let (data, direction) = if from_client {
+                (&stream.client_data, &mut inspection.client)
+            } else {
+                (&stream.server_data, &mut inspection.server)
+            };
+            // 欠落の前後のデータをつなげて検査しないよう、欠落より後のデータから検査し直す
+            if let Some(after_gap) = bytes_after_last_gap(chunks, from_client) {
+                direction.start = data.len().saturating_sub(after_gap);
+                direction.prefilter = prefilter.start();
+                direction.candidates.clear();
+                direction.progress.clear();
+            }
+            // depthを超えて保持されなかった場合は新しいデータがない
+            if direction.passed || data.len() == direction.inspected {
+                continue;
+            }
+            let scan_from = direction.inspected.max(direction.start);
+            prefilter.scan(&mut direction.prefilter, &data[scan_from..], &mut direction.candidates);
+            direction.inspected = data.len();
+
+            let packet = PacketContext {
+                protocol: IPPROTO_TCP,
+                src_ip: if from_client { key.0 } else { key.2 },
+                src_port: Some(if from_client { key.1 } else { key.3 }),
+                dst_ip: if from_client { key.2 } else { key.0 },
+                dst_port: Some(if from_client { key.3 } else { key.1 }),
+                from_client: Some(from_client),
+                established,
+                stream_tracked: true,
+            };
+            let buffer = &data[direction.start..];
+            let mut matched = Vec::new();
+            for (index, rule) in self.rules.iter().enumerate() {
+                if direction.alerted.contains(&index)
+                    || !applies_to_stream(rule)
+                    || !prefilter.is_candidate(index, &direction.candidates)
+                {
+                    continue;
+                }
+                if !matches_header(rule, &packet) {
+                    continue;
+                }
+                let progress = direction.progress.entry(index).or_default();
+                if confirm_contents(&rule.contents, buffer, progress) {
+                    direction.progress.remove(&index);
+                    direction.alerted.insert(index);
+                    matched.push(index);
+                }
+            }
+            if matched.iter().any(|index| self.rules[*index].action == RuleAction::Pass) {
+                direction.passed = true;
+            }
+            push_alerts(&self.rules, &mut self.alerts, &mut self.counters, &matched, &packet);
+        }
+    }
+
+    // 追跡を終了したストリームの検査状態を削除する
+    pub fn remove_stream(&mut self, key: &TcpStreamKey) {
+        self.streams.remove(key);
+    }
+
+    // 未出力のアラートを取り出す
+    pub fn take_alerts(&mut self) -> Vec<RuleAlert> {
+        std::mem::take(&mut self.alerts)
+    }
+}
+
+// 一致したルールのアラートを追加する (passルールに一致した場合は何も通知しない)
+fn push_alerts(
+    rules: &[Arc<Rule>],
+    alerts: &mut Vec<RuleAlert>,
+    counters: &mut RuleCounters,
+    matched: &[usize],
+    packet: &PacketContext,
+) {
+    if matched.iter().any(|index| rules[*index].action == RuleAction::Pass) {
+        counters.passed += matched
+            .iter()
+            .filter(|index| rules[**index].action != RuleAction::Pass)
+            .count() as u64;
+        return;
+    }
+    for index in matched {
+        counters.alerts += 1;
+        alerts.push(RuleAlert {
+            rule: Arc::clone(&rules[*index]),
+            protocol: packet.protocol,
+            src_ip: packet.src_ip,
+            src_port: packet.src_port,
+            dst_ip: packet.dst_ip,
+            dst_port: packet.dst_port,
+        });
+    }
+}
+
+// 3ウェイハンドシェイクを終えているか (midstreamで追跡したストリームを含む)
+pub fn is_established(stream: &TcpStream) -> bool {
+    matches!(
+        stream.state,
+        TcpState::Established
+            | TcpState::FinWait1
+            | TcpState::FinWait2
+            | TcpState::CloseWait
+            | TcpState::Closing
+            | TcpState::LastAck
+            | TcpState::TimeWait
+    )
+}
+
+// パケット単位で検査するルールか
+// TCPのcontentは再構築したストリームで検査するため、ストリームを追跡していない場合とno_streamのみパケットで検査する
+fn applies_to_packet(rule: &Rule, packet: &PacketContext) -> bool {
+    if packet.protocol != IPPROTO_TCP {
+        return true;
+    }
+    match rule.flow.stream {
+        StreamMatching::NoStream => true,
+        StreamMatching::OnlyStream => false,
+        StreamMatching::Default => !packet.stream_tracked || rule.contents.is_empty(),
+    }
+}
+
+// 再構築したストリームで検査するルールか
+fn applies_to_stream(rule: &Rule) -> bool {
+    if !rule.protocol.matches(IPPROTO_TCP) {
+        return false;
+    }
+    match rule.flow.stream {
+        StreamMatching::NoStream => false,
+        StreamMatching::OnlyStream => true,
+        StreamMatching::Default => !rule.contents.is_empty(),
+    }
+}
+
+// プロトコル、アドレス、ポート、flowの指定に一致するか
+fn matches_header(rule: &Rule, packet: &PacketContext) -> bool {
+    rule.protocol.matches(packet.protocol)
+        && rule.flow.established.is_none_or(|established| established == packet.established)
+        && rule.flow.to_server.is_none_or(|to_server| packet.from_client == Some(to_server))
+        && rule.matches_endpoints(packet.src_ip, packet.src_port, packet.dst_ip, packet.dst_port)
+}
+
+// 最後の欠落より後に連続したデータのバイト数 (この方向に欠落がなければNone)
+fn bytes_after_last_gap(chunks: &[StreamChunk], from_client: bool) -> Option<usize> {
+    let mut bytes = 0;
+    for chunk in chunks.iter().rev() {
+        match chunk {
+            StreamChunk::Data { from_client: direction, data } if *direction == from_client => bytes += data.len(),
+            StreamChunk::Gap { from_client: direction, .. } if *direction == from_client => return Some(bytes),
+            _ => (),
+        }
+    }
+    None
+}
+
+// ストリームのデータに対するルールの照合の進み具合
+#[derive(Debug, Default)]
+struct ConfirmProgress {
+    checked: usize,  // 一致しなかったことを確認したデータの長さ
+    matched: usize,  // 先頭から順に一致したcontentの数 (ContentScope::Chainのみ)
+    prev_end: usize, // 最後に一致したcontentの末尾
+    resume: usize,   // 次のcontentを探し始める位置
+}
+
+// データが増えたときに照合し直す範囲を決めるcontentの組み合わせ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ContentScope {
+    Bounded(usize), // 後続がすべてwithin付きの相対指定で、一致全体が先頭のcontentからこのバイト数に収まる
+    Chain,          // 後続がすべてwithinなしの相対指定 (最も手前の一致を順に探せばよい)
+    Unbounded,      // その他 (データ全体を照合し直す)
+}
+
+fn content_scope(contents: &[ContentMatch]) -> ContentScope {
+    let Some((first, rest)) = contents.split_first() else {
+        return ContentScope::Bounded(0);
+    };
+    if first.negated {
+        return ContentScope::Unbounded;
+    }
+    if rest.iter().all(|content| content.is_relative() && content.within.is_some()) {
+        // 相対指定のcontentを探す範囲は直前の一致の末尾からdistance + withinまで
+        let span = rest.iter().fold(first.pattern.len(), |span, content| {
+            let reach = content.distance.unwrap_or(0) + content.within.unwrap_or(0) as i64;
+            span.saturating_add(reach.max(0) as usize)
+        });
+        return ContentScope::Bounded(span);
+    }
+    if rest.iter().all(|content| content.is_relative() && content.within.is_none() && !content.negated) {
+        ContentScope::Chain
+    } else {
+        ContentScope::Unbounded
+    }
+}
+
+// 前回の照合の続きからcontentを照合する (dataは増えるだけで先頭は変わらないこと)
+// 前回一致しなかった場合、新たに一致しうるのは一致の範囲が追加されたデータにかかるものだけである
+fn confirm_contents(contents: &[ContentMatch], data: &[u8], progress: &mut ConfirmProgress) -> bool {
+    match content_scope(contents) {
+        ContentScope::Bounded(span) => {
+            let anchor_from = progress.checked.saturating_sub(span);
+            progress.checked = data.len();
+            match_anchored(contents, data, anchor_from)
+        }
+        ContentScope::Chain => match_chain(contents, data, progress),
+        ContentScope::Unbounded => match_contents(contents, data, 0),
+    }
+}
+
+// 先頭のcontentをanchor_from以降で探して照合する
+fn match_anchored(contents: &[ContentMatch], data: &[u8], anchor_from: usize) -> bool {
+    let Some((content, rest)) = contents.split_first() else {
+        return true;
+    };
+    let (start, end) = search_window(content, data.len(), 0);
+    let mut from = start.max(anchor_from);
+    while let Some(found) = find(data, content, from, end) {
+        if match_contents(rest, data, found + content.pattern.len()) {
+            return true;
+        }
+        from = found + 1;
+    }
+    false
+}
+
+// 最も手前の一致を前回の続きから順に探す
+// withinがなければ手前で一致するほど後続を探す範囲が広がるため、他の一致位置を試す必要はない
+fn match_chain(contents: &[ContentMatch], data: &[u8], progress: &mut ConfirmProgress) -> bool {
+    while let Some(content) = contents.get(progress.matched) {
+        let (start, end) = search_window(content, data.len(), progress.prev_end);
+        let from = start.max(progress.resume);
+        match find(data, content, from, end) {
+            Some(found) => {
+                progress.matched += 1;
+                progress.prev_end = found + content.pattern.len();
+                progress.resume = 0;
+            }
+            None => {
+                // 探し終えた位置は次回探さない
+                progress.resume = from.max((end + 1).saturating_sub(content.pattern.len()));
+                return false;
+            }
+        }
+    }
+    true
+}
+
+// contentを順に照合する (prev_endは直前のcontentが一致した末尾)
+// 相対指定のcontentが一致しない場合は、直前のcontentの次の一致位置からやり直す
+pub fn match_contents(contents: &[ContentMatch], data: &[u8], prev_end: usize) -> bool {
+    let Some((content, rest)) = contents.split_first() else {
+        return true;
+    };
+    let (start, end) = search_window(content, data.len(), prev_end);
+
+    if content.negated {
+        return find(data, content, start, end).is_none() && match_contents(rest, data, prev_end);
+    }
+
+    let mut from = start;
+    while let Some(found) = find(data, content, from, end) {
+        if match_contents(rest, data, found + content.pattern.len()) {
+            return true;
+        }
+        // 次のcontentが一致位置に依存しない場合は、他の位置で一致しても結果は変わらない
+        if !rest.first().is_some_and(ContentMatch::is_relative) {
+            return false;
+        }
+        from = found + 1;
+    }
+    false
+}
+
+// contentを探す範囲 [start, end)
+// withinはSuricataと同じくdistanceを適用した位置から数える
+fn search_window(content: &ContentMatch, len: usize, prev_end: usize) -> (usize, usize) {
+    if content.is_relative() {
+        let start = prev_end as i64 + content.distance.unwrap_or(0);
+        let end = content.within.map_or(len as i64, |within| start + within as i64);
+        (start.max(0) as usize, end.clamp(0, len as i64) as usize)
+    } else {
+        let start = content.offset.unwrap_or(0);
+        let end = content.depth.map_or(len, |depth| start.saturating_add(depth)).min(len);
+        (start, end)
+    }
+}
+
+fn find(data: &[u8], content: &ContentMatch, start: usize, end: usize) -> Option<usize> {
+    let length = content.pattern.len();
+    if start >= end || end - start < length {
+        return None;
+    }
+    data[start..end]
+        .windows(length)
+        .position(|window| {
+            if content.nocase {
+                window.eq_ignore_ascii_case(&content.pattern)
+            } else {
+                window == content.pattern.as_slice()
+            }
+        })
+        .map(|position| start + position)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::{parse_rule, RuleVars};
+    use crate::tcp_header::TcpHeader;
+    use crate::tcp_reassembly::{ReassemblyPolicy, StreamLimits};
+    use crate::tcp_stream::TCP_ACK;
+    use std::time::SystemTime;
+
+    fn rule(options: &str) -> Rule {
+        let line = format!("alert tcp any any -> any any (msg:\"test\"; {} sid:1;)", options);
+        parse_rule(&line, &RuleVars::new("any")).unwrap()
+    }
+
+    fn contents(options: &str) -> Vec<ContentMatch> {
+        rule(options).contents
+    }
+
+    #[test]
+    fn matches_content_modifiers() {
+        let data = b"GET /index.html HTTP/1.1\r\nHost: example\r\n";
+        let cases = [
+            ("content:\"GET\"; depth:3;", true),
+            ("content:\"/index\"; depth:9;", false),
+            ("content:\"/index\"; depth:10;", true),
+            ("content:\"HTTP\"; offset:16;", true),
+            ("content:\"HTTP\"; offset:17;", false),
+            ("content:\"get\";", false),
+            ("content:\"get\"; nocase;", true),
+            ("content:\"GET\"; content:\"HTTP\"; distance:0; within:17;", true),
+            ("content:\"GET\"; content:\"HTTP\"; distance:0; within:16;", false),
+            ("content:\"HTTP\"; content:\"GET\"; distance:0;", false),
+            ("content:\"HTTP\"; content:\"index\"; distance:-20; within:20;", true),
+            ("content:\"|0d 0a|Host\";", true),
+            ("content:\"GET\"; content:!\"POST\";", true),
+            ("content:\"GET\"; content:!\"Host\"; distance:0;", false),
+            ("content:\"GET\"; content:!\"Host\"; distance:0; within:20;", true),
+        ];
+        for (options, expected) in cases {
+            assert_eq!(match_contents(&contents(options), data, 0), expected, "{}", options);
+        }
+    }
+
+    // 相対指定のcontentが一致しない場合は、直前のcontentの次の一致位置からやり直す
+    #[test]
+    fn retries_later_occurrence_for_relative_content() {
+        let contents = contents("content:\"a\"; content:\"b\"; distance:0; within:1;");
+        assert!(match_contents(&contents, b"axxab", 0));
+        assert!(!match_contents(&contents, b"axxa", 0));
+    }
+
+    fn stream_segment(seq: u32, ack: u32) -> TcpHeader {
+        TcpHeader {
+            src_port: 40000,
+            dst_port: 80,
+            seq_num: seq,
+            ack_num: ack,
+            data_offset: 5,
+            flags: TCP_ACK,
+            window: 1000,
+            checksum: 0,
+            urgent_ptr: 0,
+            options: Vec::new(),
+        }
+    }
+
+    // セグメント(クライアントからか, seq, ack, データ)ごとにストリームを更新して検査し、アラートの数を返す
+    fn inspect_segments(engine: &mut RuleEngine, segments: &[(bool, u32, u32, &[u8])]) -> Vec<usize> {
+        let key: TcpStreamKey = ("192.0.2.1".parse().unwrap(), 40000, "192.0.2.2".parse().unwrap(), 80);
+        let now = SystemTime::UNIX_EPOCH;
+        let mut stream = TcpStream::new_midstream(1000, 5000, now, ReassemblyPolicy::First, StreamLimits::default());
+        let mut alerts = Vec::new();
+        for (from_client, seq, ack, data) in segments {
+            stream.update(*from_client, &stream_segment(*seq, *ack), data, now);
+            let chunks = stream.take_chunks();
+            engine.inspect_stream(&key, &stream, &chunks);
+            alerts.push(engine.take_alerts().len());
+        }
+        alerts
+    }
+
+    #[test]
+    fn stream_match_across_segments() {
+        let mut engine = RuleEngine::new(vec![rule("flow:to_server; content:\"attack\";")]);
+        let alerts = inspect_segments(
+            &mut engine,
+            &[(true, 1000, 5000, b"xxatt"), (true, 1005, 5000, b"ack"), (true, 1008, 5000, b"attack")],
+        );
+        // 同じ方向では一度だけ通知する
+        assert_eq!(alerts, [0, 1, 0]);
+    }
+
+    #[test]
+    fn stream_match_across_segments_with_relative_contents() {
+        let mut engine = RuleEngine::new(vec![rule("content:\"USER\"; content:\"root\"; distance:1; within:8;")]);
+        let alerts = inspect_segments(
+            &mut engine,
+            &[(true, 1000, 5000, b"xUS"), (true, 1003, 5000, b"ER  "), (true, 1007, 5000, b"root")],
+        );
+        assert_eq!(alerts, [0, 0, 1]);
+    }
+
+    // 欠落の前後のデータをつなげて一致させない
+    #[test]
+    fn stream_match_does_not_span_gaps() {
+        let mut engine = RuleEngine::new(vec![rule("content:\"attack\";")]);
+        // サーバーの確認応答で1005から1010までの欠落を飛ばす
+        let alerts = inspect_segments(
+            &mut engine,
+            &[
+                (true, 1000, 5000, b"xxatt"),
+                (true, 1010, 5000, b"ack"),
+                (false, 5000, 1013, b""),
+                (true, 1013, 5000, b"attack"),
+            ],
+        );
+        assert_eq!(alerts, [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn content_scope_by_relative_modifiers() {
+        assert_eq!(content_scope(&contents("content:\"ab\";")), ContentScope::Bounded(2));
+        assert_eq!(
+            content_scope(&contents("content:\"ab\"; content:\"cd\"; distance:1; within:4;")),
+            ContentScope::Bounded(7)
+        );
+        assert_eq!(content_scope(&contents("content:\"ab\"; content:\"cd\"; distance:0;")), ContentScope::Chain);
+        assert_eq!(content_scope(&contents("content:\"ab\"; content:\"cd\";")), ContentScope::Unbounded);
+        assert_eq!(content_scope(&contents("content:!\"ab\";")), ContentScope::Unbounded);
+    }
+
+    // データを少しずつ追加しながら照合した結果が、毎回データ全体を照合した結果と一致する
+    #[test]
+    fn incremental_confirmation_matches_full_scan() {
+        let rules = [
+            "content:\"ab\";",
+            "content:\"abc\"; nocase;",
+            "content:\"ab\"; content:\"cd\"; distance:0; within:4;",
+            "content:\"ab\"; content:\"cd\"; distance:2; within:2; content:\"a\"; distance:0; within:3;",
+            "content:\"ab\"; content:!\"cc\"; distance:0; within:3;",
+            "content:\"ab\"; content:\"dd\"; distance:1;",
+            "content:\"ba\"; depth:30; content:\"ab\"; distance:-1; content:\"cc\"; distance:0;",
+            "content:\"dc\"; content:\"ab\";",
+        ];
+        let mut seed: u32 = 12345;
+        let mut next = || {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as usize
+        };
+        for options in rules {
+            let contents = contents(options);
+            for _ in 0..200 {
+                let mut data = Vec::new();
+                let mut progress = ConfirmProgress::default();
+                for _ in 0..20 {
+                    for _ in 0..next() % 6 {
+                        data.push(b"abcdA"[next() % 5]);
+                    }
+                    let expected = match_contents(&contents, &data, 0);
+                    assert_eq!(
+                        confirm_contents(&contents, &data, &mut progress),
+                        expected,
+                        "{} {:?}",
+                        options,
+                        String::from_utf8_lossy(&data)
+                    );
+                    if expected {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,581 @@
+use crate::capture_clock::elapsed_between;
+use crate::icmp_header::IcmpMessage;
+use crate::packet_processor::{IPPROTO_TCP, IPPROTO_UDP};
+use crate::tcp_stream::{TcpStreamKey, TCP_ACK, TCP_FIN, TCP_PSH, TCP_RST, TCP_SYN, TCP_URG};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+// 送信元ごとにこの期間内のプローブを数える
+const SCAN_WINDOW: Duration = Duration::from_secs(60);
+
+// 1つのホストのこの数以上のポート、または1つのポートのこの数以上のホストへのプローブをスキャンとみなす
+const PORT_SCAN_THRESHOLD: usize = 20;
+const HOST_SWEEP_THRESHOLD: usize = 20;
+
+// スキャンと判定した送信元からこの時間プローブがなければスキャンの終了とみなして通知する
+const SCAN_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// 追跡する送信元と、1つの送信元で保持するプローブの上限
+const MAX_SOURCES: usize = 65536;
+const MAX_PROBES_PER_SOURCE: usize = 65536;
+
+// アラートのメッセージに列挙するポートとホストの数
+const MAX_LISTED: usize = 20;
+
+// プローブに使われたパケットの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ScanKind {
+    Connect, // ハンドシェイクを完了させてすぐに閉じる
+    Syn,     // SYNのみを送り、ハンドシェイクを完了させない
+    Fin,
+    Null, // フラグなし
+    Xmas, // FIN+PSH+URG
+    Ack,  // 接続のないACK (フィルタリングの調査)
+    Udp,
+}
+
+impl ScanKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScanKind::Connect => "connect",
+            ScanKind::Syn => "syn",
+            ScanKind::Fin => "fin",
+            ScanKind::Null => "null",
+            ScanKind::Xmas => "xmas",
+            ScanKind::Ack => "ack",
+            ScanKind::Udp => "udp",
+        }
+    }
+
+    // 追跡している接続のない送信元からのTCPセグメントをフラグから分類する
+    pub fn from_tcp_flags(flags: u8) -> Option<ScanKind> {
+        let control = flags & (TCP_SYN | TCP_ACK | TCP_RST | TCP_FIN);
+        match control {
+            TCP_SYN => Some(ScanKind::Syn),
+            TCP_ACK => Some(ScanKind::Ack),
+            TCP_FIN if flags & (TCP_PSH | TCP_URG) == TCP_PSH | TCP_URG => Some(ScanKind::Xmas),
+            TCP_FIN => Some(ScanKind::Fin),
+            0 if flags & (TCP_PSH | TCP_URG) == 0 => Some(ScanKind::Null),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ScanKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ScanKind::Connect => "TCP connect",
+            ScanKind::Syn => "SYN",
+            ScanKind::Fin => "FIN",
+            ScanKind::Null => "NULL",
+            ScanKind::Xmas => "XMAS",
+            ScanKind::Ack => "ACK",
+            ScanKind::Udp => "UDP",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// スキャンの形 (1つのホストの多数のポート、または多数のホストの同じポート)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanPattern {
+    PortScan,
+    HostSweep,
+}
+
+impl ScanPattern {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScanPattern::PortScan => "port_scan",
+            ScanPattern::HostSweep => "host_sweep",
+        }
+    }
+}
+
+// プローブへの応答
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeResponse {
+    Open,     // SYN-ACK
+    Closed,   // RST、またはICMPのポート到達不能
+    Filtered, // ICMPの到達不能 (ポート到達不能以外)
+}
+
+// スキャンの集計 (スキャンの終了時に一度だけ通知する)
+#[derive(Debug, Clone)]
+pub struct ScanEvent {
+    pub src_ip: IpAddr,
+    pub kind: ScanKind, // 最も多かったプローブの種類
+    pub pattern: ScanPattern,
+    pub ports: Vec<u16>,
+    pub hosts: Vec<IpAddr>,
+    pub open: Vec<(IpAddr, u16)>, // SYN-ACKを返したポート
+    pub closed: usize,
+    pub filtered: usize,
+    pub probes: usize,
+    pub started: SystemTime,
+    pub ended: SystemTime,
+}
+
+impl ScanEvent {
+    pub fn duration(&self) -> Duration {
+        elapsed_between(self.started, self.ended)
+    }
+}
+
+impl fmt::Display for ScanEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pattern = match self.pattern {
+            ScanPattern::PortScan => "port scan",
+            ScanPattern::HostSweep => "host sweep",
+        };
+        write!(
+            f,
+            "{} {} from {} ({} ports on {} hosts, {} probes, open={} closed={} filtered={}, {:?}) ports={} hosts={}",
+            self.kind,
+            pattern,
+            self.src_ip,
+            self.ports.len(),
+            self.hosts.len(),
+            self.probes,
+            self.open.len(),
+            self.closed,
+            self.filtered,
+            self.duration(),
+            list(&self.ports),
+            list(&self.hosts)
+        )
+    }
+}
+
+// 先頭のMAX_LISTED件をカンマ区切りにする
+fn list<T: fmt::Display>(items: &[T]) -> String {
+    let mut text = items
+        .iter()
+        .take(MAX_LISTED)
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    if items.len() > MAX_LISTED {
+        text.push_str(",...");
+    }
+    text
+}
+
+// プローブを識別するキー (送信元ポート, 宛先, 宛先ポート, プロトコル)
+type ProbeKey = (u16, IpAddr, u16, u8);
+
+struct Probe {
+    time: SystemTime,
+    kind: ScanKind,
+    response: Option<ProbeResponse>,
+}
+
+// 送信元ごとのプローブ
+struct SourceActivity {
+    probes: HashMap<ProbeKey, Probe>,
+    targets: HashMap<(IpAddr, u16), usize>, // (ホスト, ポート)ごとのプローブ数
+    ports_by_host: HashMap<IpAddr, usize>,  // ホストごとのポートの種類数
+    hosts_by_port: HashMap<u16, usize>,     // ポートごとのホストの種類数
+    detected: bool,                         // スキャンと判定したか (判定後は期間を過ぎたプローブも保持する)
+    last_seen: SystemTime,
+}
+
+impl SourceActivity {
+    fn new(now: SystemTime) -> Self {
+        SourceActivity {
+            probes: HashMap::new(),
+            targets: HashMap::new(),
+            ports_by_host: HashMap::new(),
+            hosts_by_port: HashMap::new(),
+            detected: false,
+            last_seen: now,
+        }
+    }
+
+    fn add(&mut self, key: ProbeKey, probe: Probe) {
+        if self.probes.insert(key, probe).is_some() {
+            return;
+        }
+        let count = self.targets.entry((key.1, key.2)).or_insert(0);
+        if *count == 0 {
+            *self.ports_by_host.entry(key.1).or_insert(0) += 1;
+            *self.hosts_by_port.entry(key.2).or_insert(0) += 1;
+        }
+        *count += 1;
+    }
+
+    fn remove(&mut self, key: &ProbeKey) {
+        if self.probes.remove(key).is_none() {
+            return;
+        }
+        let target = (key.1, key.2);
+        let Some(count) = self.targets.get_mut(&target) else {
+            return;
+        };
+        *count -= 1;
+        if *count > 0 {
+            return;
+        }
+        self.targets.remove(&target);
+        decrement(&mut self.ports_by_host, key.1);
+        decrement(&mut self.hosts_by_port, key.2);
+    }
+
+    // スキャンの形 (閾値に達していなければNone)
+    fn pattern(&self) -> Option<ScanPattern> {
+        if self.ports_by_host.values().any(|ports| *ports >= PORT_SCAN_THRESHOLD) {
+            Some(ScanPattern::PortScan)
+        } else if self.hosts_by_port.values().any(|hosts| *hosts >= HOST_SWEEP_THRESHOLD) {
+            Some(ScanPattern::HostSweep)
+        } else {
+            None
+        }
+    }
+}
+
+fn decrement<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, usize>, key: K) {
+    if let Some(count) = counts.get_mut(&key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ScanCounters {
+    pub scans: u64,
+    pub untracked: u64, // 上限により記録しなかったプローブ
+}
+
+// 送信元ごとに接続の試行を集計し、ポートスキャンとホストスイープを検知する
+// データを送受信した接続やUDPで応答のあったフローは通常の通信としてプローブから除く
+#[derive(Default)]
+pub struct ScanDetector {
+    sources: HashMap<IpAddr, SourceActivity>,
+    events: Vec<ScanEvent>, // 未出力のイベント
+    pub counters: ScanCounters,
+}
+
+impl ScanDetector {
+    pub fn new() -> Self {
+        ScanDetector::default()
+    }
+
+    // TCPセグメントを記録する
+    // new_connectionは追跡しているストリームがない接続のセグメントか
+    pub fn on_tcp_packet(&mut self, key: &TcpStreamKey, flags: u8, new_connection: bool, now: SystemTime) {
+        let &(src_ip, src_port, dst_ip, dst_port) = key;
+        // スキャンしている送信元への応答
+        let response = if flags & TCP_RST != 0 {
+            Some(ProbeResponse::Closed)
+        } else if flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK {
+            Some(ProbeResponse::Open)
+        } else {
+            None
+        };
+        if let Some(response) = response {
+            self.on_response((dst_ip, dst_port, src_ip, src_port), IPPROTO_TCP, response);
+            return;
+        }
+
+        if let Some(kind) = ScanKind::from_tcp_flags(flags).filter(|_| new_connection) {
+            self.add_probe(src_ip, (src_port, dst_ip, dst_port, IPPROTO_TCP), kind, now);
+        }
+    }
+
+    // 新しいUDPフローの最初のデータグラム
+    pub fn on_udp_flow(&mut self, key: &TcpStreamKey, now: SystemTime) {
+        self.add_probe(key.0, (key.1, key.2, key.3, IPPROTO_UDP), ScanKind::Udp, now);
+    }
+
+    // ハンドシェイクが完了した接続 (keyはクライアント, サーバーの順)
+    pub fn on_established(&mut self, key: &TcpStreamKey) {
+        if let Some(probe) = self.probe_mut(key, IPPROTO_TCP) {
+            if probe.kind == ScanKind::Syn {
+                probe.kind = ScanKind::Connect;
+            }
+        }
+    }
+
+    // データを送受信した接続は通常の通信としてプローブから除く
+    // midstreamでは接続のないACKをどちらの向きでも記録しうるため、両方向のプローブを除く
+    pub fn on_data(&mut self, key: &TcpStreamKey, protocol: u8) {
+        if let Some(source) = self.sources.get_mut(&key.0) {
+            source.remove(&(key.1, key.2, key.3, protocol));
+        }
+        if let Some(source) = self.sources.get_mut(&key.2) {
+            source.remove(&(key.3, key.0, key.1, protocol));
+        }
+    }
+
+    // プローブを引用したICMPエラー (keyは引用されたパケットの送信元, 宛先の順)
+    pub fn on_icmp_error(&mut self, key: &TcpStreamKey, protocol: u8, message: &IcmpMessage) {
+        let response = match message {
+            IcmpMessage::PortUnreachable => ProbeResponse::Closed,
+            IcmpMessage::DestinationUnreachable { .. } => ProbeResponse::Filtered,
+            _ => return,
+        };
+        self.on_response(*key, protocol, response);
+    }
+
+    fn on_response(&mut self, key: TcpStreamKey, protocol: u8, response: ProbeResponse) {
+        if let Some(probe) = self.probe_mut(&key, protocol) {
+            probe.response.get_or_insert(response);
+        }
+    }
+
+    fn probe_mut(&mut self, key: &TcpStreamKey, protocol: u8) -> Option<&mut Probe> {
+        self.sources
+            .get_mut(&key.0)?
+            .probes
+            .get_mut(&(key.1, key.2, key.3, protocol))
+    }
+
+    fn add_probe(&mut self, src_ip: IpAddr, key: ProbeKey, kind: ScanKind, now: SystemTime) {
+        if !self.sources.contains_key(&src_ip) && self.sources.len() >= MAX_SOURCES {
+            self.counters.untracked += 1;
+            return;
+        }
+        let source = self.sources.entry(src_ip).or_insert_with(|| SourceActivity::new(now));
+        if source.probes.len() >= MAX_PROBES_PER_SOURCE && !source.probes.contains_key(&key) {
+            self.counters.untracked += 1;
+            return;
+        }
+        source.last_seen = now;
+        source.add(
+            key,
+            Probe {
+                time: now,
+                kind,
+                response: None,
+            },
+        );
+        // 閾値はプローブを加えたホストとポートだけで確かめる
+        if source.ports_by_host.get(&key.1).is_some_and(|ports| *ports >= PORT_SCAN_THRESHOLD)
+            || source.hosts_by_port.get(&key.2).is_some_and(|hosts| *hosts >= HOST_SWEEP_THRESHOLD)
+        {
+            source.detected = true;
+        }
+    }
+
+    // 未出力のイベントを取り出す
+    pub fn take_events(&mut self) -> Vec<ScanEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    // キャプチャ時刻nowを基準に、期間を過ぎたプローブを削除し、終了したスキャンを通知する
+    pub fn cleanup(&mut self, now: SystemTime) {
+        let events = &mut self.events;
+        let counters = &mut self.counters;
+        self.sources.retain(|src_ip, source| {
+            if source.detected {
+                if elapsed_between(source.last_seen, now) < SCAN_IDLE_TIMEOUT {
+                    return true;
+                }
+                if let Some(event) = scan_event(*src_ip, source) {
+                    counters.scans += 1;
+                    events.push(event);
+                }
+                return false;
+            }
+
+            let expired: Vec<ProbeKey> = source
+                .probes
+                .iter()
+                .filter(|(_, probe)| elapsed_between(probe.time, now) >= SCAN_WINDOW)
+                .map(|(key, _)| *key)
+                .collect();
+            for key in &expired {
+                source.remove(key);
+            }
+            !source.probes.is_empty()
+        });
+    }
+
+    // キャプチャの終了時に継続中のスキャンを通知する
+    pub fn finish(&mut self) {
+        for (src_ip, source) in self.sources.drain() {
+            if !source.detected {
+                continue;
+            }
+            if let Some(event) = scan_event(src_ip, &source) {
+                self.counters.scans += 1;
+                self.events.push(event);
+            }
+        }
+    }
+}
+
+// 送信元のプローブを集計する (通常の通信を除いた結果が閾値に達しない場合はNone)
+fn scan_event(src_ip: IpAddr, source: &SourceActivity) -> Option<ScanEvent> {
+    let pattern = source.pattern()?;
+
+    let mut kinds: HashMap<ScanKind, usize> = HashMap::new();
+    let mut ports = BTreeSet::new();
+    let mut hosts = BTreeSet::new();
+    let mut open = BTreeSet::new();
+    let (mut closed, mut filtered) = (0, 0);
+    let mut started = source.last_seen;
+    for ((_, host, port, _), probe) in &source.probes {
+        *kinds.entry(probe.kind).or_insert(0) += 1;
+        ports.insert(*port);
+        hosts.insert(*host);
+        started = started.min(probe.time);
+        match probe.response {
+            Some(ProbeResponse::Open) => {
+                open.insert((*host, *port));
+            }
+            Some(ProbeResponse::Closed) => closed += 1,
+            Some(ProbeResponse::Filtered) => filtered += 1,
+            None => (),
+        }
+    }
+    let kind = kinds
+        .into_iter()
+        .max_by_key(|(kind, count)| (*count, std::cmp::Reverse(*kind)))
+        .map(|(kind, _)| kind)?;
+
+    Some(ScanEvent {
+        src_ip,
+        kind,
+        pattern,
+        ports: ports.into_iter().collect(),
+        hosts: hosts.into_iter().collect(),
+        open: open.into_iter().collect(),
+        closed,
+        filtered,
+        probes: source.probes.len(),
+        started,
+        ended: source.last_seen,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn at(seconds: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    fn host(n: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, n))
+    }
+
+    const SCANNER: IpAddr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+
+    #[test]
+    fn classifies_probe_flags() {
+        assert_eq!(ScanKind::from_tcp_flags(TCP_SYN), Some(ScanKind::Syn));
+        assert_eq!(ScanKind::from_tcp_flags(TCP_ACK), Some(ScanKind::Ack));
+        assert_eq!(ScanKind::from_tcp_flags(TCP_FIN), Some(ScanKind::Fin));
+        assert_eq!(ScanKind::from_tcp_flags(TCP_FIN | TCP_PSH | TCP_URG), Some(ScanKind::Xmas));
+        assert_eq!(ScanKind::from_tcp_flags(0), Some(ScanKind::Null));
+        assert_eq!(ScanKind::from_tcp_flags(TCP_PSH), None);
+        assert_eq!(ScanKind::from_tcp_flags(TCP_SYN | TCP_FIN), None);
+    }
+
+    #[test]
+    fn syn_port_scan_is_reported_when_idle() {
+        let mut detector = ScanDetector::new();
+        for port in 1..=PORT_SCAN_THRESHOLD as u16 {
+            detector.on_tcp_packet(&(SCANNER, 40000, host(1), port), TCP_SYN, true, at(0));
+        }
+        // 応答はサーバーからスキャナーへの向き
+        detector.on_tcp_packet(&(host(1), 2, SCANNER, 40000), TCP_SYN | TCP_ACK, false, at(0));
+        detector.on_tcp_packet(&(host(1), 3, SCANNER, 40000), TCP_RST | TCP_ACK, false, at(0));
+        detector.on_tcp_packet(&(host(1), 4, SCANNER, 40000), TCP_RST, false, at(0));
+
+        // 検知後は期間を過ぎてもプローブを保持し、アイドルになってから一度だけ通知する
+        detector.cleanup(at(29));
+        assert!(detector.take_events().is_empty());
+        detector.on_tcp_packet(&(SCANNER, 40000, host(1), 80), TCP_SYN, true, at(70));
+        detector.cleanup(at(99));
+        assert!(detector.take_events().is_empty());
+        detector.cleanup(at(100));
+        let events = detector.take_events();
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(
+            (event.src_ip, event.kind, event.pattern),
+            (SCANNER, ScanKind::Syn, ScanPattern::PortScan)
+        );
+        assert_eq!((event.ports.len(), event.hosts.as_slice()), (21, &[host(1)][..]));
+        assert_eq!(event.open, [(host(1), 2)]);
+        assert_eq!((event.closed, event.filtered, event.probes), (2, 0, 21));
+        assert_eq!(event.duration(), Duration::from_secs(70));
+        assert_eq!(detector.counters.scans, 1);
+
+        detector.finish();
+        assert!(detector.take_events().is_empty());
+    }
+
+    #[test]
+    fn udp_host_sweep_counts_icmp_responses() {
+        let mut detector = ScanDetector::new();
+        for n in 1..=HOST_SWEEP_THRESHOLD as u8 + 1 {
+            detector.on_udp_flow(&(SCANNER, 50000, host(n), 161), at(0));
+        }
+        let port_unreachable = IcmpMessage::PortUnreachable;
+        detector.on_icmp_error(&(SCANNER, 50000, host(1), 161), IPPROTO_UDP, &port_unreachable);
+        let unreachable = IcmpMessage::DestinationUnreachable { code: 13 };
+        detector.on_icmp_error(&(SCANNER, 50000, host(2), 161), IPPROTO_UDP, &unreachable);
+        // 応答のあったUDPフローは通常の通信としてプローブから除く
+        detector.on_data(&(host(3), 161, SCANNER, 50000), IPPROTO_UDP);
+
+        detector.finish();
+        let events = detector.take_events();
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!((event.kind, event.pattern), (ScanKind::Udp, ScanPattern::HostSweep));
+        assert_eq!(event.ports, [161]);
+        assert!(!event.hosts.contains(&host(3)));
+        assert_eq!((event.closed, event.filtered, event.probes), (1, 1, HOST_SWEEP_THRESHOLD));
+    }
+
+    // 1つのホストの閾値と同じ数のポートにハンドシェイクを完了させる
+    fn connect_to_ports(detector: &mut ScanDetector) {
+        for port in 1..=PORT_SCAN_THRESHOLD as u16 {
+            let key = (SCANNER, 40000 + port, host(1), port);
+            detector.on_tcp_packet(&key, TCP_SYN, true, at(0));
+            detector.on_established(&key);
+        }
+    }
+
+    #[test]
+    fn connections_with_data_are_not_probes() {
+        // ハンドシェイクを完了させてすぐに閉じた接続はconnectスキャンになる
+        let mut detector = ScanDetector::new();
+        connect_to_ports(&mut detector);
+        detector.finish();
+        let events = detector.take_events();
+        assert!(matches!(
+            events.as_slice(),
+            [ScanEvent { kind: ScanKind::Connect, pattern: ScanPattern::PortScan, .. }]
+        ));
+
+        // データを送受信した接続を除いて閾値を下回れば通知しない
+        let mut detector = ScanDetector::new();
+        connect_to_ports(&mut detector);
+        detector.on_data(&(host(1), 1, SCANNER, 40001), IPPROTO_TCP);
+        detector.finish();
+        assert!(detector.take_events().is_empty());
+    }
+
+    #[test]
+    fn probes_below_threshold_expire() {
+        let mut detector = ScanDetector::new();
+        for port in 1..PORT_SCAN_THRESHOLD as u16 {
+            detector.on_tcp_packet(&(SCANNER, 40000, host(1), port), TCP_FIN, true, at(0));
+        }
+        detector.cleanup(at(60));
+        detector.on_tcp_packet(&(SCANNER, 40000, host(1), 1000), TCP_FIN, true, at(60));
+        detector.finish();
+        assert!(detector.take_events().is_empty());
+        assert_eq!(detector.counters.scans, 0);
+    }
+}
@@ -1,8 +1,12 @@
-use pcap::{Active, Capture, Device};
+use crate::config::CaptureConfig;
+use crate::error::NidsError;
+use pcap::{Active, Capture, Device, Offline};
 use std::io;
 use std::io::Write;
+use std::path::Path;
 
-pub fn select_device() -> Result<(Capture<Active>, Device), Box<dyn std::error::Error>> {
+// 対話的にデバイスを選択する (インターフェースが指定されていない場合のフォールバック)
+pub fn select_device(config: &CaptureConfig) -> Result<(Capture<Active>, Device), NidsError> {
     let device_list = Device::list()?;
 
     println!("利用可能なデバイス:");
@@ -15,24 +19,54 @@ pub fn select_device() -> Result<(Capture<Active>, Device), Box<dyn std::error::
 
     let mut input = String::new();
     io::stdin().read_line(&mut input)?;
-    let device_index: usize = input.trim().parse()?;
-
-    if device_index == 0 || device_index > device_list.len() {
-        return Err("無効なデバイス番号です".into());
-    }
+    let device_index = match input.trim().parse::<usize>() {
+        Ok(index) if index >= 1 && index <= device_list.len() => index,
+        _ => return Err(NidsError::InvalidDeviceSelection(input.trim().to_string())),
+    };
 
     let selected_device = &device_list[device_index - 1];
     println!("選択されたデバイス: {}", selected_device.name);
 
-    let cap = Capture::from_device(selected_device.clone())?
-        .promisc(true)
-        .snaplen(65535)
-        .timeout(0)
+    let cap = open_capture(selected_device.clone(), config)?;
+
+    Ok((cap, selected_device.clone()))
+}
+
+// インターフェース名を指定してデバイスを開く
+pub fn open_device(name: &str, config: &CaptureConfig) -> Result<(Capture<Active>, Device), NidsError> {
+    let device = Device::list()?
+        .into_iter()
+        .find(|device| device.name == name)
+        .ok_or_else(|| NidsError::DeviceNotFound(name.to_string()))?;
+
+    let cap = open_capture(device.clone(), config)?;
+
+    Ok((cap, device))
+}
+
+fn open_capture(device: Device, config: &CaptureConfig) -> Result<Capture<Active>, NidsError> {
+    let cap = Capture::from_device(device)?
+        .promisc(config.promisc)
+        .snaplen(config.snaplen)
+        .timeout(config.timeout)
         .immediate_mode(true)
-        .buffer_size(3 * 1024 * 1024)
+        .buffer_size(config.buffer_size)
         .open()?;
 
     println!("パケットのキャプチャを開始します。Ctrl+Cで終了します。");
 
-    Ok((cap, selected_device.clone()))
+    Ok(cap)
+}
+
+// pcap/pcapngファイルを開く (形式はlibpcapが自動判別する)
+pub fn open_capture_file<P: AsRef<Path>>(path: P) -> Result<Capture<Offline>, NidsError> {
+    let path = path.as_ref();
+    if !path.is_file() {
+        return Err(NidsError::CaptureFileNotFound(path.to_path_buf()));
+    }
+
+    let cap = Capture::from_file(path)?;
+    println!("キャプチャファイルを読み込みます: {}", path.display());
+
+    Ok(cap)
 }
@@ -0,0 +1,50 @@
+use pcap::BreakLoop;
+use std::io;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+// SIGINTとSIGTERMを受け取ってキャプチャを停止する
+// シグナルハンドラーの中ではキャプチャを安全に止められないため、シグナルをブロックして専用のスレッドで待つ
+pub struct StopSignals {
+    set: libc::sigset_t,
+}
+
+impl StopSignals {
+    // このスレッドでSIGINTとSIGTERMをブロックする
+    // 以降に作成したスレッドにも引き継がれるため、書き込みなどのスレッドを作成する前に呼び出す
+    pub fn block() -> io::Result<Self> {
+        let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+        let set = unsafe {
+            libc::sigemptyset(set.as_mut_ptr());
+            libc::sigaddset(set.as_mut_ptr(), libc::SIGINT);
+            libc::sigaddset(set.as_mut_ptr(), libc::SIGTERM);
+            set.assume_init()
+        };
+        match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) } {
+            0 => Ok(StopSignals { set }),
+            e => Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+
+    // シグナルを受け取ったら停止を要求し、パケットを待っているキャプチャを起こす
+    // 終了処理が進まない場合のため、2回目のシグナルでは終了処理を待たずに終了する
+    pub fn stop_on_signal(self, break_loop: BreakLoop) -> Arc<AtomicBool> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let requested = Arc::clone(&stop);
+        thread::spawn(move || loop {
+            let mut signal = 0;
+            if unsafe { libc::sigwait(&self.set, &mut signal) } != 0 {
+                return;
+            }
+            if requested.swap(true, Ordering::SeqCst) {
+                std::process::exit(128 + signal);
+            }
+            eprintln!("シグナルを受け取りました。キャプチャを停止して統計を出力します (もう一度で即座に終了します)");
+            break_loop.breakloop();
+        });
+        stop
+    }
+}
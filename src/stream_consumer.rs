@@ -0,0 +1,75 @@
+use crate::icmp_header::IcmpMessage;
+use crate::tcp_stream::{StreamChunk, TcpStream, TcpStreamKey};
+use crate::udp_flow::{UdpFlow, UdpFlowKey};
+
+// ストリームの追跡を終了した理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    Closed,   // FINまたはRSTにより接続が終了した (TCPのみ)
+    Timeout,  // 通信がなくタイムアウトした
+    Evicted,  // ストリーム(フロー)数またはメモリの上限により破棄した
+    Shutdown, // キャプチャの終了時に残っていた
+}
+
+// 再構築したTCPストリームを受け取るコンシューマー
+// アプリケーション層の解析や検知、書き出しを実装して登録する
+// データは連続した部分が揃うたびに少しずつ渡される
+pub trait StreamConsumer {
+    // ストリームの追跡を開始した
+    fn on_open(&mut self, _key: &TcpStreamKey, _stream: &TcpStream) {}
+
+    // クライアントからサーバーへのデータが新たに連続した
+    fn on_client_data(&mut self, _key: &TcpStreamKey, _stream: &TcpStream, _data: &[u8]) {}
+
+    // サーバーからクライアントへのデータが新たに連続した
+    fn on_server_data(&mut self, _key: &TcpStreamKey, _stream: &TcpStream, _data: &[u8]) {}
+
+    // キャプチャで取りこぼしたデータを読み飛ばした
+    fn on_gap(&mut self, _key: &TcpStreamKey, _stream: &TcpStream, _from_client: bool, _seq: u32, _length: u32) {}
+
+    // ストリームのセグメントを引用したICMPエラー(ポート到達不能、フラグメント要求など)を受信した
+    // from_clientは引用されたセグメントをクライアントが送ったかどうか
+    fn on_icmp_error(&mut self, _key: &TcpStreamKey, _stream: &TcpStream, _from_client: bool, _message: &IcmpMessage) {}
+
+    // ストリームの追跡を終了した
+    fn on_close(&mut self, _key: &TcpStreamKey, _stream: &TcpStream, _reason: CloseReason) {}
+}
+
+// UDPフローを受け取るコンシューマー (DNS、DHCP、NTP、QUICなどの解析を実装して登録する)
+// データグラムごとにペイロードが渡される
+pub trait UdpConsumer {
+    // フローの追跡を開始した
+    fn on_open(&mut self, _key: &UdpFlowKey, _flow: &UdpFlow) {}
+
+    // クライアントからサーバーへのデータグラム
+    fn on_client_data(&mut self, _key: &UdpFlowKey, _flow: &UdpFlow, _data: &[u8]) {}
+
+    // サーバーからクライアントへのデータグラム
+    fn on_server_data(&mut self, _key: &UdpFlowKey, _flow: &UdpFlow, _data: &[u8]) {}
+
+    // フローのデータグラムを引用したICMPエラーを受信した
+    fn on_icmp_error(&mut self, _key: &UdpFlowKey, _flow: &UdpFlow, _from_client: bool, _message: &IcmpMessage) {}
+
+    // フローの追跡を終了した
+    fn on_close(&mut self, _key: &UdpFlowKey, _flow: &UdpFlow, _reason: CloseReason) {}
+}
+
+// 登録されたコンシューマーにデータと欠落を順に渡す
+pub fn deliver_chunks(
+    consumers: &mut [Box<dyn StreamConsumer>],
+    key: &TcpStreamKey,
+    stream: &TcpStream,
+    chunks: &[StreamChunk],
+) {
+    for chunk in chunks {
+        for consumer in consumers.iter_mut() {
+            match chunk {
+                StreamChunk::Data { from_client: true, data } => consumer.on_client_data(key, stream, data),
+                StreamChunk::Data { from_client: false, data } => consumer.on_server_data(key, stream, data),
+                StreamChunk::Gap { from_client, seq, length } => {
+                    consumer.on_gap(key, stream, *from_client, *seq, *length)
+                }
+            }
+        }
+    }
+}
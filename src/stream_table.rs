@@ -0,0 +1,275 @@
+use crate::capture_clock::elapsed_between;
+use crate::stream_consumer::CloseReason;
+use crate::tcp_stream::{TcpStream, TcpStreamKey, TIME_WAIT_DURATION};
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, SystemTime};
+
+// ストリームテーブル全体のメモリ上限
+#[derive(Debug, Clone, Copy)]
+pub struct StreamTableLimits {
+    pub max_streams: usize, // 同時に追跡するストリーム数
+    pub memcap: usize,      // 全ストリームで保持するデータのバイト数
+}
+
+impl Default for StreamTableLimits {
+    fn default() -> Self {
+        StreamTableLimits {
+            max_streams: 65536,
+            memcap: 256 * 1024 * 1024,
+        }
+    }
+}
+
+// ストリームを破棄した理由ごとの件数 (上限の調整に使う)
+#[derive(Debug, Default, Clone)]
+pub struct StreamEvictionCounters {
+    pub max_streams: u64,          // ストリーム数の上限により破棄した数
+    pub memcap: u64,               // メモリ上限により破棄した数
+    pub expired: u64,              // タイムアウトまたはクローズにより破棄した数
+    pub ooo_dropped_segments: u64, // キューの上限により破棄した順序外セグメント数 (削除済みのストリームの合計)
+    pub truncated_bytes: u64,      // depthを超えたため保持しなかったバイト数 (削除済みのストリームの合計)
+}
+
+// 追跡中のストリームと、テーブルの使用量に計上済みのバイト数
+struct TrackedStream {
+    stream: TcpStream,
+    accounted_bytes: usize,
+    indexed_activity: SystemTime, // 破棄の順序の索引に登録済みの最終通信時刻
+}
+
+// TCPストリームのテーブル
+// 上限に達した場合は最終通信時刻が最も古いストリームから破棄する
+pub struct StreamTable {
+    streams: HashMap<TcpStreamKey, TrackedStream>,
+    by_activity: BTreeSet<(SystemTime, TcpStreamKey)>, // 最終通信時刻順の索引 (破棄するストリームを全体を走査せずに選ぶ)
+    limits: StreamTableLimits,
+    memory_usage: usize,
+    closed: Vec<(TcpStreamKey, TcpStream, CloseReason)>, // コンシューマーに未通知の削除したストリーム
+    pub counters: StreamEvictionCounters,
+}
+
+impl StreamTable {
+    pub fn new(limits: StreamTableLimits) -> Self {
+        StreamTable {
+            streams: HashMap::new(),
+            by_activity: BTreeSet::new(),
+            limits,
+            memory_usage: 0,
+            closed: Vec::new(),
+            counters: StreamEvictionCounters::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage
+    }
+
+    pub fn contains_key(&self, key: &TcpStreamKey) -> bool {
+        self.streams.contains_key(key)
+    }
+
+    pub fn get_mut(&mut self, key: &TcpStreamKey) -> Option<&mut TcpStream> {
+        self.streams.get_mut(key).map(|tracked| &mut tracked.stream)
+    }
+
+    // ストリームを追加する (ストリーム数が上限に達している場合は最も古いストリームを破棄する)
+    pub fn insert(&mut self, key: TcpStreamKey, stream: TcpStream) {
+        while self.streams.len() >= self.limits.max_streams && !self.streams.contains_key(&key) {
+            if !self.evict_oldest(None) {
+                break;
+            }
+            self.counters.max_streams += 1;
+        }
+
+        let accounted_bytes = stream.memory_usage();
+        let indexed_activity = stream.last_activity;
+        self.memory_usage += accounted_bytes;
+        self.by_activity.insert((indexed_activity, key));
+        let tracked = TrackedStream {
+            stream,
+            accounted_bytes,
+            indexed_activity,
+        };
+        if let Some(previous) = self.streams.insert(key, tracked) {
+            self.memory_usage -= previous.accounted_bytes;
+            if previous.indexed_activity != indexed_activity {
+                self.by_activity.remove(&(previous.indexed_activity, key));
+            }
+        }
+        self.enforce_memcap(&key);
+    }
+
+    // 接続が終了したストリームを削除する
+    pub fn close(&mut self, key: &TcpStreamKey) {
+        self.remove(key, CloseReason::Closed);
+    }
+
+    // キャプチャの終了時に残っているストリームをすべて削除する
+    pub fn close_all(&mut self) {
+        let keys: Vec<TcpStreamKey> = self.streams.keys().copied().collect();
+        for key in keys {
+            self.remove(&key, CloseReason::Shutdown);
+        }
+    }
+
+    // 削除したストリームを取り出す (コンシューマーへの通知に使う)
+    pub fn take_closed(&mut self) -> Vec<(TcpStreamKey, TcpStream, CloseReason)> {
+        std::mem::take(&mut self.closed)
+    }
+
+    fn remove(&mut self, key: &TcpStreamKey, reason: CloseReason) -> bool {
+        let tracked = match self.streams.remove(key) {
+            Some(tracked) => tracked,
+            None => return false,
+        };
+        self.memory_usage -= tracked.accounted_bytes;
+        self.by_activity.remove(&(tracked.indexed_activity, *key));
+        self.counters.ooo_dropped_segments += tracked.stream.ooo_dropped_segments;
+        self.counters.truncated_bytes += tracked.stream.truncated_bytes;
+        self.closed.push((*key, tracked.stream, reason));
+        true
+    }
+
+    // ストリームを更新した後に使用量と最終通信時刻を計上し直し、メモリ上限を超えていれば古いストリームを破棄する
+    pub fn refresh(&mut self, key: &TcpStreamKey) {
+        if let Some(tracked) = self.streams.get_mut(key) {
+            let usage = tracked.stream.memory_usage();
+            self.memory_usage = self.memory_usage - tracked.accounted_bytes + usage;
+            tracked.accounted_bytes = usage;
+            if tracked.stream.last_activity != tracked.indexed_activity {
+                self.by_activity.remove(&(tracked.indexed_activity, *key));
+                tracked.indexed_activity = tracked.stream.last_activity;
+                self.by_activity.insert((tracked.indexed_activity, *key));
+            }
+        }
+        self.enforce_memcap(key);
+    }
+
+    // キャプチャ時刻nowの時点で不要になったストリームを破棄する
+    // 最終通信時刻の古い順に、破棄しうる期間(idle_timeoutとTIME_WAITの短い方)を過ぎたストリームだけを調べる
+    // (クローズしたストリームはパケットの処理時に削除している)
+    pub fn remove_expired(&mut self, now: SystemTime, idle_timeout: Duration) {
+        let threshold = idle_timeout.min(TIME_WAIT_DURATION);
+        let candidates: Vec<TcpStreamKey> = self
+            .by_activity
+            .iter()
+            .take_while(|(last_activity, _)| elapsed_between(*last_activity, now) >= threshold)
+            .map(|(_, key)| *key)
+            .collect();
+        for key in candidates {
+            if self.streams.get(&key).is_some_and(|tracked| tracked.stream.is_expired(now, idle_timeout)) {
+                self.remove(&key, CloseReason::Timeout);
+                self.counters.expired += 1;
+            }
+        }
+    }
+
+    // メモリ上限を超えている間、最も古いストリームを破棄する
+    // 現在処理中のストリームは最後に破棄する
+    fn enforce_memcap(&mut self, current: &TcpStreamKey) {
+        while self.memory_usage > self.limits.memcap {
+            if !self.evict_oldest(Some(current)) && !self.remove(current, CloseReason::Evicted) {
+                break;
+            }
+            self.counters.memcap += 1;
+        }
+    }
+
+    // 最終通信時刻が最も古いストリームを破棄する
+    fn evict_oldest(&mut self, exclude: Option<&TcpStreamKey>) -> bool {
+        let oldest = self
+            .by_activity
+            .iter()
+            .map(|(_, key)| *key)
+            .find(|key| Some(key) != exclude);
+        match oldest {
+            Some(key) => self.remove(&key, CloseReason::Evicted),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tcp_reassembly::{ReassemblyPolicy, StreamLimits};
+    use crate::tcp_stream::TcpState;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn key(port: u16) -> TcpStreamKey {
+        (IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), port, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)), 80)
+    }
+
+    fn at(seconds: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    fn stream(now: SystemTime) -> TcpStream {
+        TcpStream::new_midstream(1000, 5000, now, ReassemblyPolicy::First, StreamLimits::default())
+    }
+
+    fn closed_ports(table: &mut StreamTable) -> Vec<(u16, CloseReason)> {
+        table.take_closed().into_iter().map(|(key, _, reason)| (key.1, reason)).collect()
+    }
+
+    #[test]
+    fn evicts_least_recently_active_stream() {
+        let mut table = StreamTable::new(StreamTableLimits {
+            max_streams: 2,
+            ..StreamTableLimits::default()
+        });
+        table.insert(key(1), stream(at(0)));
+        table.insert(key(2), stream(at(1)));
+        // 最初のストリームに通信があった
+        table.get_mut(&key(1)).unwrap().last_activity = at(2);
+        table.refresh(&key(1));
+        table.insert(key(3), stream(at(3)));
+        assert_eq!(closed_ports(&mut table), [(2, CloseReason::Evicted)]);
+        assert!(table.contains_key(&key(1)) && table.contains_key(&key(3)));
+        assert_eq!(table.counters.max_streams, 1);
+    }
+
+    #[test]
+    fn memcap_evicts_other_streams_first() {
+        let mut table = StreamTable::new(StreamTableLimits {
+            max_streams: 10,
+            memcap: 100,
+        });
+        table.insert(key(1), stream(at(0)));
+        table.insert(key(2), stream(at(1)));
+        table.get_mut(&key(1)).unwrap().client_data = vec![0; 60];
+        table.refresh(&key(1));
+        table.get_mut(&key(2)).unwrap().client_data = vec![0; 60];
+        table.refresh(&key(2));
+        assert_eq!(closed_ports(&mut table), [(1, CloseReason::Evicted)]);
+        assert_eq!(table.memory_usage(), 60);
+        assert_eq!(table.counters.memcap, 1);
+    }
+
+    #[test]
+    fn removes_only_expired_streams() {
+        let idle_timeout = Duration::from_secs(60);
+        let mut table = StreamTable::new(StreamTableLimits::default());
+        table.insert(key(1), stream(at(0)));
+        table.insert(key(2), stream(at(10)));
+        let mut time_wait = stream(at(0));
+        time_wait.state = TcpState::TimeWait;
+        table.insert(key(3), time_wait);
+
+        table.remove_expired(at(65), idle_timeout);
+        assert_eq!(closed_ports(&mut table), [(1, CloseReason::Timeout)]);
+        // TIME_WAITは2MSLが過ぎるまで残す
+        table.remove_expired(at(121), idle_timeout);
+        assert_eq!(closed_ports(&mut table), [(3, CloseReason::Timeout), (2, CloseReason::Timeout)]);
+        assert!(table.is_empty());
+        assert_eq!(table.counters.expired, 3);
+    }
+}
@@ -0,0 +1,532 @@
+use crate::capture_clock::elapsed_between;
+use crate::tcp_stream::TcpStreamKey;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+// 宛先ごとに記録する送信元の上限 (偽装された送信元で記録が膨らまないようにする)
+const MAX_TRACKED_SOURCES: usize = 65536;
+
+// ハンドシェイクの完了を待つ接続の上限
+const MAX_HALF_OPEN: usize = 262144;
+
+// SYNフラッドと半開きの接続の検知の設定
+#[derive(Debug, Clone, Copy)]
+pub struct SynFloodConfig {
+    pub window: Duration,                 // SYNを数える期間
+    pub rate_threshold: u64,              // 1つの宛先が期間内にこの数を超えるSYNを受け取ったらフラッドとみなす
+    pub spoofed_sources: usize,           // この数以上の送信元からのフラッドを送信元の偽装とみなす
+    pub half_open_threshold: usize,       // 1つの宛先への半開きの接続の上限
+    pub source_half_open_threshold: usize, // 1つの送信元からの半開きの接続の上限
+    pub half_open_timeout: Duration,      // ハンドシェイクが完了しない接続を半開きとして保持する時間
+}
+
+impl Default for SynFloodConfig {
+    fn default() -> Self {
+        SynFloodConfig {
+            window: Duration::from_secs(1),
+            rate_threshold: 1000,
+            spoofed_sources: 100,
+            half_open_threshold: 500,
+            source_half_open_threshold: 200,
+            half_open_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+// SYNフラッドの検知イベント
+#[derive(Debug, Clone)]
+pub enum SynFloodEvent {
+    // 1つの宛先への大量のSYN (攻撃の開始時に一度だけ通知する)
+    SynFlood {
+        dst_ip: IpAddr,
+        syns: u64,
+        window: Duration,
+        sources: usize,
+        half_open: usize,
+        spoofed: bool, // 多数の送信元からのSYN (送信元の偽装の疑い)
+    },
+    // SYNフラッドの終了 (攻撃全体の集計)
+    SynFloodEnded {
+        dst_ip: IpAddr,
+        duration: Duration,
+        syns: u64,
+        sources: usize,
+        peak_syns: u64, // 期間あたりのSYN数の最大値
+        window: Duration,
+    },
+    // 1つの宛先への半開きの接続が上限を超えた
+    HalfOpenTarget {
+        dst_ip: IpAddr,
+        half_open: usize,
+        sources: usize,
+    },
+    // 1つの送信元からの半開きの接続が上限を超えた
+    HalfOpenSource {
+        src_ip: IpAddr,
+        half_open: usize,
+        targets: usize,
+    },
+}
+
+impl SynFloodEvent {
+    // 攻撃の対象 (半開きの接続が多い送信元の場合は送信元) のアドレス
+    pub fn host(&self) -> IpAddr {
+        match self {
+            SynFloodEvent::SynFlood { dst_ip, .. }
+            | SynFloodEvent::SynFloodEnded { dst_ip, .. }
+            | SynFloodEvent::HalfOpenTarget { dst_ip, .. } => *dst_ip,
+            SynFloodEvent::HalfOpenSource { src_ip, .. } => *src_ip,
+        }
+    }
+
+    // イベントの種類を表す名前 (構造化した出力に使う)
+    pub fn name(&self) -> &'static str {
+        match self {
+            SynFloodEvent::SynFlood { spoofed: false, .. } => "syn_flood",
+            SynFloodEvent::SynFlood { spoofed: true, .. } => "syn_flood_spoofed",
+            SynFloodEvent::SynFloodEnded { .. } => "syn_flood_ended",
+            SynFloodEvent::HalfOpenTarget { .. } => "half_open_target",
+            SynFloodEvent::HalfOpenSource { .. } => "half_open_source",
+        }
+    }
+}
+
+impl fmt::Display for SynFloodEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SynFloodEvent::SynFlood { dst_ip, syns, window, sources, half_open, spoofed } => write!(
+                f,
+                "SYN flood{} ({} received {} SYNs within {:?} from {} sources, half_open={})",
+                if *spoofed { " with spoofed sources" } else { "" },
+                dst_ip, syns, window, sources, half_open
+            ),
+            SynFloodEvent::SynFloodEnded { dst_ip, duration, syns, sources, peak_syns, window } => write!(
+                f,
+                "SYN flood ended ({} received {} SYNs from {} sources over {:?}, peak {} SYNs per {:?})",
+                dst_ip, syns, sources, duration, peak_syns, window
+            ),
+            SynFloodEvent::HalfOpenTarget { dst_ip, half_open, sources } => write!(
+                f,
+                "too many half-open connections ({} has {} half-open connections from {} sources)",
+                dst_ip, half_open, sources
+            ),
+            SynFloodEvent::HalfOpenSource { src_ip, half_open, targets } => write!(
+                f,
+                "too many half-open connections ({} has {} half-open connections to {} hosts)",
+                src_ip, half_open, targets
+            ),
+        }
+    }
+}
+
+// 宛先ごとのSYNの集計
+struct TargetCounter {
+    window_start: SystemTime,
+    syns: u64,                // 現在の期間のSYN数
+    sources: HashSet<IpAddr>, // 現在の期間の送信元
+    alerted: bool,            // 現在の期間で閾値を超えたか
+    attack: Option<Attack>,
+}
+
+// 継続中のSYNフラッド
+struct Attack {
+    started: SystemTime,
+    last_seen: SystemTime,
+    syns: u64,
+    sources: HashSet<IpAddr>,
+    peak_syns: u64,
+}
+
+// 半開きの接続の数 (宛先ごと、送信元ごと)
+#[derive(Default)]
+struct HalfOpenCounter {
+    count: usize,
+    alerted: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct SynFloodCounters {
+    pub syns: u64,
+    pub completed: u64, // ハンドシェイクが完了した接続
+    pub expired: u64,   // ハンドシェイクが完了しないままタイムアウトした接続
+    pub untracked: u64, // 上限により追跡しなかった接続
+    pub attacks: u64,
+}
+
+// 半開きの接続を追跡し、SYNフラッドを検知する
+// パケットごとではなく、攻撃の開始と終了、上限を超えたときに集計したイベントを出力する
+pub struct SynFloodDetector {
+    config: SynFloodConfig,
+    half_open: HashMap<TcpStreamKey, SystemTime>, // ハンドシェイクを待つ接続と最初のSYNの時刻
+    targets: HashMap<IpAddr, TargetCounter>,
+    half_open_by_dst: HashMap<IpAddr, HalfOpenCounter>,
+    half_open_by_src: HashMap<IpAddr, HalfOpenCounter>,
+    events: Vec<SynFloodEvent>, // 未出力のイベント
+    pub counters: SynFloodCounters,
+}
+
+impl Default for SynFloodDetector {
+    fn default() -> Self {
+        SynFloodDetector::new(SynFloodConfig::default())
+    }
+}
+
+impl SynFloodDetector {
+    pub fn new(config: SynFloodConfig) -> Self {
+        SynFloodDetector {
+            config,
+            half_open: HashMap::new(),
+            targets: HashMap::new(),
+            half_open_by_dst: HashMap::new(),
+            half_open_by_src: HashMap::new(),
+            events: Vec::new(),
+            counters: SynFloodCounters::default(),
+        }
+    }
+
+    // 現在の半開きの接続数
+    pub fn half_open(&self) -> usize {
+        self.half_open.len()
+    }
+
+    // クライアントからのSYN (keyはクライアント, サーバーの順)
+    pub fn on_syn(&mut self, key: &TcpStreamKey, now: SystemTime) {
+        self.counters.syns += 1;
+        let (src_ip, dst_ip) = (key.0, key.2);
+        self.count_syn(src_ip, dst_ip, now);
+
+        // 再送されたSYNは新しい接続として数えない
+        if self.half_open.contains_key(key) {
+            return;
+        }
+        if self.half_open.len() >= MAX_HALF_OPEN {
+            self.counters.untracked += 1;
+            return;
+        }
+        self.half_open.insert(*key, now);
+
+        let config = self.config;
+        let dst = self.half_open_by_dst.entry(dst_ip).or_default();
+        dst.count += 1;
+        if dst.count > config.half_open_threshold && !dst.alerted {
+            dst.alerted = true;
+            let half_open = dst.count;
+            let sources = self.distinct_peers(dst_ip, |key| (key.2, key.0));
+            self.events.push(SynFloodEvent::HalfOpenTarget { dst_ip, half_open, sources });
+        }
+        let src = self.half_open_by_src.entry(src_ip).or_default();
+        src.count += 1;
+        if src.count > config.source_half_open_threshold && !src.alerted {
+            src.alerted = true;
+            let half_open = src.count;
+            let targets = self.distinct_peers(src_ip, |key| (key.0, key.2));
+            self.events.push(SynFloodEvent::HalfOpenSource { src_ip, half_open, targets });
+        }
+    }
+
+    // ハンドシェイクが完了した、またはRSTやFINで終了した接続を半開きの接続から除く
+    pub fn on_resolved(&mut self, key: &TcpStreamKey, completed: bool) {
+        if self.half_open.remove(key).is_some() {
+            if completed {
+                self.counters.completed += 1;
+            }
+            self.release(key);
+        }
+    }
+
+    // 宛先ごとのSYN数と送信元を数え、閾値を超えたら攻撃の開始を一度だけ通知する
+    fn count_syn(&mut self, src_ip: IpAddr, dst_ip: IpAddr, now: SystemTime) {
+        let config = self.config;
+        let counter = self.targets.entry(dst_ip).or_insert(TargetCounter {
+            window_start: now,
+            syns: 0,
+            sources: HashSet::new(),
+            alerted: false,
+            attack: None,
+        });
+        if elapsed_between(counter.window_start, now) >= config.window {
+            roll_window(dst_ip, counter, now, &config, &mut self.events);
+        }
+
+        counter.syns += 1;
+        if counter.sources.len() < MAX_TRACKED_SOURCES {
+            counter.sources.insert(src_ip);
+        }
+        if counter.syns <= config.rate_threshold || counter.alerted {
+            return;
+        }
+        counter.alerted = true;
+        if counter.attack.is_some() {
+            return;
+        }
+
+        // 期間内の送信元がほとんど重複しない場合は送信元の偽装とみなす
+        let sources = counter.sources.len();
+        let spoofed = sources >= config.spoofed_sources && sources as u64 * 2 >= counter.syns;
+        counter.attack = Some(Attack {
+            started: counter.window_start,
+            last_seen: now,
+            syns: 0,
+            sources: HashSet::new(),
+            peak_syns: 0,
+        });
+        self.counters.attacks += 1;
+        let half_open = self.half_open_by_dst.get(&dst_ip).map_or(0, |counter| counter.count);
+        self.events.push(SynFloodEvent::SynFlood {
+            dst_ip,
+            syns: counter.syns,
+            window: config.window,
+            sources,
+            half_open,
+            spoofed,
+        });
+    }
+
+    // 半開きの接続の相手の数 (peerは接続のキーから(自分, 相手)を取り出す)
+    fn distinct_peers(&self, ip: IpAddr, peer: impl Fn(&TcpStreamKey) -> (IpAddr, IpAddr)) -> usize {
+        self.half_open
+            .keys()
+            .map(&peer)
+            .filter(|(own, _)| *own == ip)
+            .map(|(_, peer)| peer)
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    // 半開きの接続数を減らし、上限の半分を下回ったら再び通知できるようにする
+    fn release(&mut self, key: &TcpStreamKey) {
+        let config = self.config;
+        for (counters, ip, threshold) in [
+            (&mut self.half_open_by_dst, key.2, config.half_open_threshold),
+            (&mut self.half_open_by_src, key.0, config.source_half_open_threshold),
+        ] {
+            if let Some(counter) = counters.get_mut(&ip) {
+                counter.count -= 1;
+                if counter.count <= threshold / 2 {
+                    counter.alerted = false;
+                }
+                if counter.count == 0 {
+                    counters.remove(&ip);
+                }
+            }
+        }
+    }
+
+    // 未出力のイベントを取り出す
+    pub fn take_events(&mut self) -> Vec<SynFloodEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    // キャプチャ時刻nowを基準に、ハンドシェイクが完了しない接続と期間の過ぎた集計を削除する
+    // SYNが途絶えた宛先の攻撃はここで終了を通知する
+    pub fn cleanup(&mut self, now: SystemTime) {
+        let timeout = self.config.half_open_timeout;
+        let expired: Vec<TcpStreamKey> = self
+            .half_open
+            .iter()
+            .filter(|(_, first_syn)| elapsed_between(**first_syn, now) >= timeout)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            self.half_open.remove(&key);
+            self.counters.expired += 1;
+            self.release(&key);
+        }
+
+        let config = self.config;
+        let events = &mut self.events;
+        self.targets.retain(|dst_ip, counter| {
+            if elapsed_between(counter.window_start, now) < config.window {
+                return true;
+            }
+            // 攻撃が続いている宛先のみ残す
+            roll_window(*dst_ip, counter, now, &config, events);
+            counter.attack.is_some()
+        });
+    }
+
+    // キャプチャの終了時に継続中の攻撃の集計を通知する
+    pub fn finish(&mut self, now: SystemTime) {
+        for (dst_ip, counter) in self.targets.iter_mut() {
+            if counter.alerted {
+                add_window(counter, now);
+            }
+            if let Some(attack) = counter.attack.take() {
+                self.events.push(attack_ended(*dst_ip, attack, self.config.window));
+            }
+        }
+    }
+}
+
+// 期間を進める (閾値を超えた期間は攻撃に加え、超えなかった場合は攻撃の終了を通知する)
+fn roll_window(
+    dst_ip: IpAddr,
+    counter: &mut TargetCounter,
+    now: SystemTime,
+    config: &SynFloodConfig,
+    events: &mut Vec<SynFloodEvent>,
+) {
+    if counter.alerted {
+        add_window(counter, counter.window_start + config.window);
+    } else if let Some(attack) = counter.attack.take() {
+        events.push(attack_ended(dst_ip, attack, config.window));
+    }
+    counter.window_start = now;
+    counter.syns = 0;
+    counter.sources.clear();
+    counter.alerted = false;
+}
+
+// 現在の期間の集計を攻撃に加える
+fn add_window(counter: &mut TargetCounter, last_seen: SystemTime) {
+    if let Some(attack) = &mut counter.attack {
+        attack.last_seen = last_seen;
+        attack.syns += counter.syns;
+        attack.peak_syns = attack.peak_syns.max(counter.syns);
+        for source in counter.sources.drain() {
+            if attack.sources.len() >= MAX_TRACKED_SOURCES {
+                break;
+            }
+            attack.sources.insert(source);
+        }
+    }
+}
+
+fn attack_ended(dst_ip: IpAddr, attack: Attack, window: Duration) -> SynFloodEvent {
+    SynFloodEvent::SynFloodEnded {
+        dst_ip,
+        duration: elapsed_between(attack.started, attack.last_seen),
+        syns: attack.syns,
+        sources: attack.sources.len(),
+        peak_syns: attack.peak_syns,
+        window,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn at(seconds: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    fn key(src: u8, src_port: u16) -> TcpStreamKey {
+        (
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, src)),
+            src_port,
+            IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)),
+            80,
+        )
+    }
+
+    fn detector() -> SynFloodDetector {
+        SynFloodDetector::new(SynFloodConfig {
+            rate_threshold: 10,
+            spoofed_sources: 5,
+            half_open_threshold: 2,
+            source_half_open_threshold: 100,
+            ..SynFloodConfig::default()
+        })
+    }
+
+    fn names(detector: &mut SynFloodDetector) -> Vec<&'static str> {
+        detector.take_events().iter().map(SynFloodEvent::name).collect()
+    }
+
+    #[test]
+    fn flood_from_many_sources_is_reported_once_until_it_ends() {
+        let mut detector = SynFloodDetector::new(SynFloodConfig {
+            rate_threshold: 10,
+            spoofed_sources: 5,
+            ..SynFloodConfig::default()
+        });
+        for src in 1..=12 {
+            detector.on_syn(&key(src, 40000), at(0));
+        }
+        // 閾値を超えたときに一度だけ通知する
+        assert_eq!(names(&mut detector), vec!["syn_flood_spoofed"]);
+        assert_eq!(detector.counters.attacks, 1);
+
+        // 閾値を超えた期間は攻撃に加え、超えなかった期間で終了を通知する
+        detector.cleanup(at(1));
+        assert!(detector.take_events().is_empty());
+        detector.cleanup(at(2));
+        let events = detector.take_events();
+        assert!(matches!(
+            events.as_slice(),
+            [SynFloodEvent::SynFloodEnded { syns: 12, sources: 12, peak_syns: 12, .. }]
+        ));
+    }
+
+    #[test]
+    fn flood_from_one_source_is_not_spoofed() {
+        let mut detector = SynFloodDetector::new(SynFloodConfig {
+            rate_threshold: 10,
+            spoofed_sources: 5,
+            ..SynFloodConfig::default()
+        });
+        for port in 0..11 {
+            detector.on_syn(&key(1, 40000 + port), at(0));
+        }
+        assert_eq!(names(&mut detector), vec!["syn_flood"]);
+
+        // 終了していない攻撃は終了時に集計する
+        detector.finish(at(0));
+        assert_eq!(names(&mut detector), vec!["syn_flood_ended"]);
+    }
+
+    #[test]
+    fn half_open_connections_are_resolved_or_expired() {
+        let mut detector = detector();
+        for src in 1..=3 {
+            detector.on_syn(&key(src, 40000), at(0));
+        }
+        // 再送されたSYNは新しい接続として数えない
+        detector.on_syn(&key(1, 40000), at(1));
+        assert_eq!(detector.half_open(), 3);
+        let events = detector.take_events();
+        assert!(matches!(events.as_slice(), [SynFloodEvent::HalfOpenTarget { half_open: 3, sources: 3, .. }]));
+
+        // 同じ接続の解決は一度だけ数える
+        detector.on_resolved(&key(1, 40000), true);
+        detector.on_resolved(&key(1, 40000), true);
+        detector.on_resolved(&key(2, 40000), false);
+        assert_eq!(detector.counters.completed, 1);
+        assert_eq!(detector.half_open(), 1);
+
+        detector.cleanup(at(29));
+        assert_eq!(detector.half_open(), 1);
+        detector.cleanup(at(30));
+        assert_eq!(detector.half_open(), 0);
+        assert_eq!(detector.counters.expired, 1);
+    }
+
+    #[test]
+    fn half_open_alert_rearms_after_count_drops() {
+        let mut detector = detector();
+        for src in 1..=3 {
+            detector.on_syn(&key(src, 40000), at(0));
+        }
+        detector.on_syn(&key(4, 40000), at(0));
+        assert_eq!(names(&mut detector), vec!["half_open_target"]);
+
+        // 上限の半分以下に減るまでは再び通知しない
+        detector.on_resolved(&key(4, 40000), true);
+        detector.on_resolved(&key(3, 40000), true);
+        detector.on_syn(&key(3, 40000), at(1));
+        detector.on_syn(&key(4, 40000), at(1));
+        assert!(detector.take_events().is_empty());
+
+        for src in 2..=4 {
+            detector.on_resolved(&key(src, 40000), true);
+        }
+        for src in 2..=4 {
+            detector.on_syn(&key(src, 40001), at(2));
+        }
+        assert_eq!(names(&mut detector), vec!["half_open_target"]);
+    }
+}
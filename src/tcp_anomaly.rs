@@ -0,0 +1,390 @@
+use crate::capture_clock::elapsed_between;
+use crate::tcp_stream::{TCP_ACK, TCP_FIN, TCP_PSH, TCP_RST, TCP_SYN, TCP_URG};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+// 同じ送信元の同じ種類の異常はこの期間にこの数まで通知し、以降は件数だけを数える
+const ANOMALY_ALERT_INTERVAL: Duration = Duration::from_secs(60);
+const ANOMALY_ALERT_LIMIT: u32 = 5;
+
+// 通知の回数を記録する(送信元, 種類)の上限 (超えた場合は新しい組み合わせを通知しない)
+const MAX_RATE_ENTRIES: usize = 65536;
+
+// 通知しなかった件数が残っている記録を次の通知のために保持する期間
+const SUPPRESSED_RETENTION: Duration = Duration::from_secs(600);
+
+// 固定部分のヘッダー長 (データオフセットの単位は4バイト)
+const TCP_MIN_HEADER_SIZE: usize = 20;
+const TCP_MIN_DATA_OFFSET: u8 = 5;
+
+// データオフセットの下位にある予約ビット (0x01はAccurate ECNが使うため含めない)
+const TCP_RESERVED_BITS: u8 = 0x0E;
+
+const TCP_ALL_FLAGS: u8 = TCP_FIN | TCP_SYN | TCP_RST | TCP_PSH | TCP_ACK | TCP_URG;
+
+// TCPヘッダーの異常の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TcpAnomalyKind {
+    SynFin,
+    SynRst,
+    NullFlags,
+    AllFlags,
+    HeaderTooShort,      // データオフセットが5未満
+    HeaderBeyondPacket,  // データオフセットがセグメントの長さを超える
+    UrgentPointerZero,   // URGが立っているのに緊急ポインタが0
+    UrgentWithoutFlag,   // URGが立っていないのに緊急ポインタが0以外
+    UrgentBeyondPayload, // 緊急ポインタがペイロードを超える (ペイロードがない場合を含む)
+    ReservedBits,
+    ZeroPort,
+    Land, // 送信元と宛先のアドレスとポートが同じ
+}
+
+impl TcpAnomalyKind {
+    pub const ALL: [TcpAnomalyKind; 12] = [
+        TcpAnomalyKind::SynFin,
+        TcpAnomalyKind::SynRst,
+        TcpAnomalyKind::NullFlags,
+        TcpAnomalyKind::AllFlags,
+        TcpAnomalyKind::HeaderTooShort,
+        TcpAnomalyKind::HeaderBeyondPacket,
+        TcpAnomalyKind::UrgentPointerZero,
+        TcpAnomalyKind::UrgentWithoutFlag,
+        TcpAnomalyKind::UrgentBeyondPayload,
+        TcpAnomalyKind::ReservedBits,
+        TcpAnomalyKind::ZeroPort,
+        TcpAnomalyKind::Land,
+    ];
+
+    // イベントコード (構造化した出力と統計に使う)
+    pub fn name(&self) -> &'static str {
+        match self {
+            TcpAnomalyKind::SynFin => "tcp_syn_fin",
+            TcpAnomalyKind::SynRst => "tcp_syn_rst",
+            TcpAnomalyKind::NullFlags => "tcp_null_flags",
+            TcpAnomalyKind::AllFlags => "tcp_all_flags",
+            TcpAnomalyKind::HeaderTooShort => "tcp_hlen_too_small",
+            TcpAnomalyKind::HeaderBeyondPacket => "tcp_hlen_beyond_packet",
+            TcpAnomalyKind::UrgentPointerZero => "tcp_urg_ptr_zero",
+            TcpAnomalyKind::UrgentWithoutFlag => "tcp_urg_ptr_without_flag",
+            TcpAnomalyKind::UrgentBeyondPayload => "tcp_urg_ptr_beyond_payload",
+            TcpAnomalyKind::ReservedBits => "tcp_reserved_bits",
+            TcpAnomalyKind::ZeroPort => "tcp_zero_port",
+            TcpAnomalyKind::Land => "tcp_land",
+        }
+    }
+}
+
+impl fmt::Display for TcpAnomalyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            TcpAnomalyKind::SynFin => "SYN and FIN both set",
+            TcpAnomalyKind::SynRst => "SYN and RST both set",
+            TcpAnomalyKind::NullFlags => "no TCP flags set",
+            TcpAnomalyKind::AllFlags => "all TCP flags set",
+            TcpAnomalyKind::HeaderTooShort => "TCP data offset smaller than 5",
+            TcpAnomalyKind::HeaderBeyondPacket => "TCP data offset beyond segment length",
+            TcpAnomalyKind::UrgentPointerZero => "URG set with zero urgent pointer",
+            TcpAnomalyKind::UrgentWithoutFlag => "urgent pointer set without URG",
+            TcpAnomalyKind::UrgentBeyondPayload => "urgent pointer beyond payload",
+            TcpAnomalyKind::ReservedBits => "TCP reserved bits set",
+            TcpAnomalyKind::ZeroPort => "TCP port zero",
+            TcpAnomalyKind::Land => "land attack (source equals destination)",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+// 検知した異常 (suppressedは前回の通知以降に通知しなかった同じ異常の数)
+#[derive(Debug, Clone)]
+pub struct TcpAnomaly {
+    pub kind: TcpAnomalyKind,
+    pub src_ip: IpAddr,
+    pub src_port: u16,
+    pub dst_ip: IpAddr,
+    pub dst_port: u16,
+    pub flags: u8,
+    pub data_offset: u8,
+    pub urgent_ptr: u16,
+    pub suppressed: u64,
+}
+
+impl fmt::Display for TcpAnomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}:{} -> {}:{} (flags=0x{:02x} data_offset={} urgent_ptr={})",
+            self.kind, self.src_ip, self.src_port, self.dst_ip, self.dst_port, self.flags, self.data_offset, self.urgent_ptr
+        )?;
+        if self.suppressed > 0 {
+            write!(f, " ({} suppressed)", self.suppressed)?;
+        }
+        Ok(())
+    }
+}
+
+// 種類ごとの検知数と、レート制限により通知しなかった数
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AnomalyCount {
+    pub detected: u64,
+    pub suppressed: u64,
+}
+
+// (送信元, 種類)ごとの通知の回数
+struct AlertRate {
+    window_start: SystemTime,
+    alerts: u32,
+    suppressed: u64, // 次の通知に含める件数
+}
+
+// TCPヘッダーを解析する前のセグメントを検査し、フラグの不正な組み合わせやヘッダーの異常を検知する
+#[derive(Default)]
+pub struct TcpAnomalyDetector {
+    rates: HashMap<(IpAddr, TcpAnomalyKind), AlertRate>,
+    counts: HashMap<TcpAnomalyKind, AnomalyCount>,
+    events: Vec<TcpAnomaly>, // 未出力のイベント
+}
+
+impl TcpAnomalyDetector {
+    pub fn new() -> Self {
+        TcpAnomalyDetector::default()
+    }
+
+    // TCPセグメントを検査する (dataはTCPヘッダーから始まるセグメント)
+    // completeはセグメント全体がキャプチャされているか (切り詰められている場合は長さに関する検査をしない)
+    pub fn inspect(&mut self, src_ip: IpAddr, dst_ip: IpAddr, data: &[u8], complete: bool, now: SystemTime) {
+        if data.len() < TCP_MIN_HEADER_SIZE {
+            return;
+        }
+        let src_port = u16::from_be_bytes([data[0], data[1]]);
+        let dst_port = u16::from_be_bytes([data[2], data[3]]);
+        let data_offset = data[12] >> 4;
+        let flags = data[13];
+        let urgent_ptr = u16::from_be_bytes([data[18], data[19]]);
+        let header_size = data_offset as usize * 4;
+
+        let mut found = Vec::new();
+        if flags & TCP_ALL_FLAGS == TCP_ALL_FLAGS {
+            found.push(TcpAnomalyKind::AllFlags);
+        } else {
+            if flags & (TCP_SYN | TCP_FIN) == TCP_SYN | TCP_FIN {
+                found.push(TcpAnomalyKind::SynFin);
+            }
+            if flags & (TCP_SYN | TCP_RST) == TCP_SYN | TCP_RST {
+                found.push(TcpAnomalyKind::SynRst);
+            }
+        }
+        if flags & TCP_ALL_FLAGS == 0 {
+            found.push(TcpAnomalyKind::NullFlags);
+        }
+
+        if data_offset < TCP_MIN_DATA_OFFSET {
+            found.push(TcpAnomalyKind::HeaderTooShort);
+        } else if complete && header_size > data.len() {
+            found.push(TcpAnomalyKind::HeaderBeyondPacket);
+        } else if flags & TCP_URG != 0 {
+            // 緊急ポインタはシーケンス番号からのオフセットで、ペイロードの中を指す
+            let payload_len = data.len().saturating_sub(header_size);
+            if urgent_ptr == 0 {
+                found.push(TcpAnomalyKind::UrgentPointerZero);
+            } else if complete && urgent_ptr as usize > payload_len {
+                found.push(TcpAnomalyKind::UrgentBeyondPayload);
+            }
+        }
+        if flags & TCP_URG == 0 && urgent_ptr != 0 {
+            found.push(TcpAnomalyKind::UrgentWithoutFlag);
+        }
+
+        if data[12] & TCP_RESERVED_BITS != 0 {
+            found.push(TcpAnomalyKind::ReservedBits);
+        }
+        if src_port == 0 || dst_port == 0 {
+            found.push(TcpAnomalyKind::ZeroPort);
+        }
+        if src_ip == dst_ip && src_port == dst_port {
+            found.push(TcpAnomalyKind::Land);
+        }
+
+        for kind in found {
+            let anomaly = TcpAnomaly {
+                kind,
+                src_ip,
+                src_port,
+                dst_ip,
+                dst_port,
+                flags,
+                data_offset,
+                urgent_ptr,
+                suppressed: 0,
+            };
+            self.report(anomaly, now);
+        }
+    }
+
+    // 送信元と種類ごとに期間内の通知を制限する
+    fn report(&mut self, mut anomaly: TcpAnomaly, now: SystemTime) {
+        let count = self.counts.entry(anomaly.kind).or_default();
+        count.detected += 1;
+
+        let key = (anomaly.src_ip, anomaly.kind);
+        if !self.rates.contains_key(&key) && self.rates.len() >= MAX_RATE_ENTRIES {
+            count.suppressed += 1;
+            return;
+        }
+        let rate = self.rates.entry(key).or_insert(AlertRate {
+            window_start: now,
+            alerts: 0,
+            suppressed: 0,
+        });
+        if elapsed_between(rate.window_start, now) >= ANOMALY_ALERT_INTERVAL {
+            rate.window_start = now;
+            rate.alerts = 0;
+        }
+        if rate.alerts >= ANOMALY_ALERT_LIMIT {
+            rate.suppressed += 1;
+            count.suppressed += 1;
+            return;
+        }
+        rate.alerts += 1;
+        anomaly.suppressed = std::mem::take(&mut rate.suppressed);
+        self.events.push(anomaly);
+    }
+
+    // 種類ごとの件数
+    pub fn count(&self, kind: TcpAnomalyKind) -> AnomalyCount {
+        self.counts.get(&kind).copied().unwrap_or_default()
+    }
+
+    // 未出力のイベントを取り出す
+    pub fn take_events(&mut self) -> Vec<TcpAnomaly> {
+        std::mem::take(&mut self.events)
+    }
+
+    // キャプチャ時刻nowを基準に期間の過ぎた通知の記録を削除する
+    // 通知しなかった件数が残っている記録は次の通知に含めるため長く保持する
+    pub fn cleanup(&mut self, now: SystemTime) {
+        self.rates.retain(|_, rate| {
+            let retention = if rate.suppressed > 0 {
+                SUPPRESSED_RETENTION
+            } else {
+                ANOMALY_ALERT_INTERVAL
+            };
+            elapsed_between(rate.window_start, now) < retention
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn at(seconds: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    fn host(n: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, n))
+    }
+
+    // ポート40000から80へのオプションなしのセグメント
+    fn segment(flags: u8, urgent_ptr: u16, payload: usize) -> Vec<u8> {
+        let mut data = vec![0x9C, 0x40, 0x00, 0x50, 0, 0, 0, 1, 0, 0, 0, 0, 0x50, flags, 0xFF, 0xFF, 0, 0];
+        data.extend_from_slice(&urgent_ptr.to_be_bytes());
+        data.resize(TCP_MIN_HEADER_SIZE + payload, 0);
+        data
+    }
+
+    fn anomalies(data: &[u8], complete: bool) -> Vec<&'static str> {
+        let mut detector = TcpAnomalyDetector::new();
+        detector.inspect(host(1), host(2), data, complete, at(0));
+        detector.take_events().iter().map(|anomaly| anomaly.kind.name()).collect()
+    }
+
+    #[test]
+    fn detects_invalid_flag_combinations() {
+        assert!(anomalies(&segment(TCP_SYN, 0, 0), true).is_empty());
+        assert!(anomalies(&segment(TCP_PSH | TCP_ACK, 0, 10), true).is_empty());
+        assert_eq!(anomalies(&segment(TCP_SYN | TCP_FIN, 0, 0), true), ["tcp_syn_fin"]);
+        assert_eq!(anomalies(&segment(TCP_SYN | TCP_RST, 0, 0), true), ["tcp_syn_rst"]);
+        assert_eq!(anomalies(&segment(0, 0, 0), true), ["tcp_null_flags"]);
+        // 全てのフラグはSYN+FINなどと重ねて通知しない
+        assert_eq!(anomalies(&segment(TCP_ALL_FLAGS, 1, 1), true), ["tcp_all_flags"]);
+    }
+
+    #[test]
+    fn detects_header_length_and_urgent_pointer_anomalies() {
+        let mut short = segment(TCP_ACK, 0, 0);
+        short[12] = 0x40;
+        assert_eq!(anomalies(&short, true), ["tcp_hlen_too_small"]);
+        let mut beyond = segment(TCP_ACK, 0, 0);
+        beyond[12] = 0x60;
+        assert_eq!(anomalies(&beyond, true), ["tcp_hlen_beyond_packet"]);
+        // 切り詰められたセグメントでは長さを検査しない
+        assert!(anomalies(&beyond, false).is_empty());
+
+        assert_eq!(anomalies(&segment(TCP_ACK | TCP_URG, 0, 10), true), ["tcp_urg_ptr_zero"]);
+        assert!(anomalies(&segment(TCP_ACK | TCP_URG, 10, 10), true).is_empty());
+        assert_eq!(anomalies(&segment(TCP_ACK | TCP_URG, 11, 10), true), ["tcp_urg_ptr_beyond_payload"]);
+        assert!(anomalies(&segment(TCP_ACK | TCP_URG, 11, 10), false).is_empty());
+        assert_eq!(anomalies(&segment(TCP_ACK, 5, 10), true), ["tcp_urg_ptr_without_flag"]);
+    }
+
+    #[test]
+    fn detects_reserved_bits_zero_port_and_land() {
+        let mut reserved = segment(TCP_ACK, 0, 0);
+        reserved[12] |= 0x08;
+        assert_eq!(anomalies(&reserved, true), ["tcp_reserved_bits"]);
+        // Accurate ECNのビットは予約ビットとみなさない
+        reserved[12] = 0x51;
+        assert!(anomalies(&reserved, true).is_empty());
+
+        let mut zero_port = segment(TCP_SYN, 0, 0);
+        zero_port[2..4].copy_from_slice(&[0, 0]);
+        assert_eq!(anomalies(&zero_port, true), ["tcp_zero_port"]);
+
+        let mut land = segment(TCP_SYN, 0, 0);
+        land[2..4].copy_from_slice(&[0x9C, 0x40]);
+        let mut detector = TcpAnomalyDetector::new();
+        detector.inspect(host(1), host(1), &land, true, at(0));
+        detector.inspect(host(1), host(2), &land, true, at(0));
+        let events = detector.take_events();
+        assert!(matches!(events.as_slice(), [TcpAnomaly { kind: TcpAnomalyKind::Land, .. }]));
+    }
+
+    #[test]
+    fn alerts_are_rate_limited_per_source_and_kind() {
+        let mut detector = TcpAnomalyDetector::new();
+        let syn_fin = segment(TCP_SYN | TCP_FIN, 0, 0);
+        for _ in 0..ANOMALY_ALERT_LIMIT + 2 {
+            detector.inspect(host(1), host(2), &syn_fin, true, at(0));
+        }
+        detector.inspect(host(3), host(2), &syn_fin, true, at(0));
+        detector.inspect(host(1), host(2), &segment(0, 0, 0), true, at(0));
+        assert_eq!(detector.take_events().len(), ANOMALY_ALERT_LIMIT as usize + 2);
+        let count = detector.count(TcpAnomalyKind::SynFin);
+        assert_eq!((count.detected, count.suppressed), (ANOMALY_ALERT_LIMIT as u64 + 3, 2));
+
+        // 通知しなかった件数は期間が過ぎた後の通知に含める
+        detector.cleanup(at(60));
+        detector.inspect(host(1), host(2), &syn_fin, true, at(60));
+        let events = detector.take_events();
+        assert!(matches!(events.as_slice(), [TcpAnomaly { suppressed: 2, .. }]));
+        assert!(events[0].to_string().ends_with("(2 suppressed)"));
+    }
+
+    #[test]
+    fn cleanup_keeps_records_with_suppressed_alerts() {
+        let mut detector = TcpAnomalyDetector::new();
+        let null = segment(0, 0, 0);
+        for _ in 0..=ANOMALY_ALERT_LIMIT {
+            detector.inspect(host(1), host(2), &null, true, at(0));
+        }
+        detector.inspect(host(3), host(2), &null, true, at(0));
+        detector.cleanup(at(60));
+        assert_eq!(detector.rates.len(), 1);
+        detector.cleanup(at(600));
+        assert!(detector.rates.is_empty());
+    }
+}
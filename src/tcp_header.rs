@@ -24,6 +24,37 @@ pub struct TcpHeader {
     pub window: u16,
     pub checksum: u16,
     pub urgent_ptr: u16,
+    pub options: Vec<TcpOption>,
+}
+
+// TCPオプションの種類
+pub const TCP_OPTION_END: u8 = 0;
+pub const TCP_OPTION_NOP: u8 = 1;
+pub const TCP_OPTION_MSS: u8 = 2;
+pub const TCP_OPTION_WINDOW_SCALE: u8 = 3;
+pub const TCP_OPTION_SACK_PERMITTED: u8 = 4;
+pub const TCP_OPTION_SACK: u8 = 5;
+pub const TCP_OPTION_TIMESTAMP: u8 = 8;
+pub const TCP_OPTION_MPTCP: u8 = 30;
+pub const TCP_OPTION_FAST_OPEN: u8 = 34;
+pub const TCP_OPTION_EXPERIMENTAL: u8 = 254; // RFC 6994 (TFOの正式な番号の割り当て前に使われていた)
+
+// 実験用オプションでTFOを示すExID
+const TCP_FAST_OPEN_EXID: u16 = 0xF989;
+
+// ウィンドウスケールのシフト量の上限 (RFC 7323)
+pub const MAX_WINDOW_SCALE: u8 = 14;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpOption {
+    Mss(u16),
+    WindowScale(u8),
+    SackPermitted,
+    Sack(Vec<(u32, u32)>), // (左端, 右端) のブロック
+    Timestamp { value: u32, echo_reply: u32 },
+    FastOpen(Vec<u8>), // Cookie (空の場合はCookieの要求)
+    Mptcp { subtype: u8, data: Vec<u8> },
+    Unknown { kind: u8, data: Vec<u8> },
 }
 
 pub fn parse_tcp_header(data: &[u8]) -> Option<(TcpHeader, usize)> {
@@ -41,6 +72,13 @@ pub fn parse_tcp_header(data: &[u8]) -> Option<(TcpHeader, usize)> {
     let checksum = u16::from_be_bytes([data[16], data[17]]);
     let urgent_ptr = u16::from_be_bytes([data[18], data[19]]);
 
+    // ヘッダー長は20バイト以上で、キャプチャされたデータに収まっている必要がある
+    let header_size = data_offset as usize * 4;
+    if header_size < 20 || header_size > data.len() {
+        return None;
+    }
+    let options = parse_tcp_options(&data[20..header_size]);
+
     Some((
         TcpHeader {
             src_port,
@@ -52,24 +90,178 @@ pub fn parse_tcp_header(data: &[u8]) -> Option<(TcpHeader, usize)> {
             window,
             checksum,
             urgent_ptr,
+            options,
         },
-        data_offset as usize * 4
+        header_size
     ))
 }
 
-pub fn parse_tcp_options(data: &[u8]) -> Option<u16> {
+// ヘッダーのオプション領域を解析する
+// 長さが不正なオプション以降は解析しない
+pub fn parse_tcp_options(data: &[u8]) -> Vec<TcpOption> {
+    let mut options = Vec::new();
     let mut i = 0;
     while i < data.len() {
-        match data[i] {
-            0 => break,  // End of options
-            1 => i += 1, // NOP
-            2 if data.len() >= i + 4 => {
-                // MSS option
-                return Some(u16::from_be_bytes([data[i + 2], data[i + 3]]));
+        let kind = data[i];
+        match kind {
+            TCP_OPTION_END => break,
+            TCP_OPTION_NOP => {
+                i += 1;
+                continue;
             }
-            _ if data.len() > i + 1 => i += data[i + 1] as usize,
-            _ => break,
+            _ => {}
         }
+
+        let length = match data.get(i + 1) {
+            Some(&length) if length >= 2 && i + length as usize <= data.len() => length as usize,
+            _ => break,
+        };
+        let body = &data[i + 2..i + length];
+        let option = match (kind, body.len()) {
+            (TCP_OPTION_MSS, 2) => TcpOption::Mss(u16::from_be_bytes([body[0], body[1]])),
+            (TCP_OPTION_WINDOW_SCALE, 1) => TcpOption::WindowScale(body[0]),
+            (TCP_OPTION_SACK_PERMITTED, 0) => TcpOption::SackPermitted,
+            (TCP_OPTION_SACK, n) if n % 8 == 0 => TcpOption::Sack(
+                body.chunks_exact(8)
+                    .map(|block| {
+                        (
+                            u32::from_be_bytes([block[0], block[1], block[2], block[3]]),
+                            u32::from_be_bytes([block[4], block[5], block[6], block[7]]),
+                        )
+                    })
+                    .collect(),
+            ),
+            (TCP_OPTION_TIMESTAMP, 8) => TcpOption::Timestamp {
+                value: u32::from_be_bytes([body[0], body[1], body[2], body[3]]),
+                echo_reply: u32::from_be_bytes([body[4], body[5], body[6], body[7]]),
+            },
+            (TCP_OPTION_FAST_OPEN, _) => TcpOption::FastOpen(body.to_vec()),
+            (TCP_OPTION_EXPERIMENTAL, n)
+                if n >= 2 && u16::from_be_bytes([body[0], body[1]]) == TCP_FAST_OPEN_EXID =>
+            {
+                TcpOption::FastOpen(body[2..].to_vec())
+            }
+            (TCP_OPTION_MPTCP, n) if n >= 1 => TcpOption::Mptcp {
+                subtype: body[0] >> 4,
+                data: body.to_vec(),
+            },
+            _ => TcpOption::Unknown { kind, data: body.to_vec() },
+        };
+        options.push(option);
+        i += length;
+    }
+    options
+}
+
+impl TcpHeader {
+    pub fn mss(&self) -> Option<u16> {
+        self.options.iter().find_map(|option| match option {
+            TcpOption::Mss(mss) => Some(*mss),
+            _ => None,
+        })
+    }
+
+    // ウィンドウスケールのシフト量 (上限を超える値は上限に丸める)
+    pub fn window_scale(&self) -> Option<u8> {
+        self.options.iter().find_map(|option| match option {
+            TcpOption::WindowScale(shift) => Some((*shift).min(MAX_WINDOW_SCALE)),
+            _ => None,
+        })
+    }
+
+    pub fn sack_permitted(&self) -> bool {
+        self.options.contains(&TcpOption::SackPermitted)
+    }
+
+    // (TSval, TSecr)
+    pub fn timestamp(&self) -> Option<(u32, u32)> {
+        self.options.iter().find_map(|option| match option {
+            TcpOption::Timestamp { value, echo_reply } => Some((*value, *echo_reply)),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(options: &[u8]) -> Vec<u8> {
+        let data_offset = (20 + options.len()) / 4;
+        let mut data = vec![
+            0x30, 0x39, 0x00, 0x50, 0, 0, 0x03, 0xE8, 0, 0, 0x07, 0xD0, (data_offset as u8) << 4, 0x12, 0xFF, 0xFF,
+            0x12, 0x34, 0, 0,
+        ];
+        data.extend_from_slice(options);
+        data
+    }
+
+    #[test]
+    fn parses_header_and_options() {
+        let options = [
+            2, 4, 0x05, 0xB4, // MSS 1460
+            1, 3, 3, 7, // NOP, ウィンドウスケール 7
+            4, 2, // SACK許可
+            8, 10, 0, 0, 0, 1, 0, 0, 0, 2, // タイムスタンプ
+        ];
+        let (tcp, length) = parse_tcp_header(&segment(&options)).unwrap();
+        assert_eq!(length, 40);
+        assert_eq!((tcp.src_port, tcp.dst_port), (12345, 80));
+        assert_eq!((tcp.seq_num, tcp.ack_num), (1000, 2000));
+        assert_eq!(tcp.flags, 0x12);
+        assert_eq!(tcp.window, 0xFFFF);
+        assert_eq!(tcp.checksum, 0x1234);
+        assert_eq!(tcp.mss(), Some(1460));
+        assert_eq!(tcp.window_scale(), Some(7));
+        assert!(tcp.sack_permitted());
+        assert_eq!(tcp.timestamp(), Some((1, 2)));
     }
-    None
-}
\ No newline at end of file
+
+    #[test]
+    fn rejects_invalid_data_offset() {
+        let mut data = segment(&[]);
+        data[12] = 0x40;
+        assert!(parse_tcp_header(&data).is_none());
+        data[12] = 0x60;
+        assert!(parse_tcp_header(&data).is_none());
+        assert!(parse_tcp_header(&data[..19]).is_none());
+    }
+
+    #[test]
+    fn window_scale_is_capped() {
+        let (tcp, _) = parse_tcp_header(&segment(&[3, 3, 20, 0])).unwrap();
+        assert_eq!(tcp.window_scale(), Some(MAX_WINDOW_SCALE));
+    }
+
+    #[test]
+    fn parses_sack_fast_open_and_unknown_options() {
+        let options = parse_tcp_options(&[
+            5, 10, 0, 0, 0, 1, 0, 0, 0, 5, // SACK 1ブロック
+            34, 2, // TFOのCookie要求
+            254, 6, 0xF9, 0x89, 0xAA, 0xBB, // 実験用オプションのTFO
+            99, 3, 0x42, // 未知のオプション
+        ]);
+        assert_eq!(
+            options,
+            vec![
+                TcpOption::Sack(vec![(1, 5)]),
+                TcpOption::FastOpen(Vec::new()),
+                TcpOption::FastOpen(vec![0xAA, 0xBB]),
+                TcpOption::Unknown { kind: 99, data: vec![0x42] },
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_at_end_or_malformed_length() {
+        assert_eq!(parse_tcp_options(&[1, 0, 2, 4, 0x05, 0xB4]), Vec::new());
+        // 長さが2未満、または残りのデータを超える
+        assert_eq!(parse_tcp_options(&[2, 4, 0x05, 0xB4, 8, 1, 0, 0]), vec![TcpOption::Mss(1460)]);
+        assert_eq!(parse_tcp_options(&[4, 2, 2, 8, 0x05, 0xB4]), vec![TcpOption::SackPermitted]);
+        // 長さが種類に合わないMSSは未知のオプションとして扱う
+        assert_eq!(
+            parse_tcp_options(&[2, 3, 0x05]),
+            vec![TcpOption::Unknown { kind: 2, data: vec![0x05] }]
+        );
+    }
+}
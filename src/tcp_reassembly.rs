@@ -0,0 +1,309 @@
+use std::str::FromStr;
+
+// シーケンス番号の比較 (32ビットの剰余空間で比較する)
+pub fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+pub fn seq_le(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+pub fn seq_gt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+pub fn seq_ge(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) >= 0
+}
+
+// 重複するセグメントのどちらのデータを採用するか (ターゲットベースの再構築ポリシー)
+// 受け付け済み(上位層に渡した)データはどのポリシーでも書き換えない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReassemblyPolicy {
+    First,   // 先に届いたデータを常に採用
+    Last,    // 後から届いたデータを常に採用
+    Bsd,     // 新しいセグメントが既存のセグメントより前から始まる場合のみ新しいデータを採用
+    Linux,   // BSDに加え、開始位置が同じで新しいセグメントの方が長い場合も新しいデータを採用
+    Windows, // 新しいセグメントが既存のセグメントより前から始まり、後ろまで完全に覆う場合のみ新しいデータを採用
+}
+
+impl ReassemblyPolicy {
+    // 重複部分で新しいセグメントのデータを採用するか
+    fn new_data_wins(self, new_seq: u32, new_end: u32, old_seq: u32, old_end: u32) -> bool {
+        match self {
+            ReassemblyPolicy::First => false,
+            ReassemblyPolicy::Last => true,
+            ReassemblyPolicy::Bsd => seq_lt(new_seq, old_seq),
+            ReassemblyPolicy::Linux => {
+                seq_lt(new_seq, old_seq) || (new_seq == old_seq && seq_gt(new_end, old_end))
+            }
+            ReassemblyPolicy::Windows => seq_lt(new_seq, old_seq) && seq_gt(new_end, old_end),
+        }
+    }
+}
+
+impl FromStr for ReassemblyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "first" => Ok(ReassemblyPolicy::First),
+            "last" => Ok(ReassemblyPolicy::Last),
+            "bsd" => Ok(ReassemblyPolicy::Bsd),
+            "linux" => Ok(ReassemblyPolicy::Linux),
+            "windows" => Ok(ReassemblyPolicy::Windows),
+            s => Err(format!("不明な再構築ポリシーです: {} (first, last, bsd, linux, windows)", s)),
+        }
+    }
+}
+
+// ストリームデータ(client_data/server_data)上の位置とシーケンス番号の対応
+// キャプチャの欠落を飛ばした場合は、その位置から対応を取り直す
+#[derive(Debug, Clone, Copy)]
+pub struct SeqAnchor {
+    pub seq: u32,
+    pub offset: usize,
+}
+
+impl SeqAnchor {
+    pub fn new(seq: u32, offset: usize) -> Self {
+        SeqAnchor { seq, offset }
+    }
+
+    // シーケンス番号に対応するストリームデータ上の位置
+    pub fn offset_of(&self, seq: u32) -> Option<usize> {
+        if seq_lt(seq, self.seq) {
+            return None;
+        }
+        Some(self.offset + seq.wrapping_sub(self.seq) as usize)
+    }
+}
+
+// 1ストリームあたりのメモリ上限
+#[derive(Debug, Clone, Copy)]
+pub struct StreamLimits {
+    pub max_queued_bytes: usize,    // 両方向の合計で保持する順序外セグメントのバイト数
+    pub max_queued_segments: usize, // 両方向の合計で保持する順序外セグメント数
+    pub depth: usize,               // 方向ごとに検査用に保持する再構築済みデータのバイト数 (0は無制限)
+}
+
+impl Default for StreamLimits {
+    fn default() -> Self {
+        StreamLimits {
+            max_queued_bytes: 1024 * 1024,
+            max_queued_segments: 256,
+            depth: 1024 * 1024,
+        }
+    }
+}
+
+// 再構築済みのデータをdepthまで連結し、保持しなかったバイト数を返す
+pub fn append_within_depth(stream_data: &mut Vec<u8>, data: &[u8], depth: usize) -> usize {
+    let retained = if depth == 0 {
+        data.len()
+    } else {
+        depth.saturating_sub(stream_data.len()).min(data.len())
+    };
+    stream_data.extend_from_slice(&data[..retained]);
+    data.len() - retained
+}
+
+#[derive(Debug)]
+struct QueuedSegment {
+    seq: u32,
+    data: Vec<u8>,
+}
+
+impl QueuedSegment {
+    fn end_seq(&self) -> u32 {
+        self.seq.wrapping_add(self.data.len() as u32)
+    }
+}
+
+// 期待するシーケンス番号より先のセグメントを、欠落部分が埋まるまで保持するキュー
+#[derive(Debug, Default)]
+pub struct SegmentQueue {
+    segments: Vec<QueuedSegment>, // シーケンス番号順
+    queued_bytes: usize,
+}
+
+impl SegmentQueue {
+    pub fn new() -> Self {
+        SegmentQueue {
+            segments: Vec::new(),
+            queued_bytes: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    // 先頭(最も小さいシーケンス番号)のセグメントの開始位置
+    pub fn first_seq(&self) -> Option<u32> {
+        self.segments.first().map(|segment| segment.seq)
+    }
+
+    // セグメントをシーケンス番号順に挿入する
+    // 保持しているセグメントと重複する部分はポリシーに従って一方のデータに揃え、
+    // 重複部分のデータが異なっていた場合はtrueを返す
+    pub fn insert(&mut self, seq: u32, data: &[u8], policy: ReassemblyPolicy) -> bool {
+        let mut data = data.to_vec();
+        let end = seq.wrapping_add(data.len() as u32);
+        let mut conflict = false;
+        let mut covered = false;
+
+        for segment in &mut self.segments {
+            let segment_end = segment.end_seq();
+            let overlap_start = if seq_gt(seq, segment.seq) { seq } else { segment.seq };
+            let overlap_end = if seq_lt(end, segment_end) { end } else { segment_end };
+            if seq_ge(overlap_start, overlap_end) {
+                continue;
+            }
+
+            let length = overlap_end.wrapping_sub(overlap_start) as usize;
+            let new_start = overlap_start.wrapping_sub(seq) as usize;
+            let old_start = overlap_start.wrapping_sub(segment.seq) as usize;
+            let new_range = new_start..new_start + length;
+            let old_range = old_start..old_start + length;
+
+            if data[new_range.clone()] != segment.data[old_range.clone()] {
+                conflict = true;
+                if policy.new_data_wins(seq, end, segment.seq, segment_end) {
+                    segment.data[old_range].copy_from_slice(&data[new_range]);
+                } else {
+                    data[new_range].copy_from_slice(&segment.data[old_range]);
+                }
+            }
+
+            // 既存のセグメントに完全に含まれる場合は保持しない
+            if seq_ge(seq, segment.seq) && seq_le(end, segment_end) {
+                covered = true;
+            }
+        }
+
+        if !covered {
+            let position = self
+                .segments
+                .iter()
+                .position(|segment| seq_lt(seq, segment.seq))
+                .unwrap_or(self.segments.len());
+            self.queued_bytes += data.len();
+            self.segments.insert(position, QueuedSegment { seq, data });
+        }
+
+        conflict
+    }
+
+    // next_seqから連続して取り出せるデータを返す
+    // next_seqより前の部分(受け付け済みのデータ)は捨てる
+    pub fn take_contiguous(&mut self, next_seq: u32) -> Vec<u8> {
+        let mut contiguous = Vec::new();
+        let mut next_seq = next_seq;
+
+        while let Some(segment) = self.segments.first() {
+            if seq_gt(segment.seq, next_seq) {
+                // まだ欠落部分がある
+                break;
+            }
+
+            let segment = self.segments.remove(0);
+            self.queued_bytes -= segment.data.len();
+
+            if seq_le(segment.end_seq(), next_seq) {
+                // すべて受け付け済み
+                continue;
+            }
+
+            let skip = next_seq.wrapping_sub(segment.seq) as usize;
+            contiguous.extend_from_slice(&segment.data[skip..]);
+            next_seq = segment.end_seq();
+        }
+
+        contiguous
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_comparison_wraps_around() {
+        assert!(seq_lt(u32::MAX - 10, 5));
+        assert!(seq_gt(5, u32::MAX - 10));
+        assert!(seq_le(7, 7) && seq_ge(7, 7));
+        assert_eq!(SeqAnchor::new(u32::MAX - 1, 100).offset_of(2), Some(104));
+        assert_eq!(SeqAnchor::new(10, 0).offset_of(9), None);
+    }
+
+    #[test]
+    fn takes_contiguous_data_in_order() {
+        let mut queue = SegmentQueue::new();
+        assert!(!queue.insert(108, b"ijkl", ReassemblyPolicy::First));
+        assert!(!queue.insert(104, b"efgh", ReassemblyPolicy::First));
+        assert_eq!(queue.take_contiguous(100), b"");
+        assert!(!queue.insert(100, b"abcd", ReassemblyPolicy::First));
+        assert_eq!(queue.queued_bytes(), 12);
+        assert_eq!(queue.take_contiguous(100), b"abcdefghijkl");
+        assert!(queue.is_empty());
+        assert_eq!(queue.queued_bytes(), 0);
+    }
+
+    #[test]
+    fn skips_already_accepted_bytes() {
+        let mut queue = SegmentQueue::new();
+        queue.insert(100, b"abcdefgh", ReassemblyPolicy::First);
+        queue.insert(102, b"cd", ReassemblyPolicy::First);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.take_contiguous(104), b"efgh");
+    }
+
+    // 保持しているセグメント(seq, データ)に新しいセグメントを重ねた後の連続したデータ
+    fn overlap(policy: ReassemblyPolicy, old: (u32, &[u8]), new: (u32, &[u8])) -> (Vec<u8>, bool) {
+        let mut queue = SegmentQueue::new();
+        queue.insert(old.0, old.1, policy);
+        let conflict = queue.insert(new.0, new.1, policy);
+        (queue.take_contiguous(100), conflict)
+    }
+
+    #[test]
+    fn overlap_policies() {
+        use ReassemblyPolicy::*;
+        // 新しいセグメントが前から始まり、後ろまで覆う
+        for (policy, expected) in [(First, "yyxxxxyy"), (Last, "yyyyyyyy"), (Bsd, "yyyyyyyy"), (Linux, "yyyyyyyy"), (Windows, "yyyyyyyy")] {
+            assert_eq!(overlap(policy, (102, b"xxxx"), (100, b"yyyyyyyy")), (expected.into(), true), "{:?}", policy);
+        }
+        // 新しいセグメントが前から始まり、途中で終わる
+        for (policy, expected) in [(First, "yyxxxx"), (Last, "yyyyxx"), (Bsd, "yyyyxx"), (Linux, "yyyyxx"), (Windows, "yyxxxx")] {
+            assert_eq!(overlap(policy, (102, b"xxxx"), (100, b"yyyy")), (expected.into(), true), "{:?}", policy);
+        }
+        // 開始位置が同じで、新しいセグメントの方が長い
+        for (policy, expected) in [(First, "xxxxyy"), (Last, "yyyyyy"), (Bsd, "xxxxyy"), (Linux, "yyyyyy"), (Windows, "xxxxyy")] {
+            assert_eq!(overlap(policy, (100, b"xxxx"), (100, b"yyyyyy")), (expected.into(), true), "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn identical_overlap_is_not_a_conflict() {
+        assert_eq!(overlap(ReassemblyPolicy::First, (100, b"abcd"), (102, b"cdef")), (b"abcdef".to_vec(), false));
+    }
+
+    #[test]
+    fn appends_up_to_depth() {
+        let mut data = b"abc".to_vec();
+        assert_eq!(append_within_depth(&mut data, b"defg", 5), 2);
+        assert_eq!(data, b"abcde");
+        assert_eq!(append_within_depth(&mut data, b"xyz", 0), 0);
+        assert_eq!(data, b"abcdexyz");
+    }
+}
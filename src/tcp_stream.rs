@@ -1,5 +1,11 @@
-use std::net::Ipv4Addr;
-use std::time::{Duration, Instant, SystemTime};
+use std::net::IpAddr;
+use crate::capture_clock::elapsed_between;
+use crate::tcp_header::TcpHeader;
+use crate::tcp_reassembly::{
+    append_within_depth, seq_ge, seq_gt, seq_lt, ReassemblyPolicy, SegmentQueue, SeqAnchor, StreamLimits,
+};
+use std::fmt;
+use std::time::{Duration, SystemTime};
 
 // TCPフラグの定義
 pub const TCP_FIN: u8 = 0x01;
@@ -9,6 +15,12 @@ pub const TCP_PSH: u8 = 0x08;
 pub const TCP_ACK: u8 = 0x10;
 pub const TCP_URG: u8 = 0x20;
 
+// TIME_WAITから完全にクローズするまでの時間 (2MSL)
+pub const TIME_WAIT_DURATION: Duration = Duration::from_secs(120);
+
+// これより長く通信がない場合、保持しているタイムスタンプはPAWSの判定に使わない (RFC 7323)
+const PAWS_IDLE_LIMIT: Duration = Duration::from_secs(24 * 24 * 60 * 60);
+
 // TCPセッションの状態を表す列挙型
 #[derive(Debug, PartialEq, Clone)]
 pub enum TcpState {
@@ -27,122 +39,631 @@ pub enum TcpState {
     //https://camo.qiitausercontent.com/24d35109620da317520dc832e55b60d1e730db04/68747470733a2f2f71696974612d696d6167652d73746f72652e73332e616d617a6f6e6177732e636f6d2f302f323831332f32313639633437332d613764332d353666642d643734382d3238326331346138343637342e6a706567
 }
 
+// ストリームの再構築中に検出したイベント
+#[derive(Debug, Clone, PartialEq)]
+pub enum TcpStreamEvent {
+    // 受け付け済みのデータと異なる内容の再送 (IDS回避の典型的な手法)
+    RetransmissionConflict { from_client: bool, seq: u32, length: usize },
+    // 順序外で保持しているセグメント同士の重複部分の不一致
+    OverlapConflict { from_client: bool, seq: u32, length: usize },
+    // 直前より古いタイムスタンプを持つセグメント (PAWSにより受信側で破棄される)
+    PawsRejected { from_client: bool, seq: u32, ts_value: u32, ts_recent: u32 },
+    // 受信側のウィンドウ外のRST (RST注入による検査の回避の疑い、受信側で破棄される)
+    RstOutOfWindow { from_client: bool, seq: u32, expected: u32 },
+}
+
+impl TcpStreamEvent {
+    // イベントの種類を表す名前 (構造化した出力に使う)
+    pub fn name(&self) -> &'static str {
+        match self {
+            TcpStreamEvent::RetransmissionConflict { .. } => "retransmission_conflict",
+            TcpStreamEvent::OverlapConflict { .. } => "overlap_conflict",
+            TcpStreamEvent::PawsRejected { .. } => "paws_rejected",
+            TcpStreamEvent::RstOutOfWindow { .. } => "rst_out_of_window",
+        }
+    }
+}
+
+impl fmt::Display for TcpStreamEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = |from_client: bool| if from_client { "client" } else { "server" };
+        match self {
+            TcpStreamEvent::RetransmissionConflict { from_client, seq, length } => write!(
+                f,
+                "retransmission with different data ({} seq={} len={})",
+                direction(*from_client), seq, length
+            ),
+            TcpStreamEvent::OverlapConflict { from_client, seq, length } => write!(
+                f,
+                "overlapping segment with different data ({} seq={} len={})",
+                direction(*from_client), seq, length
+            ),
+            TcpStreamEvent::PawsRejected { from_client, seq, ts_value, ts_recent } => write!(
+                f,
+                "segment rejected by PAWS ({} seq={} tsval={} ts_recent={})",
+                direction(*from_client), seq, ts_value, ts_recent
+            ),
+            TcpStreamEvent::RstOutOfWindow { from_client, seq, expected } => write!(
+                f,
+                "RST outside the receive window ({} seq={} expected={})",
+                direction(*from_client), seq, expected
+            ),
+        }
+    }
+}
+
+// 新たに連続したデータ、または読み飛ばした欠落 (コンシューマーに順に渡す)
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamChunk {
+    Data { from_client: bool, data: Vec<u8> },
+    Gap { from_client: bool, seq: u32, length: u32 },
+}
+
+// SYNで通知されたオプションとタイムスタンプの状態 (送信側ごと)
+#[derive(Debug, Clone, Default)]
+pub struct TcpOptionState {
+    pub window_scale: Option<u8>,          // SYNで通知されたウィンドウスケール
+    pub sack_permitted: bool,              // SYNでSACKを許可したか
+    pub timestamps: bool,                  // SYNでタイムスタンプを送ったか
+    pub ts_recent: Option<u32>,            // 最後に受け付けたセグメントのTSval
+    pub ts_recent_time: Option<SystemTime>, // ts_recentを更新したキャプチャ時刻
+}
+
 // TCPストリームを表す構造体
 #[derive(Debug)]
 pub struct TcpStream {
-    pub state: TcpState,
+    pub state: TcpState,  // 両端の状態から決めたストリーム全体の状態
+    pub client_state: TcpState,  // クライアント側の状態
+    pub server_state: TcpState,  // サーバー側の状態
+    pub client_fin_seq: Option<u32>,  // クライアントが送ったFINのシーケンス番号
+    pub server_fin_seq: Option<u32>,  // サーバーが送ったFINのシーケンス番号
     pub client_init_seq: u32,
     pub server_init_seq: u32,
     pub client_next_seq: u32,
     pub server_next_seq: u32,
     pub client_data: Vec<u8>,
     pub server_data: Vec<u8>,
-    pub last_activity: Instant,
-    pub client_window: u16,
-    pub server_window: u16,
+    pub last_activity: SystemTime,  // キャプチャ時刻での最終通信時刻
+    pub client_window: u32,  // ウィンドウスケールを適用した実効ウィンドウ
+    pub server_window: u32,
     pub client_mss: u16,
     pub server_mss: u16,
     pub client_cwnd: u32,  // クライアントの輻輳ウィンドウ
     pub server_cwnd: u32,  // サーバーの輻輳ウィンドウ
-    pub arrival_time: SystemTime,  // 最後のパケット到着時間
+    pub arrival_time: SystemTime,  // 最後のパケット到着時間 (pcapのタイムスタンプ)
+    pub vlan_ids: Vec<u16>,  // ストリームが観測されたVLAN ID (外側から順)
+    pub client_ooo: SegmentQueue,  // クライアントからの順序外セグメント
+    pub server_ooo: SegmentQueue,  // サーバーからの順序外セグメント
+    pub server_seq_synced: bool,  // サーバーのシーケンス番号が判明しているか
+    pub ooo_dropped_segments: u64,  // キューの上限により破棄した順序外セグメント数
+    pub client_anchor: SeqAnchor,  // client_data上の位置とシーケンス番号の対応
+    pub server_anchor: SeqAnchor,  // server_data上の位置とシーケンス番号の対応
+    pub policy: ReassemblyPolicy,  // 重複セグメントの再構築ポリシー
+    pub retransmitted_segments: u64,  // 受け付け済みのデータを含む再送セグメント数
+    pub conflicting_segments: u64,  // 重複部分のデータが異なっていたセグメント数
+    pub limits: StreamLimits,  // キューと保持データの上限
+    pub truncated_bytes: u64,  // depthを超えたため保持しなかったバイト数
+    pub client_options: TcpOptionState,  // クライアントが通知したオプション
+    pub server_options: TcpOptionState,  // サーバーが通知したオプション
+    pub paws_rejected_segments: u64,  // PAWSにより破棄されるセグメント数
+    pub rejected_rst_segments: u64,  // ウィンドウ外のため受信側で破棄されるRST数
+    pub midstream: bool,  // 3ウェイハンドシェイクを観測せずに途中から追跡しているか
+    pub first_seen: SystemTime,  // キャプチャ時刻での追跡を開始した時刻
+    pub client_packets: u64,
+    pub client_bytes: u64,  // クライアントから送られたペイロードのバイト数 (再送を含む)
+    pub server_packets: u64,
+    pub server_bytes: u64,  // サーバーから送られたペイロードのバイト数 (再送を含む)
+    chunks: Vec<StreamChunk>,  // コンシューマーに未通知のデータと欠落
+    events: Vec<TcpStreamEvent>,  // 未出力のイベント
 }
 
-pub type TcpStreamKey = (Ipv4Addr, u16, Ipv4Addr, u16);
+// (送信元IP, 送信元ポート, 宛先IP, 宛先ポート) IPv4とIPv6で共通
+pub type TcpStreamKey = (IpAddr, u16, IpAddr, u16);
 
 impl TcpStream {
-    pub fn new(client_init_seq: u32, server_init_seq: u32) -> Self {
+    pub fn new(
+        client_init_seq: u32,
+        server_init_seq: u32,
+        now: SystemTime,
+        policy: ReassemblyPolicy,
+        limits: StreamLimits,
+    ) -> Self {
         TcpStream {
             state: TcpState::SynSent,
+            client_state: TcpState::SynSent,
+            server_state: TcpState::Listen,
+            client_fin_seq: None,
+            server_fin_seq: None,
             client_init_seq,
             server_init_seq,
             client_next_seq: client_init_seq.wrapping_add(1),
             server_next_seq: server_init_seq,
             client_data: Vec::new(),
             server_data: Vec::new(),
-            last_activity: Instant::now(),
+            last_activity: now,
             client_window: 0,
             server_window: 0,
             client_mss: 1460,  // デフォルト値
             server_mss: 1460,  // デフォルト値
             client_cwnd: 1,
             server_cwnd: 1,
-            arrival_time: SystemTime::now(),
+            arrival_time: now,
+            vlan_ids: Vec::new(),
+            client_ooo: SegmentQueue::new(),
+            server_ooo: SegmentQueue::new(),
+            server_seq_synced: false,
+            ooo_dropped_segments: 0,
+            client_anchor: SeqAnchor::new(client_init_seq.wrapping_add(1), 0),
+            server_anchor: SeqAnchor::new(server_init_seq, 0),
+            policy,
+            retransmitted_segments: 0,
+            conflicting_segments: 0,
+            limits,
+            truncated_bytes: 0,
+            client_options: TcpOptionState::default(),
+            server_options: TcpOptionState::default(),
+            paws_rejected_segments: 0,
+            rejected_rst_segments: 0,
+            midstream: false,
+            first_seen: now,
+            client_packets: 0,
+            client_bytes: 0,
+            server_packets: 0,
+            server_bytes: 0,
+            chunks: Vec::new(),
+            events: Vec::new(),
         }
     }
 
-    pub fn update(&mut self, is_from_client: bool, seq: u32, ack: u32, flags: u8, data: &[u8], window: u16) {
-        self.last_activity = Instant::now();
-        self.arrival_time = SystemTime::now();
+    // 確立済みの接続を途中から追跡する
+    // 初期シーケンス番号は不明なため、最初に観測したシーケンス番号の直前とみなす
+    pub fn new_midstream(
+        client_next_seq: u32,
+        server_next_seq: u32,
+        now: SystemTime,
+        policy: ReassemblyPolicy,
+        limits: StreamLimits,
+    ) -> Self {
+        let mut stream = TcpStream::new(client_next_seq.wrapping_sub(1), server_next_seq, now, policy, limits);
+        stream.server_seq_synced = true;
+        stream.state = TcpState::Established;
+        stream.client_state = TcpState::Established;
+        stream.server_state = TcpState::Established;
+        stream.midstream = true;
+        stream
+    }
+
+    pub fn update(&mut self, is_from_client: bool, tcp_header: &TcpHeader, data: &[u8], now: SystemTime) {
+        let seq = tcp_header.seq_num;
+        let ack = tcp_header.ack_num;
+        let flags = tcp_header.flags;
+
+        // 前回のパケットからの経過時間 (TIME_WAITの判定に使用)
+        let idle = elapsed_between(self.last_activity, now);
+        self.last_activity = now;
+        self.arrival_time = now;
 
         if is_from_client {
-            if seq == self.client_next_seq {
-                self.client_data.extend_from_slice(data);
-                self.client_next_seq = self.client_next_seq.wrapping_add(data.len() as u32);
-            }
-            if flags & TCP_ACK != 0 {
+            self.client_packets += 1;
+            self.client_bytes += data.len() as u64;
+        } else {
+            self.server_packets += 1;
+            self.server_bytes += data.len() as u64;
+        }
+
+        // SYNで通知されたオプションを記録
+        if flags & TCP_SYN != 0 {
+            self.record_syn_options(is_from_client, tcp_header);
+        }
+
+        // 古いタイムスタンプのセグメントは受信側で破棄されるため再構築しない
+        if !self.check_paws(is_from_client, tcp_header, now) {
+            return;
+        }
+
+        // ウィンドウ外のRSTは受信側で破棄されるため、接続を閉じずに無視する
+        if flags & TCP_RST != 0 && !self.rst_acceptable(is_from_client, seq, ack, flags) {
+            let expected = if is_from_client { self.client_next_seq } else { self.server_next_seq };
+            self.rejected_rst_segments += 1;
+            self.events.push(TcpStreamEvent::RstOutOfWindow {
+                from_client: is_from_client,
+                seq,
+                expected,
+            });
+            return;
+        }
+
+        // サーバーのシーケンス番号はSYN-ACK(または最初のセグメント)で同期する
+        if !is_from_client && !self.server_seq_synced {
+            self.server_init_seq = seq;
+            self.server_next_seq = if flags & TCP_SYN != 0 { seq.wrapping_add(1) } else { seq };
+            self.server_anchor = SeqAnchor::new(self.server_next_seq, self.server_data.len());
+            self.server_seq_synced = true;
+        }
+
+        // 両方向の合計でキューの上限を超える場合は順序外セグメントを保持しない
+        let can_queue = self.client_ooo.len() + self.server_ooo.len() < self.limits.max_queued_segments
+            && self.client_ooo.queued_bytes() + self.server_ooo.queued_bytes() + data.len()
+                <= self.limits.max_queued_bytes;
+
+        if !self.accept_segment(is_from_client, seq, data, can_queue) {
+            self.ooo_dropped_segments += 1;
+        }
+
+        if flags & TCP_ACK != 0 {
+            // 相手方向のデータに対する確認応答
+            if !is_from_client || self.server_seq_synced {
+                self.acknowledge(!is_from_client, ack);
+            } else {
                 self.server_next_seq = ack;
+                self.server_anchor = SeqAnchor::new(ack, self.server_data.len());
+                self.server_seq_synced = true;
             }
+        }
+
+        let window = self.effective_window(is_from_client, flags, tcp_header.window);
+        if is_from_client {
             self.client_window = window;
             self.client_cwnd += 1;  // 簡略化した輻輳制御
         } else {
-            if seq == self.server_next_seq {
-                self.server_data.extend_from_slice(data);
-                self.server_next_seq = self.server_next_seq.wrapping_add(data.len() as u32);
-            }
-            if flags & TCP_ACK != 0 {
-                self.client_next_seq = ack;
-            }
             self.server_window = window;
             self.server_cwnd += 1;  // 簡略化した輻輳制御
         }
 
         // 状態遷移の処理
-        self.state = match (self.state.clone(), flags) {
-            // サーバーが SYN を受信し、SYN_RECEIVED 状態に遷移
-            (TcpState::Listen, TCP_SYN) => TcpState::SynReceived,
-
-            // クライアントが SYN-ACK を受信し、接続確立
-            (TcpState::SynSent, flags) if flags & (TCP_SYN | TCP_ACK) == (TCP_SYN | TCP_ACK) => {
-                // 実際はSYN-ACK を受信したら ACK を送信するが、傍聴しているだけなので不要
-                TcpState::Established
-            },
-
-            // サーバーが最後の ACK を受信し、接続確立
-            (TcpState::SynReceived, TCP_ACK) => TcpState::Established,
-
-            // 確立された接続で、一方が接続終了を開始 (FIN 送信)
-            (TcpState::Established, TCP_FIN) => TcpState::FinWait1,
-
-            // FIN 送信側が ACK を受信、または同時クローズで FIN を受信
-            (TcpState::FinWait1, flags) => {
-                if flags & TCP_ACK != 0 && flags & TCP_FIN == 0 {
-                    // ACK のみを受信
-                    TcpState::FinWait2
-                } else if flags & (TCP_FIN | TCP_ACK) == (TCP_FIN | TCP_ACK) {
-                    // FIN-ACK を受信（同時クローズ）
-                    // 実際は FIN-ACK を受信したら ACK を送信するが、傍聴しているだけなので不要
-                    TcpState::TimeWait
-                } else {
-                    // その他の場合は状態を変更しない
-                    TcpState::FinWait1
+        self.track_state(is_from_client, flags, seq, ack, data.len(), idle);
+    }
+
+    // 傍受している立場での状態遷移
+    // クライアントとサーバーの状態を別々に追跡し、観測したセグメントは相手に届いたものとして扱う
+    // (実際はSYN-ACKやFINを受信したらACKを送信するが、傍聴しているだけなので不要)
+    fn track_state(&mut self, is_from_client: bool, flags: u8, seq: u32, ack: u32, data_len: usize, idle: Duration) {
+        use TcpState::*;
+
+        // TIME_WAIT 状態で 2MSL (通常 2分) 経過後、完全にクローズ
+        // 受け付けた RST (ウィンドウ内のもの) はどの状態でも接続を即座に終了させる
+        if (self.state == TimeWait && idle > TIME_WAIT_DURATION) || flags & TCP_RST != 0 {
+            self.client_state = Closed;
+            self.server_state = Closed;
+            self.state = Closed;
+            return;
+        }
+
+        let peer_init_seq = if is_from_client { self.server_init_seq } else { self.client_init_seq };
+        let peer_fin_seq = if is_from_client { self.server_fin_seq } else { self.client_fin_seq };
+        if flags & TCP_FIN != 0 {
+            // FIN は SYN とデータの後ろのシーケンス番号を1つ消費する
+            let syn_len = if flags & TCP_SYN != 0 { 1 } else { 0 };
+            let fin_seq = seq.wrapping_add(syn_len).wrapping_add(data_len as u32);
+            let own_fin_seq = if is_from_client { &mut self.client_fin_seq } else { &mut self.server_fin_seq };
+            own_fin_seq.get_or_insert(fin_seq);
+        }
+        let (sender, receiver) = if is_from_client {
+            (&mut self.client_state, &mut self.server_state)
+        } else {
+            (&mut self.server_state, &mut self.client_state)
+        };
+
+        if flags & TCP_SYN != 0 {
+            if flags & TCP_ACK != 0 {
+                // SYN-ACK の送信側は SYN_RECEIVED、SYN を送った受信側は接続確立
+                if matches!(*sender, Listen | SynSent | Closed) {
+                    *sender = SynReceived;
+                }
+                if *receiver == SynSent {
+                    *receiver = Established;
+                }
+            } else {
+                // SYN の送信 (同時オープンでは両方が SYN を送り、受信側も SYN_RECEIVED に遷移)
+                if matches!(*sender, Listen | Closed) {
+                    *sender = SynSent;
+                }
+                if *receiver == SynSent && *sender == SynSent && !is_from_client {
+                    *receiver = SynReceived;
+                }
+            }
+        }
+
+        if flags & TCP_ACK != 0 {
+            // 受信側の SYN に対する ACK で接続確立
+            if *receiver == SynReceived && seq_gt(ack, peer_init_seq) {
+                *receiver = Established;
+                if *sender == SynReceived {
+                    *sender = Established;
+                }
+            }
+            // 受信側の FIN に対する ACK
+            if peer_fin_seq.is_some_and(|fin_seq| seq_gt(ack, fin_seq)) {
+                *receiver = match *receiver {
+                    FinWait1 => FinWait2,
+                    Closing => TimeWait,
+                    LastAck => Closed,
+                    ref state => state.clone(),
+                };
+            }
+        }
+
+        if flags & TCP_FIN != 0 {
+            // 送信側が接続終了を開始、または相手の FIN を受けて接続を閉じる
+            *sender = match *sender {
+                SynSent | SynReceived | Established => FinWait1,
+                CloseWait => LastAck,
+                ref state => state.clone(),
+            };
+            // 受信側はハーフクローズ、同時クローズ、または最後の FIN を受信
+            *receiver = match *receiver {
+                SynReceived | Established => CloseWait,
+                FinWait1 => Closing,
+                FinWait2 => TimeWait,
+                ref state => state.clone(),
+            };
+        }
+
+        self.state = self.combined_state();
+    }
+
+    // 両端の状態からストリーム全体の状態を決める
+    // 確立中は確立していない側、終了処理中は先に FIN を送った側の状態を示す
+    fn combined_state(&self) -> TcpState {
+        use TcpState::*;
+        let (client, server) = (&self.client_state, &self.server_state);
+        match (client, server) {
+            (Closed, Closed) => Closed,
+            (TimeWait | Closed, TimeWait | Closed) => TimeWait,
+            (Established, Established) => Established,
+            (Listen | SynSent | SynReceived, _) => client.clone(),
+            (_, Listen | SynSent | SynReceived) => server.clone(),
+            (FinWait1 | FinWait2 | Closing | TimeWait, _) => client.clone(),
+            (_, FinWait1 | FinWait2 | Closing | TimeWait) => server.clone(),
+            (Closed, _) => server.clone(),
+            _ => client.clone(),
+        }
+    }
+
+    // RSTを受信側が受け付けるか (RFC 5961)
+    // シーケンス番号が受信側の期待する位置か、その受信ウィンドウ内にある場合のみ受け付ける
+    // SYNに対するRSTはシーケンス番号の代わりにSYNへの確認応答で判断する
+    fn rst_acceptable(&self, is_from_client: bool, seq: u32, ack: u32, flags: u8) -> bool {
+        let (receiver_state, receiver_next_seq) = if is_from_client {
+            (&self.server_state, self.server_next_seq)
+        } else {
+            (&self.client_state, self.client_next_seq)
+        };
+        if *receiver_state == TcpState::SynSent || (!is_from_client && !self.server_seq_synced) {
+            return flags & TCP_ACK != 0 && ack == receiver_next_seq;
+        }
+
+        let (next_seq, window) = if is_from_client {
+            (self.client_next_seq, self.server_window)
+        } else {
+            (self.server_next_seq, self.client_window)
+        };
+        seq_ge(seq, next_seq) && seq_lt(seq, next_seq.wrapping_add(window.max(1)))
+    }
+
+    fn record_syn_options(&mut self, is_from_client: bool, tcp_header: &TcpHeader) {
+        if let Some(mss) = tcp_header.mss() {
+            self.set_mss(is_from_client, mss);
+        }
+        let options = if is_from_client { &mut self.client_options } else { &mut self.server_options };
+        options.window_scale = tcp_header.window_scale();
+        options.sack_permitted = tcp_header.sack_permitted();
+        options.timestamps = tcp_header.timestamp().is_some();
+    }
+
+    // 両方がSYNでウィンドウスケールを通知した場合のみスケールを適用する (SYN自体のウィンドウは適用しない)
+    fn effective_window(&self, is_from_client: bool, flags: u8, window: u16) -> u32 {
+        if flags & TCP_SYN != 0 {
+            return window as u32;
+        }
+        match (self.client_options.window_scale, self.server_options.window_scale) {
+            (Some(client_scale), Some(server_scale)) => {
+                let scale = if is_from_client { client_scale } else { server_scale };
+                (window as u32) << scale
+            }
+            _ => window as u32,
+        }
+    }
+
+    // 両方がSYNでタイムスタンプを送ったか
+    pub fn timestamps_enabled(&self) -> bool {
+        self.client_options.timestamps && self.server_options.timestamps
+    }
+
+    // PAWS (Protection Against Wrapped Sequences) の判定
+    // 送信側の直前のTSvalより古いタイムスタンプを持つセグメントはfalseを返す
+    fn check_paws(&mut self, is_from_client: bool, tcp_header: &TcpHeader, now: SystemTime) -> bool {
+        let ts_value = match tcp_header.timestamp() {
+            Some((ts_value, _)) if self.timestamps_enabled() || tcp_header.flags & TCP_SYN != 0 => ts_value,
+            _ => return true,
+        };
+        let seq = tcp_header.seq_num;
+        let next_seq = if is_from_client { self.client_next_seq } else { self.server_next_seq };
+        let options = if is_from_client { &mut self.client_options } else { &mut self.server_options };
+
+        // RSTはPAWSの対象外
+        if tcp_header.flags & TCP_RST == 0 {
+            if let (Some(ts_recent), Some(ts_recent_time)) = (options.ts_recent, options.ts_recent_time) {
+                if seq_lt(ts_value, ts_recent) && elapsed_between(ts_recent_time, now) < PAWS_IDLE_LIMIT {
+                    self.paws_rejected_segments += 1;
+                    self.events.push(TcpStreamEvent::PawsRejected {
+                        from_client: is_from_client,
+                        seq,
+                        ts_value,
+                        ts_recent,
+                    });
+                    return false;
                 }
-            },
+            }
+        }
+
+        // 受け付け済みの範囲の先頭を含むセグメントのタイムスタンプを記録する
+        if options.ts_recent.is_none() || !seq_gt(seq, next_seq) {
+            options.ts_recent = Some(ts_value);
+            options.ts_recent_time = Some(now);
+        }
+        true
+    }
 
-            // 最後の FIN に対する ACK を受信、接続終了の準備
-            (TcpState::FinWait2, TCP_ACK) => TcpState::TimeWait,
+    // 片方向のデータを受け付ける
+    // 期待するシーケンス番号のデータは連結し、それより先のデータはキューに保持する
+    // 受け付け済みの範囲と重なる再送は内容を比較し、新しい部分だけを連結する
+    // キューに保持できずに破棄した場合はfalseを返す
+    fn accept_segment(&mut self, is_from_client: bool, seq: u32, data: &[u8], can_queue: bool) -> bool {
+        if data.is_empty() {
+            return true;
+        }
 
-            // FIN 受信側のアプリケーションが接続を閉じ、FIN を送信
-            (TcpState::CloseWait, TCP_FIN) => TcpState::LastAck,
+        let policy = self.policy;
+        let depth = self.limits.depth;
+        let mut truncated = 0;
+        let mut delivered = Vec::new();
+        let mut events = Vec::new();
+        let mut retransmitted = false;
+        let (next_seq, stream_data, queue, anchor) = self.direction_mut(is_from_client);
+        let end = seq.wrapping_add(data.len() as u32);
 
-            // 最後の FIN に対する ACK を受信、接続完全終了
-            (TcpState::LastAck, TCP_ACK) => TcpState::Closed,
+        let new_data = if seq == *next_seq {
+            data
+        } else if seq_gt(seq, *next_seq) {
+            if !can_queue {
+                return false;
+            }
+            if queue.insert(seq, data, policy) {
+                events.push(TcpStreamEvent::OverlapConflict { from_client: is_from_client, seq, length: data.len() });
+            }
+            &[][..]
+        } else {
+            // 再送 (全体または一部が受け付け済み)
+            retransmitted = true;
+            let overlap_end = if seq_lt(end, *next_seq) { end } else { *next_seq };
+            let overlap = &data[..overlap_end.wrapping_sub(seq) as usize];
 
-            // TIME_WAIT 状態で 2MSL (通常 2分) 経過後、完全にクローズ
-            (TcpState::TimeWait, _) if Instant::now().duration_since(self.last_activity) > Duration::from_secs(120) => TcpState::Closed,
+            // 保持しているデータと比較できる範囲 (欠落を飛ばす前のデータは比較できない)
+            let skip = if seq_lt(seq, anchor.seq) { anchor.seq.wrapping_sub(seq) as usize } else { 0 };
+            if skip < overlap.len() {
+                let start = anchor.offset_of(seq.wrapping_add(skip as u32)).unwrap_or(anchor.offset);
+                let compared = &overlap[skip..];
+                if let Some(accepted) = stream_data.get(start..start + compared.len()) {
+                    // コンシューマーとルールの検査に渡したデータと食い違わないよう、受け付け済みのデータは書き換えない
+                    if accepted != compared {
+                        events.push(TcpStreamEvent::RetransmissionConflict {
+                            from_client: is_from_client,
+                            seq,
+                            length: data.len(),
+                        });
+                    }
+                }
+            }
 
-            // 上記以外の場合は現在の状態を維持
-            (state, _) => state,
+            // 受け付け済みの部分を取り除いた残り
+            &data[overlap.len()..]
         };
+
+        if !new_data.is_empty() {
+            // 先に届いてキューに保持しているセグメントと重なる部分もポリシーに従って一方のデータに揃えるため、
+            // 期待するシーケンス番号のデータもキューを通して欠落部分が埋まったデータとともに連結する
+            let new_seq = *next_seq;
+            if queue.insert(new_seq, new_data, policy) {
+                events.push(TcpStreamEvent::OverlapConflict {
+                    from_client: is_from_client,
+                    seq: new_seq,
+                    length: new_data.len(),
+                });
+            }
+            let contiguous = queue.take_contiguous(*next_seq);
+            *next_seq = next_seq.wrapping_add(contiguous.len() as u32);
+            truncated += append_within_depth(stream_data, &contiguous, depth);
+            delivered = contiguous;
+        }
+
+        self.truncated_bytes += truncated as u64;
+        if !delivered.is_empty() {
+            self.chunks.push(StreamChunk::Data { from_client: is_from_client, data: delivered });
+        }
+        if retransmitted {
+            self.retransmitted_segments += 1;
+        }
+        if !events.is_empty() {
+            self.conflicting_segments += 1;
+            self.events.extend(events);
+        }
+
+        true
+    }
+
+    // 受信側の確認応答を反映する
+    // キャプチャで取りこぼしたデータが確認応答された場合は、その欠落を飛ばして再構築を続ける
+    fn acknowledge(&mut self, is_client_data: bool, ack: u32) {
+        let depth = self.limits.depth;
+        let mut truncated = 0;
+        let mut chunks = Vec::new();
+        let (next_seq, stream_data, queue, anchor) = self.direction_mut(is_client_data);
+
+        while seq_lt(*next_seq, ack) {
+            // 欠落部分の後ろに保持しているセグメントがあれば、その位置まで進める
+            let resume_seq = match queue.first_seq() {
+                Some(first_seq) if seq_lt(first_seq, ack) => first_seq,
+                _ => ack,
+            };
+            if seq_gt(resume_seq, *next_seq) {
+                chunks.push(StreamChunk::Gap {
+                    from_client: is_client_data,
+                    seq: *next_seq,
+                    length: resume_seq.wrapping_sub(*next_seq),
+                });
+                *next_seq = resume_seq;
+                *anchor = SeqAnchor::new(resume_seq, stream_data.len());
+            }
+            let contiguous = queue.take_contiguous(*next_seq);
+            *next_seq = next_seq.wrapping_add(contiguous.len() as u32);
+            truncated += append_within_depth(stream_data, &contiguous, depth);
+            if !contiguous.is_empty() {
+                chunks.push(StreamChunk::Data { from_client: is_client_data, data: contiguous });
+            }
+            if resume_seq == ack {
+                break;
+            }
+        }
+        self.truncated_bytes += truncated as u64;
+        self.chunks.extend(chunks);
+    }
+
+    // コンシューマーに未通知のデータと欠落を取り出す
+    pub fn take_chunks(&mut self) -> Vec<StreamChunk> {
+        std::mem::take(&mut self.chunks)
+    }
+
+    // 未出力のイベントを取り出す
+    pub fn take_events(&mut self) -> Vec<TcpStreamEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn direction_mut(&mut self, is_client: bool) -> (&mut u32, &mut Vec<u8>, &mut SegmentQueue, &mut SeqAnchor) {
+        if is_client {
+            (&mut self.client_next_seq, &mut self.client_data, &mut self.client_ooo, &mut self.client_anchor)
+        } else {
+            (&mut self.server_next_seq, &mut self.server_data, &mut self.server_ooo, &mut self.server_anchor)
+        }
+    }
+
+    // 保持しているデータ(再構築済みのデータと順序外セグメント)のバイト数
+    pub fn memory_usage(&self) -> usize {
+        self.client_data.len()
+            + self.server_data.len()
+            + self.client_ooo.queued_bytes()
+            + self.server_ooo.queued_bytes()
+    }
+
+    // キャプチャ時刻nowの時点でストリームを破棄してよいか
+    // クローズ済み、TIME_WAITで2MSL経過、またはidle_timeout以上通信がない場合に破棄する
+    pub fn is_expired(&self, now: SystemTime, idle_timeout: Duration) -> bool {
+        let idle = elapsed_between(self.last_activity, now);
+        match self.state {
+            TcpState::Closed => true,
+            TcpState::TimeWait => idle > TIME_WAIT_DURATION,
+            _ => idle >= idle_timeout,
+        }
     }
 
     pub fn set_mss(&mut self, is_client: bool, mss: u16) {
@@ -152,4 +673,112 @@ impl TcpStream {
             self.server_mss = mss;
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_SEQ: u32 = 1000;
+    const SERVER_SEQ: u32 = 5000;
+
+    fn stream(policy: ReassemblyPolicy) -> TcpStream {
+        TcpStream::new_midstream(CLIENT_SEQ, SERVER_SEQ, SystemTime::UNIX_EPOCH, policy, StreamLimits::default())
+    }
+
+    fn segment(seq: u32, ack: u32, flags: u8, window: u16) -> TcpHeader {
+        TcpHeader {
+            src_port: 40000,
+            dst_port: 80,
+            seq_num: seq,
+            ack_num: ack,
+            data_offset: 5,
+            flags,
+            window,
+            checksum: 0,
+            urgent_ptr: 0,
+            options: Vec::new(),
+        }
+    }
+
+    fn send(stream: &mut TcpStream, from_client: bool, seq: u32, flags: u8, data: &[u8]) {
+        let ack = if from_client { stream.server_next_seq } else { stream.client_next_seq };
+        stream.update(from_client, &segment(seq, ack, flags, 1000), data, SystemTime::UNIX_EPOCH);
+    }
+
+    // 順序外で保持したセグメントに、期待するシーケンス番号のデータが重なる場合もポリシーに従う
+    #[test]
+    fn in_order_data_overlapping_queued_segment() {
+        use ReassemblyPolicy::*;
+        let cases: [(ReassemblyPolicy, &[u8], &[u8]); 5] = [
+            (First, b"abcdefgh", b"abcdXXXX"),
+            (Last, b"abcdefgh", b"abcdefgh"),
+            (Bsd, b"abcdefgh", b"abcdefgh"),
+            (Windows, b"abcdefgh", b"abcdXXXX"),
+            (Windows, b"abcdefghij", b"abcdefghij"),
+        ];
+        for (policy, data, expected) in cases {
+            let mut stream = stream(policy);
+            send(&mut stream, true, CLIENT_SEQ + 4, TCP_ACK, b"XXXX");
+            assert!(stream.client_data.is_empty());
+            send(&mut stream, true, CLIENT_SEQ, TCP_ACK, data);
+            assert_eq!(stream.client_data, expected, "{:?}", policy);
+            assert_eq!(stream.client_next_seq, CLIENT_SEQ + expected.len() as u32);
+            assert_eq!(stream.conflicting_segments, 1);
+            assert!(matches!(
+                stream.take_events()[..],
+                [TcpStreamEvent::OverlapConflict { from_client: true, seq: CLIENT_SEQ, .. }]
+            ));
+            assert_eq!(
+                stream.take_chunks(),
+                vec![StreamChunk::Data { from_client: true, data: expected.to_vec() }]
+            );
+        }
+    }
+
+    // 受け付け済みのデータはポリシーによらず書き換えず、新しい部分だけを連結する
+    #[test]
+    fn retransmission_with_different_data() {
+        for policy in [ReassemblyPolicy::First, ReassemblyPolicy::Last] {
+            let mut stream = stream(policy);
+            send(&mut stream, true, CLIENT_SEQ, TCP_ACK, b"abcd");
+            stream.take_chunks();
+            send(&mut stream, true, CLIENT_SEQ, TCP_ACK, b"abXdef");
+            assert_eq!(stream.client_data, b"abcdef", "{:?}", policy);
+            assert_eq!(stream.take_chunks(), vec![StreamChunk::Data { from_client: true, data: b"ef".to_vec() }]);
+            assert_eq!(stream.retransmitted_segments, 1);
+            assert!(matches!(
+                stream.take_events()[..],
+                [TcpStreamEvent::RetransmissionConflict { from_client: true, seq: CLIENT_SEQ, length: 6 }]
+            ));
+        }
+    }
+
+    #[test]
+    fn rst_outside_receive_window_is_ignored() {
+        let mut stream = stream(ReassemblyPolicy::First);
+        // サーバーの受信ウィンドウは1000バイト
+        send(&mut stream, false, SERVER_SEQ, TCP_ACK, b"");
+        send(&mut stream, true, CLIENT_SEQ + 1000, TCP_RST, b"");
+        assert_eq!(stream.state, TcpState::Established);
+        assert_eq!(stream.rejected_rst_segments, 1);
+        assert_eq!(
+            stream.take_events(),
+            vec![TcpStreamEvent::RstOutOfWindow { from_client: true, seq: CLIENT_SEQ + 1000, expected: CLIENT_SEQ }]
+        );
+
+        send(&mut stream, true, CLIENT_SEQ + 999, TCP_RST, b"");
+        assert_eq!(stream.state, TcpState::Closed);
+        assert!(stream.take_events().is_empty());
+    }
+
+    #[test]
+    fn rst_answering_syn_requires_matching_ack() {
+        let now = SystemTime::UNIX_EPOCH;
+        let mut stream = TcpStream::new(CLIENT_SEQ, 0, now, ReassemblyPolicy::First, StreamLimits::default());
+        stream.update(false, &segment(0, CLIENT_SEQ + 2, TCP_RST | TCP_ACK, 0), b"", now);
+        assert_eq!(stream.rejected_rst_segments, 1);
+        stream.update(false, &segment(0, CLIENT_SEQ + 1, TCP_RST | TCP_ACK, 0), b"", now);
+        assert_eq!(stream.state, TcpState::Closed);
+    }
+}
@@ -0,0 +1,258 @@
+use crate::capture_clock::elapsed_between;
+use crate::stream_consumer::CloseReason;
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+// (クライアントIP, クライアントポート, サーバーIP, サーバーポート)
+// 最初にデータグラムを送った側をクライアントとする
+pub type UdpFlowKey = (IpAddr, u16, IpAddr, u16);
+
+// UDPフローテーブルの上限
+#[derive(Debug, Clone, Copy)]
+pub struct UdpFlowLimits {
+    pub max_flows: usize,       // 同時に追跡するフロー数
+    pub idle_timeout: Duration, // 通信のないフローを保持する時間
+}
+
+impl Default for UdpFlowLimits {
+    fn default() -> Self {
+        UdpFlowLimits {
+            max_flows: 65536,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+// 双方向のUDPフロー
+#[derive(Debug)]
+pub struct UdpFlow {
+    pub first_seen: SystemTime,     // キャプチャ時刻での最初のデータグラムの時刻
+    pub last_activity: SystemTime,  // キャプチャ時刻での最終通信時刻
+    pub client_packets: u64,
+    pub client_bytes: u64,          // クライアントから送られたペイロードのバイト数
+    pub server_packets: u64,
+    pub server_bytes: u64,          // サーバーから送られたペイロードのバイト数
+    pub vlan_ids: Vec<u16>,         // フローが観測されたVLAN ID (外側から順)
+}
+
+impl UdpFlow {
+    pub fn new(now: SystemTime) -> Self {
+        UdpFlow {
+            first_seen: now,
+            last_activity: now,
+            client_packets: 0,
+            client_bytes: 0,
+            server_packets: 0,
+            server_bytes: 0,
+            vlan_ids: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, is_from_client: bool, payload_len: usize, now: SystemTime) {
+        self.last_activity = now;
+        if is_from_client {
+            self.client_packets += 1;
+            self.client_bytes += payload_len as u64;
+        } else {
+            self.server_packets += 1;
+            self.server_bytes += payload_len as u64;
+        }
+    }
+
+    pub fn is_expired(&self, now: SystemTime, idle_timeout: Duration) -> bool {
+        elapsed_between(self.last_activity, now) >= idle_timeout
+    }
+}
+
+// フローを破棄した理由ごとの件数
+#[derive(Debug, Default, Clone)]
+pub struct UdpFlowCounters {
+    pub max_flows: u64, // フロー数の上限により破棄した数
+    pub expired: u64,   // タイムアウトにより破棄した数
+}
+
+// UDPフローのテーブル
+// 上限に達した場合は最終通信時刻が最も古いフローから破棄する
+pub struct UdpFlowTable {
+    flows: HashMap<UdpFlowKey, (UdpFlow, SystemTime)>, // フローと索引に登録済みの最終通信時刻
+    by_activity: BTreeSet<(SystemTime, UdpFlowKey)>,   // 最終通信時刻順の索引 (破棄するフローを全体を走査せずに選ぶ)
+    limits: UdpFlowLimits,
+    closed: Vec<(UdpFlowKey, UdpFlow, CloseReason)>, // コンシューマーに未通知の削除したフロー
+    pub counters: UdpFlowCounters,
+}
+
+impl UdpFlowTable {
+    pub fn new(limits: UdpFlowLimits) -> Self {
+        UdpFlowTable {
+            flows: HashMap::new(),
+            by_activity: BTreeSet::new(),
+            limits,
+            closed: Vec::new(),
+            counters: UdpFlowCounters::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+
+    pub fn contains_key(&self, key: &UdpFlowKey) -> bool {
+        self.flows.contains_key(key)
+    }
+
+    pub fn get_mut(&mut self, key: &UdpFlowKey) -> Option<&mut UdpFlow> {
+        self.flows.get_mut(key).map(|(flow, _)| flow)
+    }
+
+    // フローを追加する (フロー数が上限に達している場合は最も古いフローを破棄する)
+    pub fn insert(&mut self, key: UdpFlowKey, flow: UdpFlow) {
+        while self.flows.len() >= self.limits.max_flows && !self.flows.contains_key(&key) {
+            match self.by_activity.first().map(|(_, key)| *key) {
+                Some(oldest) => self.remove(&oldest, CloseReason::Evicted),
+                None => break,
+            }
+            self.counters.max_flows += 1;
+        }
+        let indexed_activity = flow.last_activity;
+        self.by_activity.insert((indexed_activity, key));
+        if let Some((_, previous)) = self.flows.insert(key, (flow, indexed_activity)) {
+            if previous != indexed_activity {
+                self.by_activity.remove(&(previous, key));
+            }
+        }
+    }
+
+    // フローを更新した後に最終通信時刻を索引に反映する
+    pub fn refresh(&mut self, key: &UdpFlowKey) {
+        if let Some((flow, indexed_activity)) = self.flows.get_mut(key) {
+            if flow.last_activity != *indexed_activity {
+                self.by_activity.remove(&(*indexed_activity, *key));
+                *indexed_activity = flow.last_activity;
+                self.by_activity.insert((*indexed_activity, *key));
+            }
+        }
+    }
+
+    // キャプチャ時刻nowの時点で通信のないフローを破棄する (索引の古い順に調べる)
+    pub fn remove_expired(&mut self, now: SystemTime) {
+        let idle_timeout = self.limits.idle_timeout;
+        while let Some(&(last_activity, key)) = self.by_activity.first() {
+            if elapsed_between(last_activity, now) < idle_timeout {
+                break;
+            }
+            // 索引に反映していない通信があったフローは位置を更新して残す
+            if self.flows.get(&key).is_some_and(|(flow, _)| !flow.is_expired(now, idle_timeout)) {
+                self.refresh(&key);
+                continue;
+            }
+            self.remove(&key, CloseReason::Timeout);
+            self.counters.expired += 1;
+        }
+    }
+
+    // キャプチャの終了時に残っているフローをすべて削除する
+    pub fn close_all(&mut self) {
+        let keys: Vec<UdpFlowKey> = self.flows.keys().copied().collect();
+        for key in keys {
+            self.remove(&key, CloseReason::Shutdown);
+        }
+    }
+
+    // 削除したフローを取り出す (コンシューマーへの通知に使う)
+    pub fn take_closed(&mut self) -> Vec<(UdpFlowKey, UdpFlow, CloseReason)> {
+        std::mem::take(&mut self.closed)
+    }
+
+    fn remove(&mut self, key: &UdpFlowKey, reason: CloseReason) {
+        if let Some((flow, indexed_activity)) = self.flows.remove(key) {
+            self.by_activity.remove(&(indexed_activity, *key));
+            self.closed.push((*key, flow, reason));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn at(seconds: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    fn key(client_port: u16) -> UdpFlowKey {
+        (
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            client_port,
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53)),
+            53,
+        )
+    }
+
+    fn table(max_flows: usize) -> UdpFlowTable {
+        UdpFlowTable::new(UdpFlowLimits {
+            max_flows,
+            idle_timeout: Duration::from_secs(60),
+        })
+    }
+
+    fn closed(table: &mut UdpFlowTable) -> Vec<(u16, CloseReason)> {
+        table.take_closed().into_iter().map(|(key, _, reason)| (key.1, reason)).collect()
+    }
+
+    #[test]
+    fn counts_each_direction() {
+        let mut flow = UdpFlow::new(at(0));
+        flow.update(true, 30, at(0));
+        flow.update(false, 120, at(1));
+        flow.update(true, 10, at(2));
+        assert_eq!((flow.client_packets, flow.client_bytes), (2, 40));
+        assert_eq!((flow.server_packets, flow.server_bytes), (1, 120));
+        assert_eq!(flow.last_activity, at(2));
+        assert!(!flow.is_expired(at(61), Duration::from_secs(60)));
+        assert!(flow.is_expired(at(62), Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn evicts_least_recently_active_flow() {
+        let mut table = table(2);
+        table.insert(key(1), UdpFlow::new(at(0)));
+        table.insert(key(2), UdpFlow::new(at(1)));
+        // 最初のフローに通信があったため、2番目のフローが最も古くなる
+        table.get_mut(&key(1)).unwrap().update(true, 10, at(2));
+        table.refresh(&key(1));
+        table.insert(key(3), UdpFlow::new(at(3)));
+
+        assert_eq!(table.len(), 2);
+        assert!(table.contains_key(&key(1)) && !table.contains_key(&key(2)));
+        assert_eq!(table.counters.max_flows, 1);
+        assert_eq!(closed(&mut table), [(2, CloseReason::Evicted)]);
+    }
+
+    #[test]
+    fn removes_idle_flows_in_activity_order() {
+        let mut table = table(10);
+        table.insert(key(1), UdpFlow::new(at(0)));
+        table.insert(key(2), UdpFlow::new(at(10)));
+        table.insert(key(3), UdpFlow::new(at(20)));
+        // 索引に反映していない通信があったフローは残す
+        table.get_mut(&key(1)).unwrap().update(false, 10, at(50));
+
+        table.remove_expired(at(75));
+        assert_eq!(closed(&mut table), [(2, CloseReason::Timeout)]);
+        assert_eq!(table.counters.expired, 1);
+
+        table.remove_expired(at(110));
+        assert_eq!(closed(&mut table), [(3, CloseReason::Timeout), (1, CloseReason::Timeout)]);
+        assert!(table.is_empty());
+
+        table.insert(key(4), UdpFlow::new(at(110)));
+        table.close_all();
+        assert_eq!(closed(&mut table), [(4, CloseReason::Shutdown)]);
+    }
+}
@@ -0,0 +1,83 @@
+// 0                   1                   2                   3
+// 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |          Source Port          |       Destination Port        |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |            Length             |           Checksum            |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+#[derive(Debug)]
+pub struct UdpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub length: u16, // ヘッダーを含むデータグラム長
+    pub checksum: u16,
+}
+
+const UDP_HEADER_SIZE: usize = 8;
+
+// UDPヘッダーを解析し、ヘッダーとペイロードを返す
+// ペイロードは長さフィールドの範囲に切り詰める (0の場合はジャンボグラムとしてキャプチャされた全体を使う)
+pub fn parse_udp_header(data: &[u8]) -> Option<(UdpHeader, &[u8])> {
+    if data.len() < UDP_HEADER_SIZE {
+        return None;
+    }
+
+    let src_port = u16::from_be_bytes([data[0], data[1]]);
+    let dst_port = u16::from_be_bytes([data[2], data[3]]);
+    let length = u16::from_be_bytes([data[4], data[5]]);
+    let checksum = u16::from_be_bytes([data[6], data[7]]);
+
+    let end = match length as usize {
+        0 => data.len(),
+        length if length < UDP_HEADER_SIZE => return None,
+        length => length.min(data.len()),
+    };
+
+    Some((
+        UdpHeader {
+            src_port,
+            dst_port,
+            length,
+            checksum,
+        },
+        &data[UDP_HEADER_SIZE..end],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(length: u16, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x30, 0x39, 0x00, 0x35];
+        data.extend_from_slice(&length.to_be_bytes());
+        data.extend_from_slice(&[0xAB, 0xCD]);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn parses_header_and_payload() {
+        let data = datagram(12, b"abcd");
+        let (udp, payload) = parse_udp_header(&data).unwrap();
+        assert_eq!((udp.src_port, udp.dst_port), (12345, 53));
+        assert_eq!((udp.length, udp.checksum), (12, 0xABCD));
+        assert_eq!(payload, b"abcd");
+    }
+
+    #[test]
+    fn payload_follows_length_field() {
+        // イーサネットのパディングは長さフィールドで取り除く
+        assert_eq!(parse_udp_header(&datagram(10, b"abcd\0\0")).unwrap().1, b"ab");
+        // 切り詰められたキャプチャではキャプチャされた範囲まで
+        assert_eq!(parse_udp_header(&datagram(100, b"abcd")).unwrap().1, b"abcd");
+        // 長さが0のジャンボグラムはキャプチャされた全体
+        assert_eq!(parse_udp_header(&datagram(0, b"abcd")).unwrap().1, b"abcd");
+    }
+
+    #[test]
+    fn rejects_invalid_datagrams() {
+        assert!(parse_udp_header(&datagram(8, b"")[..7]).is_none());
+        assert!(parse_udp_header(&datagram(7, b"abcd")).is_none());
+    }
+}